@@ -0,0 +1,13 @@
+CREATE TABLE peers_old
+  (
+     ip          VARCHAR(15) NOT NULL,
+     port        INT DEFAULT 0 CHECK (port > 0 AND port <= 65535) NOT NULL,
+     nextattempt TIMESTAMP NOT NULL,
+     numfailures INT DEFAULT 0 CHECK (numfailures >= 0) NOT NULL,
+     PRIMARY KEY (ip, port)
+  );
+
+INSERT INTO peers_old
+  SELECT ip, port, nextattempt, numfailures FROM peers WHERE ip NOT LIKE '%:%';
+DROP TABLE peers;
+ALTER TABLE peers_old RENAME TO peers;
@@ -0,0 +1,14 @@
+-- SQLite can't alter column type, so the table is rebuilt
+-- to fit textual IPv6 addresses (up to 45 characters)
+CREATE TABLE peers_new
+  (
+     ip          VARCHAR(45) NOT NULL,
+     port        INT DEFAULT 0 CHECK (port > 0 AND port <= 65535) NOT NULL,
+     nextattempt TIMESTAMP NOT NULL,
+     numfailures INT DEFAULT 0 CHECK (numfailures >= 0) NOT NULL,
+     PRIMARY KEY (ip, port)
+  );
+
+INSERT INTO peers_new SELECT ip, port, nextattempt, numfailures FROM peers;
+DROP TABLE peers;
+ALTER TABLE peers_new RENAME TO peers;
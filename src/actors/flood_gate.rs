@@ -1,61 +1,112 @@
-use super::{debug, message_abbr, peer_ref, riker::actors::*, xdr, AstroProtocol, FloodGate};
-use std::collections::HashSet;
-use std::time::{SystemTime, UNIX_EPOCH};
+use super::{
+    debug, message_hash, riker::actors::*, warn, xdr, AstroProtocol, FloodGate, FloodGateMsg,
+    FloodGateRef, Misbehavior, OverlayManagerMsg, OverlayManagerRef, PeerMsg, PeerRef, METRICS,
+};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often records are checked for the fallback clear
+const EXPIRE_RECORDS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Records older than that are cleared even if no ledger closes. It is
+/// about 10 ledgers of 5 seconds, the window `clear_below` keeps
+const RECORD_TTL: Duration = Duration::from_secs(50);
 
 #[derive(Clone, Debug)]
 pub(crate) struct FloodGateActor {
     state: FloodGate,
+    overlay_manager: OverlayManagerRef,
+    /// Sequence of the last closed ledger, new records are tagged with it
+    ledger_seq: u32,
 }
 
 impl FloodGateActor {
-    pub fn new() -> BoxActor<AstroProtocol> {
+    pub fn new(overlay_manager: OverlayManagerRef) -> BoxActor<AstroProtocol> {
         let actor = FloodGateActor {
             state: FloodGate::new(),
+            overlay_manager,
+            ledger_seq: 0,
         };
 
         Box::new(actor)
     }
 
-    pub fn props() -> BoxActorProd<AstroProtocol> {
-        Props::new(Box::new(FloodGateActor::new))
+    pub fn props(overlay_manager: OverlayManagerRef) -> BoxActorProd<AstroProtocol> {
+        Props::new_args(Box::new(FloodGateActor::new), overlay_manager)
+    }
+
+    /// Record message received from peer and, if it is new, flood it to the
+    /// rest of peers in one step, so nothing can run in between
+    pub fn add_record_and_broadcast(
+        &mut self,
+        message: xdr::StellarMessage,
+        address: SocketAddr,
+        peers: HashMap<SocketAddr, PeerRef>,
+    ) {
+        if self.state.is_duplicate(&message, &address) {
+            METRICS.record_flood_message(false);
+            self.overlay_manager
+                .tell(OverlayManagerMsg::MisbehavedPeerCmd(
+                    address,
+                    Misbehavior::DuplicateData,
+                ));
+            return;
+        }
+
+        let unique = self
+            .state
+            .add_record(&message, Some(address), self.ledger_seq);
+        METRICS.record_flood_message(unique);
+        if unique {
+            self.tell_peers(&message, &peers);
+        }
     }
 
     /// Send message to anyone you haven't gotten it from
     pub fn broadcast(
         &mut self,
-        ctx: &Context<AstroProtocol>,
         message: xdr::StellarMessage,
         force: bool,
-        peers: HashSet<String>,
+        peers: HashMap<SocketAddr, PeerRef>,
     ) {
         if self.state.m_shutting_down {
             return;
         };
 
-        let index = message_abbr(&message);
+        let index = message_hash(&message);
 
         // no one has sent us this message
         if self.state.flood_map.get(&index).is_none() || force {
-            self.state
-                .add_record(&message, "self".to_string(), unix_time());
+            self.state.add_record(&message, None, self.ledger_seq);
         };
 
-        let record = match self.state.flood_map.get_mut(&index) {
-            Some(rec) => rec,
-            None => return,
-        };
+        self.tell_peers(&message, &peers);
+    }
 
-        let previous_sent = record.m_peers_told.clone();
-        for peer in peers {
-            peer_ref(&peer, ctx).tell(AstroProtocol::SendPeerMessageCmd(message.to_owned()), None);
-            record.m_peers_told.push(peer.to_owned());
+    /// Nothing sends LedgerClosedCmd until Ledger subsystem exists,
+    /// so records are also cleared by their age
+    fn expire_records(&mut self, ctx: &Context<AstroProtocol>) {
+        if let Some(deadline) = Instant::now().checked_sub(RECORD_TTL) {
+            self.state.clear_received_before(deadline);
         }
 
-        debug!(
-            "[Overlay][FloodGate] broadcast told {}",
-            record.m_peers_told.len() - previous_sent.len()
+        FloodGateRef::new(ctx.myself()).schedule_once(
+            ctx,
+            EXPIRE_RECORDS_INTERVAL,
+            FloodGateMsg::ExpireFloodGateRecordsCmd,
         );
     }
+
+    fn tell_peers(&mut self, message: &xdr::StellarMessage, peers: &HashMap<SocketAddr, PeerRef>) {
+        let addresses: HashSet<SocketAddr> = peers.keys().cloned().collect();
+        let told = self.state.peers_to_tell(message, &addresses);
+        for address in &told {
+            peers[address].tell(PeerMsg::SendPeerMessageCmd(message.to_owned()));
+        }
+
+        debug!("[Overlay][FloodGate] broadcast told {}", told.len());
+    }
 }
 
 impl Actor for FloodGateActor {
@@ -67,24 +118,31 @@ impl Actor for FloodGateActor {
         msg: Self::Msg,
         _sender: Option<ActorRef<Self::Msg>>,
     ) {
-        debug!("FLOOD_GATE RECEIVE: {:?}", msg);
+        let msg = match msg {
+            AstroProtocol::FloodGate(msg) => msg,
+            msg => {
+                warn!("[Overlay][FloodGate] unexpected message: {:?}", msg);
+                return;
+            }
+        };
+
         match msg {
-            AstroProtocol::AddRecordFloodGateCmd(message, address, seq_ledger) => {
-                self.state.add_record(&message, address, seq_ledger);
+            FloodGateMsg::AddRecordAndBroadcastFloodGateCmd(message, address, peers) => {
+                self.add_record_and_broadcast(message, address, peers);
+            }
+            FloodGateMsg::BroadcastFloodGateCmd(message, force, peers) => {
+                self.broadcast(message, force, peers);
             }
-            AstroProtocol::BroadcastFloodGateCmd(message, force, peers) => {
-                self.broadcast(ctx, message, force, peers);
+            FloodGateMsg::LedgerClosedCmd(ledger_seq) => {
+                self.ledger_seq = ledger_seq;
+                self.state.clear_below(ledger_seq);
             }
-            AstroProtocol::ClearFloodGateCmd(seq_ledger) => self.state.clear_below(seq_ledger),
-            _ => unreachable!(),
+            FloodGateMsg::ExpireFloodGateRecordsCmd => self.expire_records(ctx),
+            FloodGateMsg::ShutdownFloodGateCmd => self.state.shutdown(),
         }
     }
-}
 
-// stub for current ledger value
-fn unix_time() -> u32 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32
+    fn post_start(&mut self, ctx: &Context<Self::Msg>) {
+        self.expire_records(ctx);
+    }
 }
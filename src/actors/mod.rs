@@ -1,4 +1,4 @@
-#![allow(dead_code, unused_must_use)]
+#![allow(dead_code)]
 #![allow(clippy::new_ret_no_self)]
 
 mod flood_gate;
@@ -7,12 +7,25 @@ mod overlay_manager;
 mod peer;
 
 pub(crate) use crate::{
-    astro_protocol::AstroProtocol,
+    astro_protocol::{
+        AstroProtocol, FloodGateMsg, FloodGateRef, ItemWaiter, OverlayManagerMsg,
+        OverlayManagerRef, PeerMsg, PeerRef,
+    },
+    command_handler,
     config::CONFIG,
-    overlay::{message_abbr, FloodGate, OverlayManager, Peer, PeerInterface},
+    overlay::{
+        message_hash, Admission, BanManager, Connection, ConnectionEvent, FloodGate, HelloVerdict,
+        Item, ItemFetcher, ItemKind, MessageCategory, Misbehavior, NodeConnection, OverlayManager,
+        Peer, PeerError, PeerInterface, PeerRateLimiter, PeerTimeout, PeerTimeouts, RateCheck,
+        Reactor, METRICS,
+    },
+    scp::{
+        envelope::verify_envelope,
+        local_node::{LOCAL_NODE, PEER_AUTH},
+    },
     xdr,
 };
-pub(crate) use log::{debug, info};
+pub(crate) use log::{debug, info, warn};
 pub(crate) use riker;
 
 use self::flood_gate::FloodGateActor;
@@ -22,28 +35,50 @@ use self::peer::PeerActor;
 
 use riker::actors::*;
 use riker_default::DefaultModel;
+use std::net::SocketAddr;
+use std::time::Duration;
 
-pub(crate) fn start() {
-    let model: DefaultModel<AstroProtocol> = DefaultModel::new();
-    let sys = ActorSystem::new(&model).unwrap();
-    let props = OverlayManagerActor::props();
-
-    sys.actor_of(props, "overlay_manager").unwrap();
+/// Running actor system with the overlay
+pub(crate) struct Node {
+    system: ActorSystem<AstroProtocol>,
+    overlay_manager: OverlayManagerRef,
+    reactor: Reactor,
 }
 
-fn overlay_manager_ref(ctx: &Context<AstroProtocol>) -> ActorSelection<AstroProtocol> {
-    ctx.select("/user/overlay_manager").unwrap()
+impl Node {
+    /// Drop peers, save them and wait until their queued messages are written.
+    /// Returns false if it didn't finish within timeout
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        self.overlay_manager.tell(OverlayManagerMsg::ShutdownCmd);
+        let stopped = self.reactor.wait_stopped(timeout);
+        self.system.stop(self.overlay_manager.actor());
+        stopped
+    }
 }
 
-fn flood_gate_ref(ctx: &Context<AstroProtocol>) -> ActorSelection<AstroProtocol> {
-    ctx.select("/user/flood_gate").unwrap()
-}
+pub(crate) fn start() -> Node {
+    let model: DefaultModel<AstroProtocol> = DefaultModel::new();
+    let system = ActorSystem::new(&model).unwrap();
+    let reactor = Reactor::spawn().expect("[Overlay] Unable to start network reactor");
+    let props = OverlayManagerActor::props(reactor.clone());
+
+    let overlay_manager =
+        OverlayManagerRef::new(system.actor_of(props, "overlay_manager").unwrap());
+
+    command_handler::spawn(overlay_manager.clone())
+        .expect("[CommandHandler] Unable to listen operator commands");
 
-fn peer_ref(address: &str, ctx: &Context<AstroProtocol>) -> ActorSelection<AstroProtocol> {
-    ctx.select(&format!("/user/peer-{}", peer_actor_name(address)))
-        .unwrap()
+    Node {
+        system,
+        overlay_manager,
+        reactor,
+    }
 }
 
-fn peer_actor_name(address: &str) -> String {
-    format!("peer-{}", address.replace(".", "-").replace(":", "-"))
+/// Actor names must be unique among manager children, actors are told through refs
+fn peer_actor_name(address: &SocketAddr) -> String {
+    let address = address
+        .to_string()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "-");
+    format!("peer-{}", address)
 }
@@ -1,31 +1,50 @@
-use super::{info, overlay_manager_ref, riker::actors::*, AstroProtocol, Peer, CONFIG};
+use super::{
+    info, riker::actors::*, warn, AstroProtocol, OverlayManagerMsg, OverlayManagerRef, Reactor,
+    CONFIG,
+};
 use std::net::TcpListener;
 
-pub(crate) struct OverlayListenerActor;
+#[derive(Debug)]
+pub(crate) struct OverlayListenerActor {
+    reactor: Reactor,
+    overlay_manager: OverlayManagerRef,
+}
 
 impl OverlayListenerActor {
-    pub fn new() -> BoxActor<AstroProtocol> {
-        Box::new(OverlayListenerActor)
+    pub fn new(
+        (reactor, overlay_manager): (Reactor, OverlayManagerRef),
+    ) -> BoxActor<AstroProtocol> {
+        Box::new(OverlayListenerActor {
+            reactor,
+            overlay_manager,
+        })
     }
 
-    pub fn props() -> BoxActorProd<AstroProtocol> {
-        Props::new(Box::new(OverlayListenerActor::new))
+    pub fn props(
+        reactor: Reactor,
+        overlay_manager: OverlayManagerRef,
+    ) -> BoxActorProd<AstroProtocol> {
+        Props::new_args(
+            Box::new(OverlayListenerActor::new),
+            (reactor, overlay_manager),
+        )
     }
 }
 
 impl Actor for OverlayListenerActor {
     type Msg = AstroProtocol;
 
+    /// Accepted connections go straight to overlay manager, listener gets no messages
     fn receive(
         &mut self,
         _ctx: &Context<Self::Msg>,
-        _msg: Self::Msg,
+        msg: Self::Msg,
         _sender: Option<ActorRef<Self::Msg>>,
     ) {
-        unreachable!();
+        warn!("[Overlay][Listener] unexpected message: {:?}", msg);
     }
 
-    fn post_start(&mut self, ctx: &Context<Self::Msg>) {
+    fn post_start(&mut self, _ctx: &Context<Self::Msg>) {
         let listener = TcpListener::bind(CONFIG.local_node().address()).expect(
             "[Overlay][Listener] Unable to listen local address to handle incoming connections",
         );
@@ -35,18 +54,15 @@ impl Actor for OverlayListenerActor {
             CONFIG.local_node().address()
         );
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let peer = Box::new(Peer::new(stream, CONFIG.local_node().address()));
-                    overlay_manager_ref(ctx)
-                        .tell(AstroProtocol::HandleOverlayIncomingPeerCmd(peer), None)
-                }
-                Err(e) => {
-                    info!("[Overlay][Listener] connection failed, cause: {:?}", e);
-                }
-            }
-        }
-        unreachable!();
+        // Connections are accepted on reactor thread, so the actor isn't blocked
+        let overlay_manager = self.overlay_manager.clone();
+        self.reactor.listen(
+            listener,
+            Box::new(move |connection, address| {
+                overlay_manager.tell(OverlayManagerMsg::HandleOverlayIncomingPeerCmd(
+                    connection, address,
+                ))
+            }),
+        );
     }
 }
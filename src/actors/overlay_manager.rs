@@ -1,120 +1,505 @@
 use super::{
-    flood_gate_ref, info, peer_actor_name, riker::actors::*, xdr, AstroProtocol, FloodGateActor,
-    OverlayListenerActor, OverlayManager, Peer, PeerActor,
+    debug, info, peer_actor_name, riker::actors::*, verify_envelope, warn, xdr, Admission,
+    AstroProtocol, BanManager, Connection, FloodGateActor, FloodGateMsg, FloodGateRef,
+    HelloVerdict, Item, ItemFetcher, ItemKind, ItemWaiter, Misbehavior, NodeConnection,
+    OverlayListenerActor, OverlayManager, OverlayManagerMsg, OverlayManagerRef, PeerActor,
+    PeerError, PeerMsg, PeerRef, Reactor, CONFIG, LOCAL_NODE, PEER_AUTH,
 };
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Max number of addresses in PEERS message, the same as XDR `PeerAddress peers<100>`
+const MAX_PEERS_IN_MESSAGE: usize = 100;
+/// How often fetchers check whether peer replies timed out
+const ITEM_FETCHERS_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Clone, Debug)]
 pub(crate) struct OverlayManagerActor {
     state: OverlayManager,
+    /// Network reactor shared by all peer connections
+    reactor: Reactor,
+    tx_set_fetcher: ItemFetcher<ItemWaiter>,
+    quorum_set_fetcher: ItemFetcher<ItemWaiter>,
+    /// Actors of pending and authenticated peers, they are children of manager
+    peers: HashMap<SocketAddr, PeerRef>,
+    flood_gate: Option<FloodGateRef>,
+    /// No new connections are made or accepted once shutdown started
+    shutting_down: bool,
 }
 
 impl OverlayManagerActor {
-    pub fn new() -> BoxActor<AstroProtocol> {
+    pub fn new(reactor: Reactor) -> BoxActor<AstroProtocol> {
         let actor = OverlayManagerActor {
             state: OverlayManager::new(),
+            reactor,
+            tx_set_fetcher: ItemFetcher::new(ItemKind::TxSet),
+            quorum_set_fetcher: ItemFetcher::new(ItemKind::QuorumSet),
+            peers: HashMap::new(),
+            flood_gate: None,
+            shutting_down: false,
         };
 
         Box::new(actor)
     }
 
-    pub fn props() -> BoxActorProd<AstroProtocol> {
-        Props::new(Box::new(OverlayManagerActor::new))
+    pub fn props(reactor: Reactor) -> BoxActorProd<AstroProtocol> {
+        Props::new_args(Box::new(OverlayManagerActor::new), reactor)
     }
 
     /// Run FloodGate
     pub fn run_flood_gate(&mut self, ctx: &Context<AstroProtocol>) {
-        ctx.system
-            .actor_of(FloodGateActor::props(), "flood_gate")
+        let flood_gate = ctx
+            .system
+            .actor_of(
+                FloodGateActor::props(OverlayManagerRef::new(ctx.myself())),
+                "flood_gate",
+            )
             .unwrap();
+        self.flood_gate = Some(FloodGateRef::new(flood_gate));
     }
 
     /// Run Listener actor for checking incoming connections
     pub fn run_listener_actor(&mut self, ctx: &Context<AstroProtocol>) {
         ctx.system
-            .actor_of(OverlayListenerActor::props(), "overlay_connection_listener")
+            .actor_of(
+                OverlayListenerActor::props(
+                    self.reactor.clone(),
+                    OverlayManagerRef::new(ctx.myself()),
+                ),
+                "overlay_connection_listener",
+            )
             .unwrap();
     }
 
+    fn tell_peer(&self, address: &SocketAddr, msg: PeerMsg) {
+        match self.peers.get(address) {
+            Some(peer) => peer.tell(msg),
+            None => debug!("[Overlay] no actor of peer {} for {:?}", address, msg),
+        }
+    }
+
+    fn tell_flood_gate(&self, msg: FloodGateMsg) {
+        if let Some(ref flood_gate) = self.flood_gate {
+            flood_gate.tell(msg);
+        }
+    }
+
+    /// Actors of authenticated peers, messages are flooded to them
+    fn authenticated_peer_refs(&self) -> HashMap<SocketAddr, PeerRef> {
+        self.state
+            .authenticated_peers()
+            .iter()
+            .filter_map(|address| self.peers.get(address).map(|peer| (*address, peer.clone())))
+            .collect()
+    }
+
+    /// Start peer actor as a child of manager
+    fn spawn_peer(
+        &mut self,
+        ctx: &Context<AstroProtocol>,
+        address: SocketAddr,
+        props: BoxActorProd<AstroProtocol>,
+    ) {
+        match ctx.actor_of(props, &peer_actor_name(&address)) {
+            Ok(peer) => {
+                self.peers.insert(address, PeerRef::new(peer));
+            }
+            Err(_) => {
+                warn!("[Overlay] unable to start actor of peer {}", address);
+                self.state.remove_pending_peer(&address);
+            }
+        }
+    }
+
     /// Run sheduler for checking minimal count of connections with peers
     pub fn run_periodic_checker(&mut self, ctx: &Context<AstroProtocol>) {
         let delay = Duration::from_millis(1000);
-        ctx.schedule_once(
+        OverlayManagerRef::new(ctx.myself()).schedule_once(
+            ctx,
             delay,
-            ctx.myself(),
-            None,
-            AstroProtocol::CheckOverlayMinConnectionsCmd,
+            OverlayManagerMsg::CheckOverlayMinConnectionsCmd,
+        );
+    }
+
+    /// Sign new AuthCert shortly before the current one expires, so connections
+    /// don't have to wait for it
+    pub fn refresh_auth_cert(&mut self, ctx: &Context<AstroProtocol>) {
+        if PEER_AUTH.refresh_if_expiring(&LOCAL_NODE) {
+            info!("[Overlay] AuthCert refreshed");
+        }
+
+        let delay = Duration::from_secs(60);
+        OverlayManagerRef::new(ctx.myself()).schedule_once(
+            ctx,
+            delay,
+            OverlayManagerMsg::RefreshAuthCertCmd,
         );
     }
 
     /// Check minimal connections
     pub fn check_min_connections(&mut self, ctx: &Context<AstroProtocol>) {
-        if self.state.reached_min_of_authenticated_peers() {
+        if self.shutting_down {
             return;
         }
 
-        let limit = self.state.peers_to_authenticated_min_limit() as usize;
-        let taked_peers: Vec<_> = self
-            .state
-            .known_peer_adresses()
-            .iter()
-            .cloned()
-            .take(limit)
-            .collect();
+        // keep checking, connected peers could drop later
+        self.run_periodic_checker(ctx);
 
-        for peer in taked_peers {
-            self.state.move_peer_to_pending_list(peer.to_owned());
+        // preferred peers are dialed whenever they are not connected
+        for peer in self.state.preferred_peers_to_connect() {
+            self.state.move_peer_to_pending_list(peer);
             self.handle_new_initiated_peer(ctx, peer);
         }
 
-        self.run_periodic_checker(ctx);
+        let limit = self.state.outbound_peers_to_target();
+        if *CONFIG.preferred_peers_only() || limit == 0 {
+            return;
+        }
+
+        let taked_peers = self.state.peers_ready_to_connect(limit);
+
+        for peer in taked_peers {
+            self.state.move_peer_to_pending_list(peer);
+            self.handle_new_initiated_peer(ctx, peer);
+        }
     }
 
-    pub fn handle_new_incoming_peer(&mut self, ctx: &Context<AstroProtocol>, peer: Peer) {
-        if self.state.reached_max_of_authenticated_peers() {
+    pub fn handle_new_incoming_peer(
+        &mut self,
+        ctx: &Context<AstroProtocol>,
+        connection: Connection,
+        address: SocketAddr,
+    ) {
+        // node ID is checked after Hello, limits after authentication
+        if self.shutting_down {
+            connection.close();
+        } else if *CONFIG.preferred_peers_only() && !self.state.may_be_preferred(&address.ip()) {
             info!(
-                "[Overlay][Listener] new incoming peer {} dropped, cause: limit of peers",
-                peer.peer_addr()
+                "[Overlay][Listener] new incoming peer {} dropped, cause: preferred peers only",
+                address
             );
+            connection.close();
         } else {
-            info!("[Overlay][Listener] new incoming peer {}", peer.peer_addr());
-            let name = peer_actor_name(&peer.peer_addr());
-            ctx.system
-                .actor_of(PeerActor::incoming_peer_props(peer), &name);
+            info!("[Overlay][Listener] new incoming peer {}", address);
+            let props = PeerActor::incoming_peer_props(
+                address,
+                self.reactor.clone(),
+                OverlayManagerRef::new(ctx.myself()),
+                connection,
+            );
+            self.spawn_peer(ctx, address, props);
         }
     }
 
-    pub fn handle_new_initiated_peer(&mut self, ctx: &Context<AstroProtocol>, address: String) {
-        let name = peer_actor_name(&address);
-        ctx.system
-            .actor_of(PeerActor::initiated_peer_props(address), &name);
+    pub fn handle_new_initiated_peer(&mut self, ctx: &Context<AstroProtocol>, address: SocketAddr) {
+        let props = PeerActor::initiated_peer_props(
+            address,
+            self.reactor.clone(),
+            OverlayManagerRef::new(ctx.myself()),
+        );
+        self.spawn_peer(ctx, address, props);
+    }
+
+    /// Keep only one connection per remote node ID
+    pub fn handle_peer_hello(&mut self, node_id: xdr::NodeId, connection: NodeConnection) {
+        let verdict = self.hello_verdict(node_id, &connection);
+        if let Err(e) = verdict {
+            info!(
+                "[Overlay] rejected peer {}, cause: {}",
+                connection.address, e
+            );
+        }
+
+        self.tell_peer(&connection.address, PeerMsg::HelloVerdictCmd(verdict));
     }
 
-    pub fn handle_incoming_message(
+    fn hello_verdict(
+        &mut self,
+        node_id: xdr::NodeId,
+        connection: &NodeConnection,
+    ) -> Result<(), PeerError> {
+        if BanManager::is_banned(&node_id) {
+            return Err(PeerError::Banned);
+        }
+
+        self.state
+            .check_admission(&node_id, connection, *CONFIG.preferred_peers_only())?;
+        let is_preferred = self.state.is_preferred(&node_id, connection);
+
+        match self
+            .state
+            .register_node_connection(&LOCAL_NODE.node_id(), node_id, *connection)
+        {
+            HelloVerdict::Accept => (),
+            HelloVerdict::Reject => return Err(PeerError::DuplicateConnection),
+            HelloVerdict::ReplaceExisting(existing) => self.tell_peer(
+                &existing,
+                PeerMsg::HelloVerdictCmd(Err(PeerError::DuplicateConnection)),
+            ),
+        }
+
+        if is_preferred {
+            self.state.add_preferred_connection(connection.address);
+        }
+        Ok(())
+    }
+
+    /// Authenticated peer takes inbound or outbound slot. Rejected one gets
+    /// a list of other peers to try, like stellar-core does
+    pub fn handle_authenticated_peer(&mut self, address: SocketAddr) {
+        match self.state.admit_authenticated(&address) {
+            Admission::Accept => (),
+            Admission::Evict(victim) => {
+                info!(
+                    "[Overlay] evicting peer {} to make room for preferred peer {}",
+                    victim, address
+                );
+                self.tell_peer(&victim, PeerMsg::DropPeerCmd(PeerError::Evicted));
+            }
+            Admission::Reject => {
+                let peers = self.state.peers_to_share(&address);
+                self.tell_peer(
+                    &address,
+                    PeerMsg::SendPeerMessageCmd(xdr::StellarMessage::Peers(peers)),
+                );
+                self.tell_peer(&address, PeerMsg::DropPeerCmd(PeerError::TooManyPeers));
+                return;
+            }
+        }
+
+        self.tell_peer(
+            &address,
+            PeerMsg::SendPeerMessageCmd(xdr::StellarMessage::GetPeers),
+        );
+        self.state.move_peer_to_authenticated_list(address)
+    }
+
+    /// Raise misbehavior score of peer node. Past the threshold the peer is
+    /// dropped through the FailedPeerCmd path, and its node is banned if enabled
+    pub fn handle_misbehavior(&mut self, address: SocketAddr, misbehavior: Misbehavior) {
+        let (node_id, score) =
+            match self
+                .state
+                .record_misbehavior(&address, misbehavior, Instant::now())
+            {
+                Some(result) => result,
+                None => return,
+            };
+
+        debug!(
+            "[Overlay] peer {} misbehaved: {}, score {}",
+            address, misbehavior, score
+        );
+        if score < *CONFIG.peer_misbehavior_threshold() {
+            return;
+        }
+
+        info!(
+            "[Overlay] dropping misbehaving peer {}, score {}",
+            address, score
+        );
+        if *CONFIG.ban_misbehaving_peers() && BanManager::ban_node(&node_id) {
+            info!("[Overlay] banned misbehaving peer {}", address);
+        }
+        self.tell_peer(&address, PeerMsg::DropPeerCmd(PeerError::Misbehaving));
+    }
+
+    /// Ban is already saved by command handler, drop connection with the node if we have one
+    pub fn handle_node_banned(&mut self, node_id: &xdr::NodeId) {
+        if let Some(connection) = self.state.node_connection(node_id) {
+            info!("[Overlay] dropping banned peer {}", connection.address);
+            self.tell_peer(&connection.address, PeerMsg::DropPeerCmd(PeerError::Banned));
+        }
+    }
+
+    /// Stop accepting connections, tell peers we are shutting down and save
+    /// them to database. Reactor stops once queued messages are written
+    pub fn shutdown(&mut self) {
+        if self.shutting_down {
+            return;
+        }
+        self.shutting_down = true;
+        info!("[Overlay] shutting down");
+
+        self.reactor.stop_listening();
+        self.tell_flood_gate(FloodGateMsg::ShutdownFloodGateCmd);
+
+        let saved = self.state.save_peers();
+        info!("[Overlay] saved {} connected peers", saved);
+
+        for peer in self.peers.values() {
+            peer.tell(PeerMsg::DropPeerCmd(PeerError::ShuttingDown));
+        }
+
+        self.reactor.shutdown();
+    }
+
+    /// Connection with peer is closed, its actor is stopped if it is still running.
+    /// Peers dropped on shutdown or as duplicates aren't failed ones, so their
+    /// backoff isn't raised
+    fn handle_closed_peer(
         &mut self,
         ctx: &Context<AstroProtocol>,
-        address: String,
-        message: xdr::StellarMessage,
+        address: SocketAddr,
+        is_failed: bool,
     ) {
+        if self.shutting_down || !is_failed {
+            self.state.remove_authenticated_peer(&address);
+            self.state.remove_pending_peer(&address);
+        } else {
+            self.state.move_peer_to_failed_list(address);
+        }
+        self.state.forget_node_connection(&address);
+
+        if let Some(peer) = self.peers.remove(&address) {
+            ctx.system.stop(peer.actor());
+        }
+    }
+
+    fn fetcher(&mut self, kind: ItemKind) -> &mut ItemFetcher<ItemWaiter> {
+        match kind {
+            ItemKind::TxSet => &mut self.tx_set_fetcher,
+            ItemKind::QuorumSet => &mut self.quorum_set_fetcher,
+        }
+    }
+
+    fn fetch_peers(&self) -> Vec<SocketAddr> {
+        self.state.authenticated_peers().iter().cloned().collect()
+    }
+
+    fn send_fetch_request(&self, request: Option<(SocketAddr, xdr::StellarMessage)>) {
+        if let Some((peer, message)) = request {
+            self.tell_peer(&peer, PeerMsg::SendPeerMessageCmd(message));
+        }
+    }
+
+    pub fn fetch_item(&mut self, kind: ItemKind, hash: xdr::Hash, waiter: Option<ItemWaiter>) {
+        if let Some(item) = self.fetcher(kind).get(&hash).cloned() {
+            if let Some(waiter) = waiter {
+                waiter.notify(item);
+            }
+            return;
+        }
+
+        let peers = self.fetch_peers();
+        let request = self
+            .fetcher(kind)
+            .fetch(hash, waiter, &peers, Instant::now());
+        self.send_fetch_request(request);
+    }
+
+    /// Waiters of item which isn't needed anymore aren't notified
+    pub fn stop_fetching_item(&mut self, kind: ItemKind, hash: &xdr::Hash) {
+        self.fetcher(kind).stop_fetching(hash);
+    }
+
+    /// Ask next peers for items whose requests timed out and schedule the next check.
+    /// Waiters of items nobody sent us are told the fetch is given up
+    pub fn check_item_fetchers(&mut self, ctx: &Context<AstroProtocol>) {
+        let peers = self.fetch_peers();
+        let now = Instant::now();
+        for kind in &[ItemKind::TxSet, ItemKind::QuorumSet] {
+            let (requests, waiters) = self.fetcher(*kind).check_timeouts(&peers, now);
+            for request in requests {
+                self.send_fetch_request(Some(request));
+            }
+            for waiter in waiters {
+                waiter.give_up();
+            }
+        }
+
+        OverlayManagerRef::new(ctx.myself()).schedule_once(
+            ctx,
+            ITEM_FETCHERS_CHECK_INTERVAL,
+            OverlayManagerMsg::CheckItemFetchersCmd,
+        );
+    }
+
+    /// Answer GET request from cache, or tell peer we don't have the item
+    fn handle_item_request(&mut self, address: SocketAddr, kind: ItemKind, hash: xdr::Uint256) {
+        let message = match self.fetcher(kind).get(&xdr::Hash(hash.0)) {
+            Some(item) => item.to_message(),
+            None => xdr::StellarMessage::DontHave(xdr::DontHave {
+                type_: kind.message_type(),
+                req_hash: hash,
+            }),
+        };
+        self.tell_peer(&address, PeerMsg::SendPeerMessageCmd(message));
+    }
+
+    fn handle_received_item(&mut self, item: Item) {
+        match self.fetcher(item.kind()).recv_item(item.clone()) {
+            Some(waiters) => {
+                for waiter in waiters {
+                    waiter.notify(item.clone());
+                }
+            }
+            None => debug!("[Overlay] dropped {:?} which wasn't requested", item.kind()),
+        }
+    }
+
+    fn handle_dont_have(&mut self, address: SocketAddr, dont_have: &xdr::DontHave) {
+        if let Some(kind) = ItemKind::from_message_type(dont_have.type_) {
+            let peers = self.fetch_peers();
+            let hash = xdr::Hash(dont_have.req_hash.0);
+            let request = self
+                .fetcher(kind)
+                .doesnt_have(&hash, &address, &peers, Instant::now());
+            self.send_fetch_request(request);
+        }
+    }
+
+    /// Flood message received from peer to everyone else
+    fn flood(&mut self, message: xdr::StellarMessage, address: SocketAddr) {
+        self.tell_flood_gate(FloodGateMsg::AddRecordAndBroadcastFloodGateCmd(
+            message,
+            address,
+            self.authenticated_peer_refs(),
+        ));
+    }
+
+    pub fn handle_incoming_message(&mut self, address: SocketAddr, message: xdr::StellarMessage) {
         match message {
+            xdr::StellarMessage::Peers(ref set_of_peers)
+                if set_of_peers.len() > MAX_PEERS_IN_MESSAGE =>
+            {
+                self.handle_misbehavior(address, Misbehavior::OversizedData)
+            }
             xdr::StellarMessage::Peers(ref set_of_peers) => {
                 self.state.add_known_peers(set_of_peers);
             }
-            xdr::StellarMessage::Transaction(_) | xdr::StellarMessage::Envelope(_) => {
-                let flood_gate = flood_gate_ref(ctx);
-                flood_gate.tell(
-                    AstroProtocol::AddRecordFloodGateCmd(message.to_owned(), address, unix_time()),
-                    None,
+            xdr::StellarMessage::GetPeers => {
+                let peers = self.state.peers_to_share(&address);
+                self.tell_peer(
+                    &address,
+                    PeerMsg::SendPeerMessageCmd(xdr::StellarMessage::Peers(peers)),
                 );
-                flood_gate.tell(
-                    AstroProtocol::BroadcastFloodGateCmd(
-                        message.to_owned(),
-                        false,
-                        self.state.authenticated_peers().clone(),
-                    ),
-                    None,
+            }
+            xdr::StellarMessage::Envelope(ref envelope)
+                if !verify_envelope(LOCAL_NODE.network_id(), envelope) =>
+            {
+                debug!(
+                    "[Overlay] dropped SCP envelope from {} with invalid signature",
+                    address
                 );
-                flood_gate.tell(AstroProtocol::ClearFloodGateCmd(unix_time()), None);
+                self.handle_misbehavior(address, Misbehavior::InvalidSignature)
+            }
+            xdr::StellarMessage::Transaction(_) | xdr::StellarMessage::Envelope(_) => {
+                self.flood(message, address)
+            }
+            xdr::StellarMessage::TxSetHash(hash) => {
+                self.handle_item_request(address, ItemKind::TxSet, hash)
+            }
+            xdr::StellarMessage::QSetHash(hash) => {
+                self.handle_item_request(address, ItemKind::QuorumSet, hash)
+            }
+            xdr::StellarMessage::TxSet(tx_set) => self.handle_received_item(Item::TxSet(tx_set)),
+            xdr::StellarMessage::QSet(quorum_set) => {
+                self.handle_received_item(Item::QuorumSet(quorum_set))
+            }
+            xdr::StellarMessage::DontHave(ref dont_have) => {
+                self.handle_dont_have(address, dont_have)
             }
             _ => (),
         }
@@ -127,44 +512,82 @@ impl Actor for OverlayManagerActor {
     fn pre_start(&mut self, _ctx: &Context<Self::Msg>) {
         self.state = OverlayManager::new();
         self.state.populate_known_peers_from_db();
+        self.state.load_preferred_peers();
     }
 
     fn receive(
         &mut self,
         ctx: &Context<Self::Msg>,
         msg: Self::Msg,
-        sender: Option<ActorRef<Self::Msg>>,
+        _sender: Option<ActorRef<Self::Msg>>,
     ) {
+        let msg = match msg {
+            AstroProtocol::OverlayManager(msg) => msg,
+            msg => {
+                warn!("[Overlay] unexpected message: {:?}", msg);
+                return;
+            }
+        };
+
         match msg {
-            AstroProtocol::CheckOverlayMinConnectionsCmd => self.check_min_connections(ctx),
-            AstroProtocol::HandleOverlayIncomingPeerCmd(peer) => {
-                self.handle_new_incoming_peer(ctx, *peer)
+            OverlayManagerMsg::CheckOverlayMinConnectionsCmd => self.check_min_connections(ctx),
+            OverlayManagerMsg::HandleOverlayIncomingPeerCmd(connection, address) => {
+                self.handle_new_incoming_peer(ctx, connection, address)
+            }
+            OverlayManagerMsg::ReceivedPeerMessageCmd(address, message) => {
+                self.handle_incoming_message(address, message)
+            }
+            OverlayManagerMsg::PeerHelloCmd(node_id, connection) => {
+                self.handle_peer_hello(node_id, connection)
             }
-            AstroProtocol::ReceivedPeerMessageCmd(address, message) => {
-                self.handle_incoming_message(ctx, address, message)
+            OverlayManagerMsg::NodeBannedCmd(node_id) => self.handle_node_banned(&node_id),
+            OverlayManagerMsg::AuthPeerOkCmd(address) => self.handle_authenticated_peer(address),
+            OverlayManagerMsg::MisbehavedPeerCmd(address, misbehavior) => {
+                self.handle_misbehavior(address, misbehavior)
             }
-            AstroProtocol::AuthPeerOkCmd(address) => {
-                self.state.move_peer_to_authenticated_list(address)
+            OverlayManagerMsg::TimedOutPeerCmd(address, timeout) => {
+                info!("[Overlay] peer {} dropped, cause: {}", address, timeout);
+                self.handle_closed_peer(ctx, address, true)
             }
-            AstroProtocol::FailedPeerCmd(address) => {
-                self.state.move_peer_to_failed_list(address);
-                ctx.system.stop(&sender.unwrap());
+            OverlayManagerMsg::FailedPeerCmd(address) => {
+                self.handle_closed_peer(ctx, address, true)
             }
-            _ => unreachable!(),
+            OverlayManagerMsg::DuplicatePeerCmd(address) => {
+                self.handle_closed_peer(ctx, address, false)
+            }
+            OverlayManagerMsg::StoppedPeerCmd(address) => {
+                info!("[Overlay] peer {} stopped unexpectedly", address);
+                self.handle_closed_peer(ctx, address, true)
+            }
+            OverlayManagerMsg::RefreshAuthCertCmd => self.refresh_auth_cert(ctx),
+            OverlayManagerMsg::FetchItemCmd(kind, hash, waiter) => {
+                self.fetch_item(kind, hash, Some(waiter))
+            }
+            OverlayManagerMsg::CacheItemCmd(item) => self.fetcher(item.kind()).add_to_cache(item),
+            OverlayManagerMsg::StopFetchingItemCmd(kind, hash) => {
+                self.stop_fetching_item(kind, &hash)
+            }
+            OverlayManagerMsg::CheckItemFetchersCmd => self.check_item_fetchers(ctx),
+            OverlayManagerMsg::ShutdownCmd => self.shutdown(),
         }
     }
 
+    /// Peer actors are children of manager. A failed one is stopped, so
+    /// it is cleaned up alone and the rest of overlay keeps running
+    fn supervisor_strategy(&self) -> Strategy {
+        Strategy::Stop
+    }
+
     fn post_start(&mut self, ctx: &Context<Self::Msg>) {
+        // peers requesting our quorum set by its hash get it from cache
+        if let Some(quorum_set) = CONFIG.quorum_set() {
+            self.quorum_set_fetcher
+                .add_to_cache(Item::QuorumSet(quorum_set.clone()));
+        }
+        self.refresh_auth_cert(ctx);
         self.run_listener_actor(ctx);
         self.run_periodic_checker(ctx);
         self.run_flood_gate(ctx);
+        self.check_item_fetchers(ctx);
     }
 }
-
-// stub for current ledger value
-fn unix_time() -> u32 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32
-}
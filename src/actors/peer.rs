@@ -1,34 +1,273 @@
-use super::{debug, overlay_manager_ref, riker::actors::*, AstroProtocol, Peer, PeerInterface};
-use std::time::Duration;
+use super::{
+    debug, riker::actors::*, warn, xdr, AstroProtocol, Connection, ConnectionEvent,
+    MessageCategory, Misbehavior, NodeConnection, OverlayManagerMsg, OverlayManagerRef, Peer,
+    PeerError, PeerInterface, PeerMsg, PeerRateLimiter, PeerRef, PeerTimeout, PeerTimeouts,
+    RateCheck, Reactor, METRICS,
+};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
+/// How often peer timeouts are checked
+const TIMEOUTS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lifecycle of peer actor
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PeerStage {
+    /// We called remote peer and wait for connection to be established
+    Connecting,
+    /// Connection is established, Hello and Auth are exchanged
+    Authenticating,
+    /// Peer is authenticated and exchanges overlay messages
+    Active,
+    /// Connection is closed and manager is told about it, actor waits to be stopped
+    Closing,
+}
+
+/**
+ * PeerActor owns connection with one peer. It is a child of overlay manager,
+ * which stops it once the connection is closed. If the actor stops in any
+ * other stage, e.g. it panicked and supervisor stopped it, manager is told
+ * from `post_stop`, so only this peer is cleaned up.
+ */
 #[derive(Debug)]
 pub struct PeerActor {
-    address: Option<String>,
+    address: SocketAddr,
+    reactor: Reactor,
+    overlay_manager: OverlayManagerRef,
+    stage: PeerStage,
+    /// Connection accepted by listener, None if we initiate connection
+    incoming_connection: Option<Connection>,
     peer: Option<Peer>,
+    timeouts: PeerTimeouts,
+    rate_limiter: PeerRateLimiter,
+    /// When connection was created, closed ones report their duration
+    started_at: Instant,
 }
 
 impl PeerActor {
-    pub fn new((address, peer): (Option<String>, Option<Peer>)) -> BoxActor<AstroProtocol> {
-        let actor = PeerActor { address, peer };
+    pub fn new(
+        (address, reactor, overlay_manager, incoming_connection): (
+            SocketAddr,
+            Reactor,
+            OverlayManagerRef,
+            Option<Connection>,
+        ),
+    ) -> BoxActor<AstroProtocol> {
+        let actor = PeerActor {
+            address,
+            reactor,
+            overlay_manager,
+            stage: PeerStage::Connecting,
+            incoming_connection,
+            peer: None,
+            timeouts: PeerTimeouts::from_config(),
+            rate_limiter: PeerRateLimiter::new(Instant::now()),
+            started_at: Instant::now(),
+        };
 
         Box::new(actor)
     }
 
-    pub fn initiated_peer_props(address: String) -> BoxActorProd<AstroProtocol> {
-        Props::new_args(Box::new(PeerActor::new), (Some(address), None))
+    pub fn initiated_peer_props(
+        address: SocketAddr,
+        reactor: Reactor,
+        overlay_manager: OverlayManagerRef,
+    ) -> BoxActorProd<AstroProtocol> {
+        Props::new_args(
+            Box::new(PeerActor::new),
+            (address, reactor, overlay_manager, None),
+        )
+    }
+
+    pub fn incoming_peer_props(
+        address: SocketAddr,
+        reactor: Reactor,
+        overlay_manager: OverlayManagerRef,
+        connection: Connection,
+    ) -> BoxActorProd<AstroProtocol> {
+        Props::new_args(
+            Box::new(PeerActor::new),
+            (address, reactor, overlay_manager, Some(connection)),
+        )
+    }
+
+    /// Move to Closing stage and tell manager why, only once
+    fn close(&mut self, notice: OverlayManagerMsg) {
+        if self.stage == PeerStage::Closing {
+            return;
+        }
+        self.stage = PeerStage::Closing;
+        self.overlay_manager.tell(notice);
+    }
+
+    /// Close connection and ask manager to stop us. Connection lost to a
+    /// duplicate isn't a failure of the address, so manager is told it apart
+    fn drop_peer(&mut self, error: Option<PeerError>) {
+        if let Some(peer) = self.peer.take() {
+            peer.close();
+        }
+        let notice = match error {
+            Some(PeerError::DuplicateConnection) => {
+                OverlayManagerMsg::DuplicatePeerCmd(self.address)
+            }
+            _ => OverlayManagerMsg::FailedPeerCmd(self.address),
+        };
+        self.close(notice);
+    }
+
+    fn schedule_timeouts_check(&self, ctx: &Context<AstroProtocol>) {
+        PeerRef::new(ctx.myself()).schedule_once(
+            ctx,
+            TIMEOUTS_CHECK_INTERVAL,
+            PeerMsg::CheckPeerTimeoutsCmd,
+        );
+    }
+
+    /// Drop peer which stopped making progress, otherwise keep it alive and check again later
+    fn check_timeouts(&mut self, ctx: &Context<AstroProtocol>) {
+        let peer = match self.peer {
+            Some(ref mut peer) => peer,
+            None => return,
+        };
+
+        let timeout = match peer.check_timeouts(&self.timeouts) {
+            Some(timeout) => timeout,
+            None => {
+                peer.send_keepalive_if_needed(&self.timeouts);
+                self.schedule_timeouts_check(ctx);
+                return;
+            }
+        };
+
+        debug!(
+            "[Overlay][Peer] dropping peer {}, cause: {}",
+            self.address, timeout
+        );
+        if timeout == PeerTimeout::Authentication {
+            METRICS.record_handshake("AuthenticationTimeout");
+        }
+        peer.abort();
+        self.peer = None;
+        self.close(OverlayManagerMsg::TimedOutPeerCmd(self.address, timeout));
+    }
+
+    fn tell_misbehavior(&self, misbehavior: Misbehavior) {
+        self.overlay_manager
+            .tell(OverlayManagerMsg::MisbehavedPeerCmd(
+                self.address,
+                misbehavior,
+            ));
     }
 
-    pub fn incoming_peer_props(peer: Peer) -> BoxActorProd<AstroProtocol> {
-        Props::new_args(Box::new(PeerActor::new), (None, Some(peer)))
+    /// Messages over the rate limit of their category are dropped,
+    /// the first one in a window is reported as misbehavior
+    fn is_within_rate_limit(&mut self, message: &xdr::StellarMessage) -> bool {
+        let category = MessageCategory::of(message);
+        match self
+            .rate_limiter
+            .check(category, category.rate_limit(), Instant::now())
+        {
+            RateCheck::Within => true,
+            RateCheck::Exceeded => {
+                self.tell_misbehavior(Misbehavior::RateLimitExceeded(category));
+                false
+            }
+            RateCheck::StillExceeded => false,
+        }
     }
 
-    pub fn repeateable_read(&self, ctx: &Context<AstroProtocol>) {
-        let delay = Duration::from_millis(200);
-        ctx.schedule_once(delay, ctx.myself(), None, AstroProtocol::ServePeerCmd);
+    /// Reactor delivers connection events to actor mailbox
+    fn connection_sink(ctx: &Context<AstroProtocol>) -> Box<dyn Fn(ConnectionEvent) + Send> {
+        let myself = PeerRef::new(ctx.myself());
+        Box::new(move |event| myself.tell(PeerMsg::ConnectionEventCmd(event)))
     }
 
-    pub fn tell_peer_failed(&self, address: String, ctx: &Context<AstroProtocol>) {
-        overlay_manager_ref(ctx).tell(AstroProtocol::FailedPeerCmd(address), Some(ctx.myself()));
+    fn handle_connection_event(&mut self, event: ConnectionEvent) {
+        let peer = match self.peer {
+            Some(ref mut peer) => peer,
+            None => return,
+        };
+
+        match event {
+            ConnectionEvent::Connected => {
+                peer.start_authentication();
+                self.stage = PeerStage::Authenticating;
+            }
+            ConnectionEvent::Record(record) => self.handle_record(&record),
+            ConnectionEvent::Drained => peer.record_drained(),
+            ConnectionEvent::Closed(reason) => {
+                debug!(
+                    "[Overlay][Peer] connection with {} closed, cause: {}",
+                    self.address, reason
+                );
+                self.peer = None;
+                self.close(OverlayManagerMsg::FailedPeerCmd(self.address));
+            }
+        }
+    }
+
+    fn handle_record(&mut self, record: &[u8]) {
+        let peer = match self.peer {
+            Some(ref mut peer) => peer,
+            None => return,
+        };
+
+        let message: xdr::StellarMessage = match peer.receive_message(record) {
+            Ok(msg) => msg.into(),
+            Err(e) => {
+                debug!("Cant read XDR message cause: {}", e);
+                if let Some(misbehavior) = e.misbehavior() {
+                    self.tell_misbehavior(misbehavior);
+                }
+                self.drop_peer(None);
+                return;
+            }
+        };
+
+        if peer.is_authenticated() {
+            if self.is_within_rate_limit(&message) {
+                self.overlay_manager
+                    .tell(OverlayManagerMsg::ReceivedPeerMessageCmd(
+                        self.address,
+                        message,
+                    ));
+            }
+            return;
+        }
+
+        if let Err(e) = peer.handle_handshake_message(message) {
+            METRICS.record_handshake(&format!("{:?}", e));
+            self.drop_peer(Some(e));
+            return;
+        }
+
+        if peer.is_awaiting_verdict() {
+            self.overlay_manager.tell(OverlayManagerMsg::PeerHelloCmd(
+                *peer.remote_node_id(),
+                NodeConnection {
+                    address: self.address,
+                    listening_address: peer.remote_listening_address(),
+                    we_called_remote: peer.we_called_remote(),
+                },
+            ));
+        } else if peer.is_authenticated() {
+            self.stage = PeerStage::Active;
+            METRICS.record_handshake("success");
+            self.overlay_manager
+                .tell(OverlayManagerMsg::AuthPeerOkCmd(self.address));
+        }
+    }
+
+    fn handle_hello_verdict(&mut self, verdict: Result<(), PeerError>) {
+        match (self.peer.as_mut(), verdict) {
+            (Some(peer), Ok(())) => peer.continue_handshake(),
+            (Some(peer), Err(e)) => {
+                METRICS.record_handshake(&format!("{:?}", e));
+                peer.reject_handshake(e);
+                self.drop_peer(Some(e));
+            }
+            (None, _) => (),
+        }
     }
 }
 
@@ -41,64 +280,72 @@ impl Actor for PeerActor {
         msg: Self::Msg,
         _sender: Option<ActorRef<Self::Msg>>,
     ) {
-        match msg {
-            AstroProtocol::ServePeerCmd => {
-                match self.peer.as_mut().unwrap().receive_message() {
-                    Ok(msg) => {
-                        overlay_manager_ref(ctx).tell(
-                            AstroProtocol::ReceivedPeerMessageCmd(
-                                self.peer.as_ref().unwrap().address().to_owned(),
-                                msg.into(),
-                            ),
-                            Some(ctx.myself()),
-                        );
-                        self.repeateable_read(ctx);
-                    }
-                    Err(e) => {
-                        debug!("Cant read XDR message cause: {}", e);
-                        overlay_manager_ref(ctx).tell(
-                            AstroProtocol::FailedPeerCmd(
-                                self.peer.as_ref().unwrap().address().to_owned(),
-                            ),
-                            Some(ctx.myself()),
-                        );
-                    }
-                };
+        let msg = match msg {
+            AstroProtocol::Peer(msg) => msg,
+            msg => {
+                warn!(
+                    "[Overlay][Peer] unexpected message for peer {}: {:?}",
+                    self.address, msg
+                );
+                return;
             }
-            AstroProtocol::SendPeerMessageCmd(message) => {
-                self.peer.as_mut().unwrap().send_message(message);
+        };
+
+        match msg {
+            PeerMsg::ConnectionEventCmd(event) => self.handle_connection_event(event),
+            PeerMsg::HelloVerdictCmd(verdict) => self.handle_hello_verdict(verdict),
+            PeerMsg::CheckPeerTimeoutsCmd => self.check_timeouts(ctx),
+            PeerMsg::DropPeerCmd(error) => {
+                // peer could be already dropped, manager is told about it only once
+                if let Some(ref mut peer) = self.peer {
+                    debug!(
+                        "[Overlay][Peer] dropping peer {}, cause: {}",
+                        self.address, error
+                    );
+                    peer.send_error_message(error);
+                    self.drop_peer(Some(error));
+                }
             }
-            _ => unreachable!(),
+            PeerMsg::SendPeerMessageCmd(message) => match self.peer {
+                Some(ref mut peer) if self.stage == PeerStage::Active => peer.send_message(message),
+                _ => (),
+            },
         }
     }
 
     fn post_start(&mut self, ctx: &Context<Self::Msg>) {
-        if self.address.is_some() && self.peer.is_none() {
-            let result = Peer::connect_to(self.address.as_ref().unwrap().to_owned());
-            if result.is_ok() {
-                let mut peer = result.unwrap();
-                peer.start_authentication(true);
-                if peer.is_authenticated() {
-                    self.peer = Some(peer);
-                }
+        self.started_at = Instant::now();
+
+        let sink = Self::connection_sink(ctx);
+
+        let (connection, we_called_remote) = match self.incoming_connection.take() {
+            Some(connection) => {
+                // remote peer sends Hello first
+                connection.attach(sink);
+                (connection, false)
             }
-        } else if self.address.is_none() && self.peer.is_some() {
-            self.peer.as_mut().unwrap().start_authentication(false);
-        } else {
-            unreachable!()
+            None => (self.reactor.connect(self.address, sink), true),
+        };
+
+        let mut peer = Peer::new(connection, self.address, we_called_remote);
+        // outgoing connection starts authentication when it is established
+        if !we_called_remote {
+            peer.start_authentication();
+            self.stage = PeerStage::Authenticating;
         }
+        self.peer = Some(peer);
+        self.schedule_timeouts_check(ctx);
+    }
 
-        if let Some(ref peer) = self.peer {
-            if peer.is_authenticated() {
-                overlay_manager_ref(ctx).tell(
-                    AstroProtocol::AuthPeerOkCmd(self.peer.as_ref().unwrap().address().to_owned()),
-                    Some(ctx.myself()),
-                );
-                self.repeateable_read(ctx);
-                return;
-            }
-            self.tell_peer_failed(self.peer.as_ref().unwrap().address().to_owned(), ctx);
+    fn post_stop(&mut self) {
+        METRICS.record_connection_closed(&self.address, self.started_at.elapsed());
+
+        if let Some(peer) = self.peer.take() {
+            peer.close();
+        }
+        if self.stage != PeerStage::Closing {
+            self.overlay_manager
+                .tell(OverlayManagerMsg::StoppedPeerCmd(self.address));
         }
-        self.tell_peer_failed(self.address.as_ref().unwrap().to_owned(), ctx);
     }
 }
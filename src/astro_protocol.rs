@@ -1,35 +1,138 @@
-use crate::overlay::Peer;
+use crate::overlay::{
+    Connection, ConnectionEvent, Item, ItemKind, Misbehavior, NodeConnection, PeerError,
+    PeerTimeout,
+};
 use crate::xdr;
 use riker::actors::*;
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 /**
- * AstroProtocol contains all kind of messages we use for
- * communication between actors
-*/
-
+ * AstroProtocol is the message type of the actor system. Each subsystem has
+ * its own message enum wrapped in one of the variants, and actors are told
+ * through typed refs, like `PeerRef`, which accept only messages of their
+ * subsystem. Subsystem enums don't convert into actor messages on their own,
+ * so they can't be told to a raw `ActorRef`. A new subsystem (Herder, SCP,
+ * Ledger) adds its enum, a variant here and a typed ref.
+ *
+ * The typing is advisory only. Riker gives the whole actor system one message
+ * type, so every actor still has `type Msg = AstroProtocol` and its mailbox
+ * accepts any variant, e.g. from a raw ref of `ctx.myself()` or a sender.
+ * Actors log variants of other subsystems as unexpected and drop them.
+ */
 #[derive(Clone, Debug)]
 pub enum AstroProtocol {
-    /// New Peer incoming from Listener
-    HandleOverlayIncomingPeerCmd(Box<Peer>),
+    OverlayManager(OverlayManagerMsg),
+    Peer(PeerMsg),
+    FloodGate(FloodGateMsg),
+}
+
+/// Callback getting fetched item which hashes to the requested value, the
+/// reply to `FetchItemCmd`. Waiter tells it to its own typed ref, whatever
+/// subsystem it belongs to. It gets None if no peer sent the item
+#[derive(Clone)]
+pub struct ItemWaiter(Arc<dyn Fn(Option<Item>) + Send + Sync>);
+
+impl ItemWaiter {
+    pub fn new<F>(notify: F) -> Self
+    where
+        F: Fn(Option<Item>) + Send + Sync + 'static,
+    {
+        ItemWaiter(Arc::new(notify))
+    }
+
+    pub fn notify(&self, item: Item) {
+        (self.0)(Some(item))
+    }
+
+    /// Fetch is given up after the last round of asking peers
+    pub fn give_up(&self) {
+        (self.0)(None)
+    }
+}
+
+impl fmt::Debug for ItemWaiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ItemWaiter")
+    }
+}
+
+/// Messages handled by OverlayManagerActor
+#[derive(Clone, Debug)]
+pub enum OverlayManagerMsg {
+    /// New connection accepted by Listener
+    HandleOverlayIncomingPeerCmd(Connection, SocketAddr),
     /// Check connections limit
     CheckOverlayMinConnectionsCmd,
-    /// Awaiting incoming messag from remote peer
-    ServePeerCmd,
-    /// PeerActor must send message
-    SendPeerMessageCmd(xdr::StellarMessage),
     /// Received message from PeerActor
-    ReceivedPeerMessageCmd(String, xdr::StellarMessage),
+    ReceivedPeerMessageCmd(SocketAddr, xdr::StellarMessage),
+    /// PeerActor received valid Hello from node ID
+    PeerHelloCmd(xdr::NodeId, NodeConnection),
+    /// Operator banned node ID, connection with it must be dropped
+    NodeBannedCmd(xdr::NodeId),
     /// Authentication process between PeerActor and remote peer succeed
-    AuthPeerOkCmd(String),
+    AuthPeerOkCmd(SocketAddr),
     /// PeerActor interaction failed
-    FailedPeerCmd(String),
-    /// Add message from some peer at some seq_ledger
-    AddRecordFloodGateCmd(xdr::StellarMessage, String, u32),
+    FailedPeerCmd(SocketAddr),
+    /// PeerActor dropped connection which lost to another one with the same node
+    DuplicatePeerCmd(SocketAddr),
+    /// PeerActor dropped peer which stopped making progress
+    TimedOutPeerCmd(SocketAddr, PeerTimeout),
+    /// PeerActor stopped before telling manager its connection is closed, e.g. it panicked
+    StoppedPeerCmd(SocketAddr),
+    /// Peer misbehaved, its score is raised and it is dropped past the threshold
+    MisbehavedPeerCmd(SocketAddr, Misbehavior),
+    /// Re-sign our AuthCert if it expires soon
+    RefreshAuthCertCmd,
+    /// Fetch item by hash from peers, the waiter is notified when it arrives or
+    /// the fetch is given up
+    FetchItemCmd(ItemKind, xdr::Hash, ItemWaiter),
+    /// Stop fetching item which isn't needed anymore, its waiters aren't notified
+    StopFetchingItemCmd(ItemKind, xdr::Hash),
+    /// Keep our own item to answer peers requesting it
+    CacheItemCmd(Item),
+    /// Ask next peers for items whose requests timed out
+    CheckItemFetchersCmd,
+    /// Stop accepting connections, drop peers and save them to database
+    ShutdownCmd,
+}
+
+/// Messages handled by PeerActor
+#[derive(Clone, Debug)]
+pub enum PeerMsg {
+    /// Reactor event of PeerActor connection
+    ConnectionEventCmd(ConnectionEvent),
+    /// PeerActor must send message
+    SendPeerMessageCmd(xdr::StellarMessage),
+    /// Overlay manager decision whether PeerActor keeps connection with remote node
+    HelloVerdictCmd(Result<(), PeerError>),
+    /// PeerActor must notify remote peer with error and close connection
+    DropPeerCmd(PeerError),
+    /// PeerActor must check whether its peer stopped making progress
+    CheckPeerTimeoutsCmd,
+}
+
+/// Messages handled by FloodGateActor
+#[derive(Clone, Debug)]
+pub enum FloodGateMsg {
+    /// Add message from some peer at the current ledger and, if it is new,
+    /// broadcast it to authenticated peers which haven't got it
+    AddRecordAndBroadcastFloodGateCmd(
+        xdr::StellarMessage,
+        SocketAddr,
+        HashMap<SocketAddr, PeerRef>,
+    ),
     /// Broadcast message to authenticated peers
-    BroadcastFloodGateCmd(xdr::StellarMessage, bool, HashSet<String>),
-    /// Clear records in flood gate below transfered seq_ledger
-    ClearFloodGateCmd(u32),
+    BroadcastFloodGateCmd(xdr::StellarMessage, bool, HashMap<SocketAddr, PeerRef>),
+    /// Ledger with the sequence closed, flood gate records of older ledgers are cleared
+    LedgerClosedCmd(u32),
+    /// Clear records older than their TTL, fallback while no ledger closes
+    ExpireFloodGateRecordsCmd,
+    /// Stop flooding messages
+    ShutdownFloodGateCmd,
 }
 
 impl Into<ActorMsg<AstroProtocol>> for AstroProtocol {
@@ -37,3 +140,55 @@ impl Into<ActorMsg<AstroProtocol>> for AstroProtocol {
         ActorMsg::User(self)
     }
 }
+
+/// Ref of actor which accepts only messages of its subsystem
+macro_rules! typed_ref {
+    ($(#[$doc:meta])* $name:ident, $msg:ident, $variant:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug)]
+        pub struct $name(ActorRef<AstroProtocol>);
+
+        impl $name {
+            pub fn new(actor: ActorRef<AstroProtocol>) -> Self {
+                $name(actor)
+            }
+
+            pub fn tell(&self, msg: $msg) {
+                self.0.tell(AstroProtocol::$variant(msg), None);
+            }
+
+            /// Tell message after the delay, e.g. actor schedules its periodic check
+            pub fn schedule_once(
+                &self,
+                ctx: &Context<AstroProtocol>,
+                delay: Duration,
+                msg: $msg,
+            ) {
+                ctx.schedule_once(delay, self.0.clone(), None, AstroProtocol::$variant(msg));
+            }
+
+            pub fn actor(&self) -> &ActorRef<AstroProtocol> {
+                &self.0
+            }
+        }
+    };
+}
+
+typed_ref!(
+    /// Ref of OverlayManagerActor
+    OverlayManagerRef,
+    OverlayManagerMsg,
+    OverlayManager
+);
+typed_ref!(
+    /// Ref of PeerActor
+    PeerRef,
+    PeerMsg,
+    Peer
+);
+typed_ref!(
+    /// Ref of FloodGateActor
+    FloodGateRef,
+    FloodGateMsg,
+    FloodGate
+);
@@ -0,0 +1,364 @@
+use crate::{
+    actors,
+    config::{self, CONFIG},
+    crypto, database,
+    network::Network,
+    overlay::Item,
+    scp::local_node::LOCAL_NODE,
+    xdr,
+};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use ed25519_dalek::{PublicKey, SecretKey};
+use log::info;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use signal_hook::{iterator::Signals, SIGINT, SIGTERM};
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Time peers have to receive our queued messages before we exit anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// XDR types `print-xdr` can decode
+const XDR_FILE_TYPES: [&str; 7] = [
+    "tx",
+    "result",
+    "resultpair",
+    "meta",
+    "ledgerheader",
+    "scp",
+    "qset",
+];
+
+/// Command line interface, subcommands are modeled on stellar-core ones
+pub fn app() -> App<'static, 'static> {
+    App::new("astrocore")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Alternative implementation of stellar-core")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("conf")
+                .long("conf")
+                .value_name("FILE")
+                .global(true)
+                .help("Config file, config.toml in working directory by default"),
+        )
+        .subcommand(SubCommand::with_name("run").about("Run node, the default command"))
+        .subcommand(
+            SubCommand::with_name("new-db").about("Create database tables and load initial peers"),
+        )
+        .subcommand(
+            SubCommand::with_name("gen-seed").about("Generate and print a random node seed"),
+        )
+        .subcommand(
+            SubCommand::with_name("convert-id")
+                .about("Print key given as strkey or hex in other forms")
+                .arg(Arg::with_name("ID").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("print-xdr")
+                .about("Decode and print XDR file")
+                .arg(Arg::with_name("FILE").required(true))
+                .arg(
+                    Arg::with_name("filetype")
+                        .long("filetype")
+                        .value_name("TYPE")
+                        .possible_values(&XDR_FILE_TYPES)
+                        .default_value("tx"),
+                )
+                .arg(
+                    Arg::with_name("base64")
+                        .long("base64")
+                        .help("File holds base64 instead of raw XDR"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sign-transaction")
+                .about("Add signature to transaction envelope, secret seed is read from stdin")
+                .arg(Arg::with_name("FILE").required(true))
+                .arg(
+                    Arg::with_name("netid")
+                        .long("netid")
+                        .value_name("PASSPHRASE")
+                        .required(true)
+                        .help("Passphrase of network transaction is signed for"),
+                )
+                .arg(
+                    Arg::with_name("base64")
+                        .long("base64")
+                        .help("File holds base64 instead of raw XDR"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("http-command")
+                .about("Send command to running node, like `bans` or `ban?node=G...`")
+                .arg(Arg::with_name("COMMAND").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("offline-info").about("Print node info without running it"),
+        )
+}
+
+/// Run the subcommand, an error is printed and the process exits with 1
+pub fn execute(matches: &ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        ("new-db", _) => {
+            load_config()?;
+            database::new_db().map_err(|e| format!("unable to migrate: {}", e))
+        }
+        ("gen-seed", _) => {
+            println!("{}", gen_seed());
+            Ok(())
+        }
+        ("convert-id", Some(args)) => convert_id(args.value_of("ID").unwrap()).map(|info| {
+            println!("{}", info);
+        }),
+        ("print-xdr", Some(args)) => {
+            let data = read_xdr_file(args.value_of("FILE").unwrap(), args.is_present("base64"))?;
+            println!("{}", print_xdr(&data, args.value_of("filetype").unwrap())?);
+            Ok(())
+        }
+        ("sign-transaction", Some(args)) => {
+            let data = read_xdr_file(args.value_of("FILE").unwrap(), args.is_present("base64"))?;
+            let seed = read_seed()?;
+            let envelope = sign_transaction(&data, &seed, args.value_of("netid").unwrap())?;
+            println!("{}", envelope);
+            Ok(())
+        }
+        ("http-command", Some(args)) => {
+            load_config()?;
+            http_command(args.value_of("COMMAND").unwrap())
+        }
+        ("offline-info", _) => {
+            load_config()?;
+            println!("{}", offline_info());
+            Ok(())
+        }
+        _ => run(),
+    }
+}
+
+/// Config errors are reported before any subsystem touches it
+fn load_config() -> Result<(), String> {
+    config::load().map_err(|e| e.to_string())
+}
+
+/// Run node until SIGINT or SIGTERM, then shut it down gracefully
+fn run() -> Result<(), String> {
+    load_config()?;
+    database::init();
+    let signals =
+        Signals::new(&[SIGINT, SIGTERM]).map_err(|e| format!("unable to handle signals: {}", e))?;
+    let node = actors::start();
+
+    if let Some(signal) = signals.forever().next() {
+        info!("Received signal {}, shutting down", signal);
+    }
+
+    if node.shutdown(SHUTDOWN_TIMEOUT) {
+        info!("Shut down");
+        Ok(())
+    } else {
+        Err("shutdown timed out, exiting with connections still open".to_string())
+    }
+}
+
+fn gen_seed() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill(&mut bytes);
+    let secret = SecretKey::from_bytes(&bytes).unwrap();
+    let public = PublicKey::from(&secret);
+
+    format!(
+        "Secret seed: {}\nPublic: {}",
+        crypto::encode_secret_seed(secret.as_bytes()).unwrap(),
+        crypto::encode_account_id(public.as_bytes()).unwrap()
+    )
+}
+
+/// Key is a public key or seed strkey, or a public key in hex
+fn convert_id(id: &str) -> Result<String, String> {
+    if let Ok(public) = crypto::decode_account_id(id) {
+        return Ok(format!(
+            "PublicKey:\n  strKey: {}\n  hex: {}",
+            id,
+            hex::encode(public)
+        ));
+    }
+
+    if crypto::decode_secret_seed(id).is_ok() {
+        let key_pair = crypto::from_secret_seed(id).map_err(|_| "invalid seed".to_string())?;
+        let public = key_pair.public.as_bytes();
+        return Ok(format!(
+            "Seed:\n  strKey: {}\nPublicKey:\n  strKey: {}\n  hex: {}",
+            id,
+            crypto::encode_account_id(public).unwrap(),
+            hex::encode(public)
+        ));
+    }
+
+    match hex::decode(id) {
+        Ok(ref public) if public.len() == 32 => Ok(format!(
+            "PublicKey:\n  strKey: {}\n  hex: {}",
+            crypto::encode_account_id(public).unwrap(),
+            id
+        )),
+        _ => Err(format!("unknown key {}", id)),
+    }
+}
+
+fn read_xdr_file(path: &str, is_base64: bool) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    if !is_base64 {
+        return Ok(data);
+    }
+
+    let text = String::from_utf8_lossy(&data);
+    base64::decode(text.trim()).map_err(|e| format!("invalid base64 in {}: {}", path, e))
+}
+
+fn decode_xdr<T>(data: &[u8]) -> Result<String, String>
+where
+    T: Debug + serde::de::DeserializeOwned,
+{
+    let value: T = serde_xdr::from_bytes(data).map_err(|e| format!("invalid XDR: {}", e))?;
+    Ok(format!("{:#?}", value))
+}
+
+fn print_xdr(data: &[u8], file_type: &str) -> Result<String, String> {
+    match file_type {
+        "tx" => decode_xdr::<xdr::TransactionEnvelope>(data),
+        "result" => decode_xdr::<xdr::TransactionResult>(data),
+        "resultpair" => decode_xdr::<xdr::TransactionResultPair>(data),
+        "meta" => decode_xdr::<xdr::TransactionMeta>(data),
+        "ledgerheader" => decode_xdr::<xdr::LedgerHeader>(data),
+        "scp" => decode_xdr::<xdr::ScpEnvelope>(data),
+        "qset" => decode_xdr::<xdr::ScpQuorumSet>(data),
+        _ => Err(format!("unknown file type {}", file_type)),
+    }
+}
+
+fn read_seed() -> Result<String, String> {
+    eprint!("Secret seed: ");
+    io::stderr().flush().ok();
+
+    let mut seed = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut seed)
+        .map_err(|e| format!("unable to read seed: {}", e))?;
+    Ok(seed.trim().to_string())
+}
+
+/// Sign transaction the way stellar-core does and return the envelope in base64
+fn sign_transaction(data: &[u8], seed: &str, passphrase: &str) -> Result<String, String> {
+    let mut envelope: xdr::TransactionEnvelope =
+        serde_xdr::from_bytes(data).map_err(|e| format!("invalid transaction XDR: {}", e))?;
+    let key_pair = crypto::from_secret_seed(seed).map_err(|_| "invalid seed".to_string())?;
+    let network_id = Network::new(passphrase.to_string()).network_id();
+
+    // (network_id, EnvelopeTypeTx, tx) serialized to XDR
+    let mut payload = network_id;
+    serde_xdr::to_writer(&mut payload, &xdr::EnvelopeType::EnvelopeTypeTx).unwrap();
+    serde_xdr::to_writer(&mut payload, &envelope.tx).unwrap();
+    let hash = Sha256::digest(&payload);
+
+    let public = key_pair.public.to_bytes();
+    let mut hint = [0; 4];
+    hint.copy_from_slice(&public[28..]);
+    envelope.signatures.push(xdr::DecoratedSignature {
+        hint: xdr::SignatureHint(hint),
+        signature: xdr::Signature(key_pair.sign(&hash).to_bytes().to_vec()),
+    });
+
+    let mut signed = Vec::new();
+    serde_xdr::to_writer(&mut signed, &envelope).unwrap();
+    Ok(base64::encode(&signed))
+}
+
+/// Send command to operator interface of running node and print its reply
+fn http_command(command: &str) -> Result<(), String> {
+    let address = ("127.0.0.1", *CONFIG.http_port());
+    let mut stream = TcpStream::connect(address)
+        .map_err(|e| format!("unable to connect to node on port {}: {}", address.1, e))?;
+
+    write!(
+        stream,
+        "GET /{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        command.trim_start_matches('/')
+    )
+    .map_err(|e| format!("unable to send command: {}", e))?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| format!("unable to read reply: {}", e))?;
+
+    let mut parts = response.splitn(2, "\r\n\r\n");
+    let status = parts.next().unwrap_or_default();
+    let body = parts.next().unwrap_or_default();
+    if status.starts_with("HTTP/1.1 200") {
+        println!("{}", body);
+        Ok(())
+    } else {
+        Err(body.to_string())
+    }
+}
+
+fn offline_info() -> String {
+    let known_peers = database::Peer::all().map(|peers| peers.len()).unwrap_or(0);
+    let xdr::PublicKey::Ed25519(xdr::Uint256(node_key)) = LOCAL_NODE.node_id();
+    let quorum_set = match CONFIG.quorum_set() {
+        Some(quorum_set) => hex::encode(Item::QuorumSet(quorum_set.clone()).hash().0),
+        None => "none, watcher node".to_string(),
+    };
+
+    format!(
+        "version: {}\nnetwork: {}\nnode: {}\naddress: {}\nhttp port: {}\nknown peers: {}\n\
+         quorum set: {}",
+        env!("CARGO_PKG_VERSION"),
+        Network::network().passphrase(),
+        crypto::encode_account_id(&node_key).unwrap(),
+        CONFIG.local_node().address(),
+        CONFIG.http_port(),
+        known_peers,
+        quorum_set
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: &str = "SATKBWSRLPHGM2FKMLZ4QNH64XYKP7J2O6U5QNFBJHYEXDSHN75R5MVE";
+
+    #[test]
+    fn converts_public_key_between_strkey_and_hex() {
+        let key_pair = crypto::from_secret_seed(SEED).unwrap();
+        let hex_key = hex::encode(key_pair.public.as_bytes());
+        let strkey = crypto::encode_account_id(key_pair.public.as_bytes()).unwrap();
+
+        let from_hex = convert_id(&hex_key).unwrap();
+        assert!(from_hex.contains(&strkey));
+        assert_eq!(convert_id(&strkey).unwrap(), from_hex);
+        assert!(convert_id(SEED).unwrap().contains(&strkey));
+        assert!(convert_id("not a key").is_err());
+    }
+
+    #[test]
+    fn signs_transaction_envelope() {
+        let mut data = Vec::new();
+        serde_xdr::to_writer(&mut data, &xdr::TransactionEnvelope::default()).unwrap();
+
+        let signed = sign_transaction(&data, SEED, "Test SDF Network ; September 2015").unwrap();
+        let envelope: xdr::TransactionEnvelope =
+            serde_xdr::from_bytes(&base64::decode(&signed).unwrap()).unwrap();
+
+        assert_eq!(envelope.signatures.len(), 1);
+        let public = crypto::from_secret_seed(SEED).unwrap().public.to_bytes();
+        assert_eq!(envelope.signatures[0].hint.0, public[28..]);
+    }
+}
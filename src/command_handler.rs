@@ -0,0 +1,259 @@
+use crate::astro_protocol::{OverlayManagerMsg, OverlayManagerRef};
+use crate::config::CONFIG;
+use crate::crypto::{node_id_from_strkey, node_id_to_strkey};
+use crate::overlay::ban_manager::BanManager;
+use crate::overlay::METRICS;
+use crate::xdr;
+use log::{info, warn};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Connections are served one by one, so a client which doesn't send its
+/// request or read the response can't hold the others for longer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Operator command parsed from HTTP request line
+#[derive(Debug, PartialEq)]
+enum Command {
+    Ban(xdr::NodeId),
+    Unban(xdr::NodeId),
+    Bans,
+    /// Metrics of all peers, or only of the peer with given address
+    Metrics(Option<String>),
+}
+
+/**
+ * CommandHandler serves operator commands over HTTP on localhost, the same way
+ * stellar-core command interface does:
+ *
+ *  - /ban?node=NODE_ID drops connection with node and refuses it from now on
+ *  - /unban?node=NODE_ID removes the ban
+ *  - /bans lists banned node IDs
+ *  - /metrics[?peer=ADDRESS] exports overlay metrics in Prometheus text format,
+ *    ADDRESS is percent-encoded
+ */
+pub(crate) fn spawn(overlay_manager: OverlayManagerRef) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", *CONFIG.http_port()))?;
+
+    info!(
+        "[CommandHandler] listening commands on port {}",
+        CONFIG.http_port()
+    );
+
+    thread::Builder::new()
+        .name("command-handler".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => handle_connection(stream, &overlay_manager),
+                    Err(e) => warn!("[CommandHandler] unable to accept connection: {}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, overlay_manager: &OverlayManagerRef) {
+    if let Err(e) = stream
+        .set_read_timeout(Some(CLIENT_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+    {
+        warn!("[CommandHandler] unable to set connection timeouts: {}", e);
+        return;
+    }
+
+    let mut request_line = String::new();
+    if let Err(e) = BufReader::new(&stream).read_line(&mut request_line) {
+        warn!("[CommandHandler] unable to read request: {}", e);
+        return;
+    }
+
+    let (status, body) = match parse_command(&request_line) {
+        Ok(command) => execute(command, overlay_manager),
+        Err(e) => ("400 Bad Request", e),
+    };
+
+    if let Err(e) = write_response(stream, status, &body) {
+        warn!("[CommandHandler] unable to write response: {}", e);
+    }
+}
+
+fn execute(command: Command, overlay_manager: &OverlayManagerRef) -> (&'static str, String) {
+    match command {
+        Command::Ban(node_id) => {
+            if !BanManager::ban_node(&node_id) {
+                return (
+                    "500 Internal Server Error",
+                    "unable to save ban".to_string(),
+                );
+            }
+            overlay_manager.tell(OverlayManagerMsg::NodeBannedCmd(node_id));
+            ("200 OK", format!("banned {}", node_id_to_strkey(&node_id)))
+        }
+        Command::Unban(node_id) => {
+            if !BanManager::unban_node(&node_id) {
+                return (
+                    "500 Internal Server Error",
+                    "unable to remove ban".to_string(),
+                );
+            }
+            (
+                "200 OK",
+                format!("unbanned {}", node_id_to_strkey(&node_id)),
+            )
+        }
+        Command::Bans => ("200 OK", BanManager::bans().join("\n")),
+        Command::Metrics(peer) => ("200 OK", METRICS.export(peer.as_ref().map(String::as_str))),
+    }
+}
+
+/// Request line looks like `GET /ban?node=G... HTTP/1.1`
+fn parse_command(request_line: &str) -> Result<Command, String> {
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| "malformed request".to_string())?;
+
+    let mut parts = target.splitn(2, '?');
+    let path = parts.next().unwrap_or_default();
+    let query = parts.next().unwrap_or_default();
+
+    match path {
+        "/ban" => Ok(Command::Ban(node_param(query)?)),
+        "/unban" => Ok(Command::Unban(node_param(query)?)),
+        "/bans" => Ok(Command::Bans),
+        "/metrics" => Ok(Command::Metrics(
+            query_param(query, "peer").map(peer_param).transpose()?,
+        )),
+        _ => Err(format!("unknown command {}", path)),
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Peer address is percent-encoded, as IPv6 one has brackets and colons.
+/// It is formatted back the way metrics keep it
+fn peer_param(value: &str) -> Result<String, String> {
+    let address = percent_decode(value)?;
+    address
+        .parse::<SocketAddr>()
+        .map(|address| address.to_string())
+        .map_err(|_| format!("invalid peer address {}", address))
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    let invalid = || format!("invalid percent-encoding {}", value);
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+
+    while let Some(byte) = input.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+
+        let hex = [
+            input.next().ok_or_else(invalid)?,
+            input.next().ok_or_else(invalid)?,
+        ];
+        let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+        bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+    }
+
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+fn node_param(query: &str) -> Result<xdr::NodeId, String> {
+    let node =
+        query_param(query, "node").ok_or_else(|| "node parameter is required".to_string())?;
+
+    node_id_from_strkey(node).ok_or_else(|| format!("invalid node ID {}", node))
+}
+
+fn write_response(mut stream: TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factories::internal_xdr::build_public_key;
+
+    mod parse_command {
+        use super::*;
+
+        #[test]
+        fn ban_and_unban() {
+            let node_id = build_public_key();
+            let strkey = node_id_to_strkey(&node_id);
+
+            assert_eq!(
+                parse_command(&format!("GET /ban?node={} HTTP/1.1\r\n", strkey)),
+                Ok(Command::Ban(node_id))
+            );
+            assert_eq!(
+                parse_command(&format!("GET /unban?foo=1&node={} HTTP/1.1\r\n", strkey)),
+                Ok(Command::Unban(node_id))
+            );
+        }
+
+        #[test]
+        fn bans() {
+            assert_eq!(parse_command("GET /bans HTTP/1.1\r\n"), Ok(Command::Bans));
+        }
+
+        #[test]
+        fn metrics() {
+            assert_eq!(
+                parse_command("GET /metrics HTTP/1.1\r\n"),
+                Ok(Command::Metrics(None))
+            );
+            assert_eq!(
+                parse_command("GET /metrics?peer=54.1.2.3:11625 HTTP/1.1\r\n"),
+                Ok(Command::Metrics(Some("54.1.2.3:11625".to_string())))
+            );
+            assert_eq!(
+                parse_command("GET /metrics?peer=%5B2001%3Adb8%3A%3A1%5D%3A11625 HTTP/1.1\r\n"),
+                Ok(Command::Metrics(Some("[2001:db8::1]:11625".to_string())))
+            );
+        }
+
+        #[test]
+        fn rejects_invalid_peer() {
+            assert!(parse_command("GET /metrics?peer=%5B2001 HTTP/1.1\r\n").is_err());
+            assert!(parse_command("GET /metrics?peer=%zz HTTP/1.1\r\n").is_err());
+        }
+
+        #[test]
+        fn rejects_missing_or_invalid_node() {
+            assert!(parse_command("GET /ban HTTP/1.1\r\n").is_err());
+            assert!(parse_command("GET /ban?node=GABC HTTP/1.1\r\n").is_err());
+        }
+
+        #[test]
+        fn rejects_unknown_command() {
+            assert!(parse_command("GET /stop HTTP/1.1\r\n").is_err());
+            assert!(parse_command("\r\n").is_err());
+        }
+    }
+}
@@ -1,37 +1,511 @@
 #![allow(dead_code)]
 
+use crate::crypto::{self, node_id_from_strkey};
+use crate::scp::quorum_set;
+use crate::xdr;
 use lazy_static::lazy_static;
 use serde_derive::Deserialize;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use toml;
 
+/// Config is read from working directory unless `--conf` is given
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Lowest top level threshold which is safe, the same as stellar-core requires
+const SAFE_THRESHOLD_PERCENT: u32 = 67;
+
+/// Threshold of validators of one home domain and of sets below HIGH quality
+const SIMPLE_MAJORITY_PERCENT: u32 = 51;
+
+/// HIGH and CRITICAL quality home domains must be redundant
+const MIN_HIGH_QUALITY_VALIDATORS: usize = 3;
+
+/// Prefix of environment variables overriding config keys, e.g. `ASTROCORE_SEED`
+const ENV_PREFIX: &str = "ASTROCORE_";
+
+/// Separator of nested keys in environment variables, e.g. `ASTROCORE_LOCAL_NODE__PORT`
+const ENV_NESTING_SEPARATOR: &str = "__";
+
 lazy_static! {
+    /// File config is read from, it must be set before CONFIG is used
+    static ref CONFIG_PATH: Mutex<PathBuf> = Mutex::new(PathBuf::from(DEFAULT_CONFIG_PATH));
+    /// Config validated by `load`, so CONFIG takes it instead of reading file again
+    static ref LOADED_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
     #[derive(Debug)]
     pub static ref CONFIG: Config = Config::init();
 }
 
+/// Read config from the file instead of the default one
+pub fn set_path(path: &str) {
+    *CONFIG_PATH.lock().unwrap() = PathBuf::from(path);
+}
+
+/// Read and validate config before CONFIG is used, so errors are reported instead of panic
+pub fn load() -> Result<(), ConfigError> {
+    let config = Config::load(&CONFIG_PATH.lock().unwrap(), env::vars())?;
+    *LOADED_CONFIG.lock().unwrap() = Some(config);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Config file can't be read
+    Read(PathBuf, io::Error),
+    /// Config isn't valid TOML or its keys have wrong types
+    Parse(toml::de::Error),
+    /// Environment variable can't override config key
+    Env(String, String),
+    /// Config key has invalid value
+    Invalid(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => {
+                write!(f, "unable to read config {}: {}", path.display(), e)
+            }
+            ConfigError::Parse(e) => write!(f, "invalid config: {}", e),
+            ConfigError::Env(var, reason) => {
+                write!(f, "invalid environment variable {}: {}", var, reason)
+            }
+            ConfigError::Invalid(key, reason) => {
+                write!(f, "invalid config key {}: {}", key, reason)
+            }
+        }
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::Parse(err)
+    }
+}
+
+fn invalid<T>(key: &str, reason: &str) -> Result<T, ConfigError> {
+    Err(ConfigError::Invalid(key.to_string(), reason.to_string()))
+}
+
+/// Count of entries which makes the percent of them, rounded up as stellar-core does
+fn threshold_of(entries: usize, percent: u32) -> u32 {
+    ((entries as u32) * percent + 99) / 100
+}
+
+fn check_positive(key: &str, value: u64) -> Result<(), ConfigError> {
+    if value == 0 {
+        return invalid(key, "must be greater than 0");
+    }
+    Ok(())
+}
+
+fn check_port(key: &str, port: u64) -> Result<(), ConfigError> {
+    if port == 0 || port > u64::from(u16::max_value()) {
+        return invalid(key, "must be a port between 1 and 65535");
+    }
+    Ok(())
+}
+
+/// Value of environment variable is TOML, like `8080` or `["a", "b"]`,
+/// anything else is taken as a bare string
+fn parse_env_value(raw: &str) -> toml::Value {
+    format!("value = {}", raw)
+        .parse::<toml::Value>()
+        .ok()
+        .and_then(|mut parsed| {
+            parsed
+                .as_table_mut()
+                .and_then(|table| table.remove("value"))
+        })
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// Set keys named by `ASTROCORE_*` variables, nested tables are separated by `__`
+fn apply_env_overrides<I>(config: &mut toml::Value, vars: I) -> Result<(), ConfigError>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    for (var, raw) in vars {
+        if !var.starts_with(ENV_PREFIX) {
+            continue;
+        }
+
+        let path: Vec<String> = var[ENV_PREFIX.len()..]
+            .split(ENV_NESTING_SEPARATOR)
+            .map(str::to_lowercase)
+            .collect();
+        if path.iter().any(String::is_empty) {
+            return Err(ConfigError::Env(var, "empty key name".to_string()));
+        }
+
+        let (key, tables) = path.split_last().unwrap();
+        let mut table = config.as_table_mut().unwrap();
+        for name in tables {
+            table = match table
+                .entry(name.clone())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+            {
+                toml::Value::Table(nested) => nested,
+                _ => return Err(ConfigError::Env(var, format!("{} is not a table", name))),
+            };
+        }
+        table.insert(key.clone(), parse_env_value(&raw));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    // public, test or name of a custom network which network_passphrase is set for
     network: String,
+    // Passphrase of private and standalone networks, resolved from network for known ones
+    #[serde(default)]
+    network_passphrase: String,
     local_node: LocalNode,
     initial_peers: Vec<InitialPeer>,
+    #[serde(default = "Config::default_public_passphrase")]
     public_passphrase: String,
+    #[serde(default = "Config::default_test_passphrase")]
     test_passphrase: String,
+    // Secret seed of the node, better set by ASTROCORE_SEED than kept in the file
     seed: String,
     db_pool: u32,
-    // Maximum of connected peers
-    max_peers: u32,
-    min_peers: u32,
+    // Number of outbound connections we dial to keep
+    #[serde(default = "Config::default_target_outbound_peers")]
+    target_outbound_peers: usize,
+    // Limits of authenticated connections, preferred peers evict others when reached
+    #[serde(default = "Config::default_max_outbound_peers")]
+    max_outbound_peers: usize,
+    #[serde(default = "Config::default_max_inbound_peers")]
+    max_inbound_peers: usize,
+    // Peers we always keep connection with, they are never rejected or evicted
+    #[serde(default)]
+    preferred_peers: Vec<InitialPeer>,
+    // Node IDs of preferred peers, connection from any address is preferred
+    #[serde(default)]
+    preferred_peer_keys: Vec<String>,
+    // Refuse connections with peers not listed as preferred
+    #[serde(default)]
+    preferred_peers_only: bool,
+    // Limits of messages waiting to be written to a single peer
+    #[serde(default = "Config::default_outbound_queue_max_bytes")]
+    outbound_queue_max_bytes: usize,
+    #[serde(default = "Config::default_outbound_queue_max_messages")]
+    outbound_queue_max_messages: usize,
+    // Max size of record received from peer
+    #[serde(default = "Config::default_max_message_size")]
+    max_message_size: usize,
+    // Timeouts of peer connections in seconds: unfinished handshake, no reads
+    // and writes, outbound queue not drained
+    #[serde(default = "Config::default_peer_authentication_timeout")]
+    peer_authentication_timeout: u64,
+    #[serde(default = "Config::default_peer_timeout")]
+    peer_timeout: u64,
+    #[serde(default = "Config::default_peer_straggler_timeout")]
+    peer_straggler_timeout: u64,
+    // Port of operator commands HTTP interface, listened on localhost only
+    #[serde(default = "Config::default_http_port")]
+    http_port: u16,
+    // Messages per second a single peer may send us, extra ones are dropped
+    #[serde(default)]
+    peer_rate_limits: PeerRateLimits,
+    // Misbehavior score after which peer is disconnected, and banned if enabled
+    #[serde(default = "Config::default_peer_misbehavior_threshold")]
+    peer_misbehavior_threshold: u32,
+    #[serde(default)]
+    ban_misbehaving_peers: bool,
+    // Quorum set given explicitly, like stellar-core QUORUM_SET
+    #[serde(default, rename = "quorum_set")]
+    quorum_set_config: Option<QuorumSetConfig>,
+    // Validators quorum set is generated from by their quality, can't be mixed with quorum_set
+    #[serde(default)]
+    validators: Vec<ValidatorConfig>,
+    #[serde(default)]
+    home_domains: Vec<HomeDomainConfig>,
+    // Allow top level threshold below 67%, like stellar-core UNSAFE_QUORUM
+    #[serde(default)]
+    unsafe_quorum: bool,
+    // Parsed from either quorum form on validation
+    #[serde(skip)]
+    quorum_set: Option<xdr::ScpQuorumSet>,
 }
 
 impl Config {
     pub fn init() -> Config {
-        let mut file = File::open(Path::new("config.toml")).unwrap();
-        let mut toml_str = String::new();
-        file.read_to_string(&mut toml_str).unwrap();
-        toml::from_str::<Config>(&toml_str).unwrap()
+        if let Some(config) = LOADED_CONFIG.lock().unwrap().take() {
+            return config;
+        }
+
+        let path = CONFIG_PATH.lock().unwrap().clone();
+        Config::load(&path, env::vars()).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Read config file, override its keys by environment variables and validate it
+    pub fn load<I>(path: &Path, vars: I) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let toml_str =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        Config::from_toml(&toml_str, vars)
+    }
+
+    fn from_toml<I>(toml_str: &str, vars: I) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut value = toml_str.parse::<toml::Value>()?;
+        apply_env_overrides(&mut value, vars)?;
+
+        let mut config: Config = value.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check values deserialization can't, the first invalid key is reported
+    fn validate(&mut self) -> Result<(), ConfigError> {
+        self.network_passphrase = self.resolve_network_passphrase()?;
+
+        if crypto::from_secret_seed(&self.seed).is_err() {
+            return invalid("seed", "must be a secret seed strkey starting with S");
+        }
+
+        if self.local_node.ip.parse::<IpAddr>().is_err() {
+            return invalid("local_node.ip", "must be an IP address");
+        }
+        check_port("local_node.port", self.local_node.port)?;
+
+        for (peers_key, peers) in &[
+            ("initial_peers", &self.initial_peers),
+            ("preferred_peers", &self.preferred_peers),
+        ] {
+            for (i, peer) in peers.iter().enumerate() {
+                if peer.host.is_empty() {
+                    return invalid(&format!("{}[{}].host", peers_key, i), "must not be empty");
+                }
+                check_port(&format!("{}[{}].port", peers_key, i), peer.port)?;
+            }
+        }
+
+        for (i, key) in self.preferred_peer_keys.iter().enumerate() {
+            if node_id_from_strkey(key).is_none() {
+                return invalid(
+                    &format!("preferred_peer_keys[{}]", i),
+                    "must be a public key strkey starting with G",
+                );
+            }
+        }
+
+        if self.target_outbound_peers > self.max_outbound_peers {
+            return invalid(
+                "target_outbound_peers",
+                "must not be greater than max_outbound_peers",
+            );
+        }
+
+        check_positive("db_pool", u64::from(self.db_pool))?;
+        check_positive(
+            "outbound_queue_max_bytes",
+            self.outbound_queue_max_bytes as u64,
+        )?;
+        check_positive(
+            "outbound_queue_max_messages",
+            self.outbound_queue_max_messages as u64,
+        )?;
+        check_positive("max_message_size", self.max_message_size as u64)?;
+        check_positive(
+            "peer_authentication_timeout",
+            self.peer_authentication_timeout,
+        )?;
+        check_positive("peer_timeout", self.peer_timeout)?;
+        check_positive("peer_straggler_timeout", self.peer_straggler_timeout)?;
+        check_positive("http_port", u64::from(self.http_port))?;
+        check_positive(
+            "peer_misbehavior_threshold",
+            u64::from(self.peer_misbehavior_threshold),
+        )?;
+
+        self.quorum_set = self.parse_quorum_set()?;
+
+        Ok(())
+    }
+
+    /// Quorum set from `[quorum_set]` or generated from `[[validators]]`, None for watcher node
+    fn parse_quorum_set(&self) -> Result<Option<xdr::ScpQuorumSet>, ConfigError> {
+        let (key, parsed) = match self.quorum_set_config {
+            Some(_) if !self.validators.is_empty() => {
+                return invalid("validators", "can't be used together with quorum_set");
+            }
+            Some(ref config) => {
+                if config.threshold_percent < SAFE_THRESHOLD_PERCENT && !self.unsafe_quorum {
+                    return invalid(
+                        "quorum_set.threshold_percent",
+                        "below 67 is unsafe, set unsafe_quorum = true to force it",
+                    );
+                }
+                ("quorum_set", config.parse("quorum_set")?)
+            }
+            None if self.validators.is_empty() => return Ok(None),
+            None => ("validators", self.generate_quorum_set()?),
+        };
+
+        quorum_set::check_sanity(&parsed, !self.unsafe_quorum)
+            .map_err(|reason| ConfigError::Invalid(key.to_string(), reason))?;
+        Ok(Some(parsed))
+    }
+
+    /// Each home domain is an inner set of its validators with simple majority threshold.
+    /// Domains of the same quality are grouped into a set which includes the set of lower
+    /// quality ones as another entry, the same way stellar-core generateQuorumSet does
+    fn generate_quorum_set(&self) -> Result<xdr::ScpQuorumSet, ConfigError> {
+        let mut domain_qualities = BTreeMap::new();
+        for (i, home_domain) in self.home_domains.iter().enumerate() {
+            if home_domain.home_domain.is_empty() {
+                return invalid(
+                    &format!("home_domains[{}].home_domain", i),
+                    "must not be empty",
+                );
+            }
+            if domain_qualities
+                .insert(home_domain.home_domain.as_str(), home_domain.quality)
+                .is_some()
+            {
+                return invalid(
+                    &format!("home_domains[{}].home_domain", i),
+                    "is declared more than once",
+                );
+            }
+        }
+
+        let mut domains: BTreeMap<&str, (ValidatorQuality, Vec<xdr::NodeId>)> = BTreeMap::new();
+        for (i, validator) in self.validators.iter().enumerate() {
+            let node_id = match node_id_from_strkey(&validator.public_key) {
+                Some(node_id) => node_id,
+                None => {
+                    return invalid(
+                        &format!("validators[{}].public_key", i),
+                        "must be a public key strkey starting with G",
+                    );
+                }
+            };
+
+            let domain_quality = domain_qualities.get(validator.home_domain.as_str());
+            let quality = match (validator.quality, domain_quality) {
+                (Some(quality), None) => quality,
+                (None, Some(quality)) => *quality,
+                (Some(_), Some(_)) => {
+                    return invalid(
+                        &format!("validators[{}].quality", i),
+                        "is set for its home domain already",
+                    );
+                }
+                (None, None) => {
+                    return invalid(
+                        &format!("validators[{}].quality", i),
+                        "must be set here or for its home domain",
+                    );
+                }
+            };
+
+            let domain = domains
+                .entry(validator.home_domain.as_str())
+                .or_insert_with(|| (quality, vec![]));
+            if domain.0 != quality {
+                return invalid(
+                    &format!("validators[{}].quality", i),
+                    "differs from other validators of its home domain",
+                );
+            }
+            domain.1.push(node_id);
+        }
+
+        for (home_domain, (quality, validators)) in &domains {
+            if *quality >= ValidatorQuality::High && validators.len() < MIN_HIGH_QUALITY_VALIDATORS
+            {
+                return Err(ConfigError::Invalid(
+                    "validators".to_string(),
+                    format!(
+                        "home domain {} of {:?} quality must have at least {} validators",
+                        home_domain, quality, MIN_HIGH_QUALITY_VALIDATORS
+                    ),
+                ));
+            }
+        }
+
+        let mut qualities: Vec<ValidatorQuality> =
+            domains.values().map(|(quality, _)| *quality).collect();
+        qualities.sort();
+        qualities.dedup();
+
+        if *qualities.last().unwrap() < ValidatorQuality::High && !self.unsafe_quorum {
+            return invalid(
+                "validators",
+                "without HIGH quality ones threshold is 51% which is unsafe, \
+                 set unsafe_quorum = true to force it",
+            );
+        }
+
+        // lower quality set becomes an entry of the higher quality one
+        let mut lower_set: Option<xdr::ScpQuorumSet> = None;
+        for quality in qualities {
+            let mut inner_sets: Vec<xdr::ScpQuorumSet> = domains
+                .values()
+                .filter(|(domain_quality, _)| *domain_quality == quality)
+                .map(|(_, validators)| xdr::ScpQuorumSet {
+                    threshold: threshold_of(validators.len(), SIMPLE_MAJORITY_PERCENT),
+                    validators: validators.clone(),
+                    inner_sets: vec![],
+                })
+                .collect();
+            inner_sets.extend(lower_set.take());
+
+            let percent = if quality >= ValidatorQuality::High {
+                SAFE_THRESHOLD_PERCENT
+            } else {
+                SIMPLE_MAJORITY_PERCENT
+            };
+            lower_set = Some(xdr::ScpQuorumSet {
+                threshold: threshold_of(inner_sets.len(), percent),
+                validators: vec![],
+                inner_sets,
+            });
+        }
+
+        Ok(lower_set.unwrap())
+    }
+
+    /// Known networks have their passphrases, custom ones must set network_passphrase
+    fn resolve_network_passphrase(&self) -> Result<String, ConfigError> {
+        let known_passphrase = match self.network.as_str() {
+            "public" => Some(&self.public_passphrase),
+            "test" => Some(&self.test_passphrase),
+            _ => None,
+        };
+
+        match known_passphrase {
+            Some(passphrase)
+                if self.network_passphrase.is_empty() || self.network_passphrase == *passphrase =>
+            {
+                Ok(passphrase.clone())
+            }
+            Some(_) => invalid(
+                "network_passphrase",
+                "differs from the passphrase of the network, name custom network otherwise",
+            ),
+            None if self.network.is_empty() => invalid("network", "must not be empty"),
+            None if self.network_passphrase.is_empty() => invalid(
+                "network",
+                "must be public or test, unless network_passphrase is set for a custom network",
+            ),
+            None => Ok(self.network_passphrase.clone()),
+        }
     }
 
     pub fn local_node(&self) -> &LocalNode {
@@ -46,6 +520,11 @@ impl Config {
         &self.network
     }
 
+    /// Passphrase of the network node works in, known or custom one
+    pub fn network_passphrase(&self) -> &String {
+        &self.network_passphrase
+    }
+
     pub fn seed(&self) -> &String {
         &self.seed
     }
@@ -62,16 +541,249 @@ impl Config {
         &self.db_pool
     }
 
-    pub fn max_peers(&self) -> &u32 {
-        &self.max_peers
+    pub fn target_outbound_peers(&self) -> &usize {
+        &self.target_outbound_peers
+    }
+
+    pub fn max_outbound_peers(&self) -> &usize {
+        &self.max_outbound_peers
+    }
+
+    pub fn max_inbound_peers(&self) -> &usize {
+        &self.max_inbound_peers
+    }
+
+    pub fn preferred_peers(&self) -> &Vec<InitialPeer> {
+        &self.preferred_peers
+    }
+
+    pub fn preferred_peer_keys(&self) -> &Vec<String> {
+        &self.preferred_peer_keys
+    }
+
+    pub fn preferred_peers_only(&self) -> &bool {
+        &self.preferred_peers_only
+    }
+
+    pub fn outbound_queue_max_bytes(&self) -> &usize {
+        &self.outbound_queue_max_bytes
+    }
+
+    pub fn outbound_queue_max_messages(&self) -> &usize {
+        &self.outbound_queue_max_messages
+    }
+
+    pub fn max_message_size(&self) -> &usize {
+        &self.max_message_size
+    }
+
+    pub fn peer_authentication_timeout(&self) -> &u64 {
+        &self.peer_authentication_timeout
+    }
+
+    pub fn peer_timeout(&self) -> &u64 {
+        &self.peer_timeout
+    }
+
+    pub fn peer_straggler_timeout(&self) -> &u64 {
+        &self.peer_straggler_timeout
+    }
+
+    pub fn http_port(&self) -> &u16 {
+        &self.http_port
     }
 
-    pub fn min_peers(&self) -> &u32 {
-        &self.min_peers
+    pub fn peer_rate_limits(&self) -> &PeerRateLimits {
+        &self.peer_rate_limits
+    }
+
+    pub fn peer_misbehavior_threshold(&self) -> &u32 {
+        &self.peer_misbehavior_threshold
+    }
+
+    pub fn ban_misbehaving_peers(&self) -> &bool {
+        &self.ban_misbehaving_peers
+    }
+
+    /// Quorum set of our node, None if it only watches the network
+    pub fn quorum_set(&self) -> Option<&xdr::ScpQuorumSet> {
+        self.quorum_set.as_ref()
+    }
+
+    fn default_public_passphrase() -> String {
+        "Public Global Stellar Network ; September 2015".to_string()
+    }
+
+    fn default_test_passphrase() -> String {
+        "Test SDF Network ; September 2015".to_string()
+    }
+
+    // the same as stellar-core TARGET_PEER_CONNECTIONS
+    fn default_target_outbound_peers() -> usize {
+        8
+    }
+
+    fn default_max_outbound_peers() -> usize {
+        8
+    }
+
+    // the same as stellar-core default of MAX_ADDITIONAL_PEER_CONNECTIONS
+    fn default_max_inbound_peers() -> usize {
+        64
+    }
+
+    fn default_outbound_queue_max_bytes() -> usize {
+        4 * 1024 * 1024
+    }
+
+    fn default_outbound_queue_max_messages() -> usize {
+        1024
+    }
+
+    // the same limit as stellar-core MAX_MESSAGE_SIZE
+    fn default_max_message_size() -> usize {
+        0x0100_0000
+    }
+
+    // the same defaults as stellar-core PEER_AUTHENTICATION_TIMEOUT,
+    // PEER_TIMEOUT and PEER_STRAGGLER_TIMEOUT
+    fn default_peer_authentication_timeout() -> u64 {
+        2
+    }
+
+    fn default_peer_timeout() -> u64 {
+        30
+    }
+
+    fn default_peer_straggler_timeout() -> u64 {
+        120
+    }
+
+    // the same as stellar-core HTTP_PORT
+    fn default_http_port() -> u16 {
+        11626
+    }
+
+    fn default_peer_misbehavior_threshold() -> u32 {
+        100
+    }
+}
+
+/// `[quorum_set]` section, inner sets are nested `[[quorum_set.inner_sets]]` ones
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuorumSetConfig {
+    threshold_percent: u32,
+    #[serde(default)]
+    validators: Vec<String>,
+    #[serde(default)]
+    inner_sets: Vec<QuorumSetConfig>,
+}
+
+impl QuorumSetConfig {
+    /// Percent threshold becomes count of entries, strkeys become node IDs
+    fn parse(&self, key: &str) -> Result<xdr::ScpQuorumSet, ConfigError> {
+        if self.threshold_percent == 0 || self.threshold_percent > 100 {
+            return invalid(
+                &format!("{}.threshold_percent", key),
+                "must be between 1 and 100",
+            );
+        }
+
+        let mut validators = vec![];
+        for (i, strkey) in self.validators.iter().enumerate() {
+            match node_id_from_strkey(strkey) {
+                Some(node_id) => validators.push(node_id),
+                None => {
+                    return invalid(
+                        &format!("{}.validators[{}]", key, i),
+                        "must be a public key strkey starting with G",
+                    );
+                }
+            }
+        }
+
+        let mut inner_sets = vec![];
+        for (i, inner_set) in self.inner_sets.iter().enumerate() {
+            inner_sets.push(inner_set.parse(&format!("{}.inner_sets[{}]", key, i))?);
+        }
+
+        Ok(xdr::ScpQuorumSet {
+            threshold: threshold_of(validators.len() + inner_sets.len(), self.threshold_percent),
+            validators,
+            inner_sets,
+        })
+    }
+}
+
+/// Quality of validators, the same levels as stellar-core has
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ValidatorQuality {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// `[[validators]]` entry, quality is set here or for its home domain
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidatorConfig {
+    name: String,
+    home_domain: String,
+    public_key: String,
+    quality: Option<ValidatorQuality>,
+}
+
+/// `[[home_domains]]` entry, quality of all validators of the domain
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HomeDomainConfig {
+    home_domain: String,
+    quality: ValidatorQuality,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerRateLimits {
+    transactions: u32,
+    scp_messages: u32,
+    peers: u32,
+    other: u32,
+}
+
+impl PeerRateLimits {
+    pub fn transactions(&self) -> &u32 {
+        &self.transactions
+    }
+
+    pub fn scp_messages(&self) -> &u32 {
+        &self.scp_messages
+    }
+
+    pub fn peers(&self) -> &u32 {
+        &self.peers
+    }
+
+    pub fn other(&self) -> &u32 {
+        &self.other
+    }
+}
+
+impl Default for PeerRateLimits {
+    fn default() -> Self {
+        PeerRateLimits {
+            transactions: 100,
+            scp_messages: 100,
+            peers: 2,
+            other: 50,
+        }
     }
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct LocalNode {
     ip: String,
     port: u64,
@@ -92,6 +804,7 @@ impl LocalNode {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct InitialPeer {
     host: String,
     #[serde(default = "InitialPeer::default_port")]
@@ -100,10 +813,189 @@ pub struct InitialPeer {
 
 impl InitialPeer {
     pub fn address(&self) -> String {
-        format!("{}:{}", &self.host, &self.port)
+        // IPv6 literal must be wrapped in brackets to be followed by port
+        if self.host.contains(':') {
+            format!("[{}]:{}", &self.host, &self.port)
+        } else {
+            format!("{}:{}", &self.host, &self.port)
+        }
     }
 
     fn default_port() -> u64 {
         11625
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG_TOML: &str = r#"
+        network = "test"
+        seed = "SATKBWSRLPHGM2FKMLZ4QNH64XYKP7J2O6U5QNFBJHYEXDSHN75R5MVE"
+        db_pool = 4
+        initial_peers = []
+
+        [local_node]
+        ip = "127.0.0.1"
+        port = 8080
+    "#;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn invalid_key(result: Result<Config, ConfigError>) -> String {
+        match result {
+            Err(ConfigError::Invalid(key, _)) => key,
+            other => panic!("expected invalid key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_invalid_key() {
+        let toml_str = CONFIG_TOML.replace("port = 8080", "port = 70000");
+        assert_eq!(
+            invalid_key(Config::from_toml(&toml_str, vec![])),
+            "local_node.port"
+        );
+
+        let overridden = Config::from_toml(CONFIG_TOML, vars(&[("ASTROCORE_SEED", "SBAD")]));
+        assert_eq!(invalid_key(overridden), "seed");
+    }
+
+    #[test]
+    fn overrides_keys_by_environment_variables() {
+        let config = Config::from_toml(
+            CONFIG_TOML,
+            vars(&[
+                ("ASTROCORE_LOCAL_NODE__PORT", "11625"),
+                ("ASTROCORE_PREFERRED_PEERS_ONLY", "true"),
+                ("ASTROCORE_NETWORK", "public"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.local_node().address(), "127.0.0.1:11625");
+        assert!(*config.preferred_peers_only());
+        assert_eq!(
+            config.network_passphrase(),
+            "Public Global Stellar Network ; September 2015"
+        );
+    }
+
+    #[test]
+    fn requires_passphrase_of_custom_network() {
+        let toml_str = CONFIG_TOML.replace("\"test\"", "\"standalone\"");
+        assert_eq!(invalid_key(Config::from_toml(&toml_str, vec![])), "network");
+
+        let passphrase = "Standalone Network ; February 2017";
+        let config = Config::from_toml(
+            &toml_str,
+            vars(&[("ASTROCORE_NETWORK_PASSPHRASE", passphrase)]),
+        )
+        .unwrap();
+        assert_eq!(config.network_passphrase(), passphrase);
+    }
+
+    const VALIDATORS: [&str; 3] = [
+        "GDKXE2OZMJIPOSLNA6N6F2BVCI3O777I2OOC4BV7VOYUEHYX7RTRYA7Y",
+        "GCUCJTIYXSOXKBSNFGNFWW5MUQ54HKRPGJUTQFJ5RQXZXNOLNXYDHRAP",
+        "GC2V2EFSXN6SQTWVYA5EPJPBWWIMSD2XQNKUOHGEKB535AQE2I6IXV2Z",
+    ];
+
+    #[test]
+    fn parses_nested_quorum_set() {
+        let toml_str = format!(
+            r#"{}
+            [quorum_set]
+            threshold_percent = 67
+            validators = ["{}"]
+
+            [[quorum_set.inner_sets]]
+            threshold_percent = 51
+            validators = ["{}", "{}"]
+            "#,
+            CONFIG_TOML, VALIDATORS[0], VALIDATORS[1], VALIDATORS[2]
+        );
+        let quorum_set = Config::from_toml(&toml_str, vec![])
+            .unwrap()
+            .quorum_set
+            .unwrap();
+
+        assert_eq!(quorum_set.threshold, 2);
+        assert_eq!(quorum_set.validators.len(), 1);
+        assert_eq!(quorum_set.inner_sets[0].threshold, 2);
+        assert_eq!(quorum_set.inner_sets[0].validators.len(), 2);
+
+        let unsafe_toml = toml_str.replace("threshold_percent = 67", "threshold_percent = 50");
+        assert_eq!(
+            invalid_key(Config::from_toml(&unsafe_toml, vec![])),
+            "quorum_set.threshold_percent"
+        );
+
+        // 1 of 2 entries is accepted only when forced
+        let forced = Config::from_toml(&format!("unsafe_quorum = true\n{}", unsafe_toml), vec![])
+            .unwrap()
+            .quorum_set
+            .unwrap();
+        assert_eq!(forced.threshold, 1);
+    }
+
+    #[test]
+    fn generates_quorum_set_from_validators() {
+        let mut toml_str = format!(
+            r#"{}
+            [[home_domains]]
+            home_domain = "testnet.stellar.org"
+            quality = "HIGH"
+
+            [[validators]]
+            name = "other"
+            home_domain = "example.com"
+            quality = "MEDIUM"
+            public_key = "{}"
+            "#,
+            CONFIG_TOML, VALIDATORS[0]
+        );
+        for (i, validator) in VALIDATORS[1..].iter().enumerate() {
+            toml_str.push_str(&format!(
+                r#"
+                [[validators]]
+                name = "sdf{}"
+                home_domain = "testnet.stellar.org"
+                public_key = "{}"
+                "#,
+                i, validator
+            ));
+        }
+
+        // HIGH quality domain has only 2 validators
+        assert_eq!(
+            invalid_key(Config::from_toml(&toml_str, vec![])),
+            "validators"
+        );
+
+        let toml_str = toml_str.replace("\"MEDIUM\"", "\"LOW\"").replace(
+            "home_domain = \"example.com\"",
+            "home_domain = \"testnet.stellar.org\"",
+        );
+        // quality is already set for the home domain
+        assert_eq!(
+            invalid_key(Config::from_toml(&toml_str, vec![])),
+            "validators[0].quality"
+        );
+
+        let toml_str = toml_str.replace("quality = \"LOW\"", "");
+        let quorum_set = Config::from_toml(&toml_str, vec![])
+            .unwrap()
+            .quorum_set
+            .unwrap();
+        assert_eq!(quorum_set.threshold, 1);
+        assert_eq!(quorum_set.inner_sets[0].threshold, 2);
+        assert_eq!(quorum_set.inner_sets[0].validators.len(), 3);
+    }
+}
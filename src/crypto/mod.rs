@@ -2,6 +2,11 @@
 
 mod error;
 mod keypair;
+mod node_id;
 mod strkey;
 
 pub use self::keypair::from_secret_seed;
+pub use self::node_id::{node_id_from_strkey, node_id_to_strkey};
+pub use self::strkey::{
+    decode_account_id, decode_secret_seed, encode_account_id, encode_secret_seed,
+};
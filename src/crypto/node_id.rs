@@ -0,0 +1,45 @@
+use super::strkey;
+use crate::xdr;
+
+/// Node ID in the same G... form stellar-core shows and stores it
+pub fn node_id_to_strkey(node_id: &xdr::NodeId) -> String {
+    let xdr::PublicKey::Ed25519(xdr::Uint256(key)) = node_id;
+    strkey::encode_account_id(key).unwrap()
+}
+
+/// Node ID from public key strkey, None if it isn't a valid one
+pub fn node_id_from_strkey(data: &str) -> Option<xdr::NodeId> {
+    let decoded = strkey::decode_account_id(data).ok()?;
+    if decoded.len() != 32 {
+        return None;
+    }
+
+    let mut key: [u8; 32] = Default::default();
+    key.copy_from_slice(&decoded);
+    Some(xdr::PublicKey::Ed25519(xdr::Uint256(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factories::internal_xdr::build_public_key;
+
+    #[test]
+    fn node_id_strkey_roundtrip() {
+        let node_id = build_public_key();
+        let strkey = node_id_to_strkey(&node_id);
+
+        assert!(strkey.starts_with('G'));
+        assert_eq!(node_id_from_strkey(&strkey), Some(node_id));
+    }
+
+    #[test]
+    fn invalid_node_id_strkey() {
+        assert_eq!(node_id_from_strkey(""), None);
+        assert_eq!(node_id_from_strkey("GABC"), None);
+        assert_eq!(
+            node_id_from_strkey("SATKBWSRLPHGM2FKMLZ4QNH64XYKP7J2O6U5QNFBJHYEXDSHN75R5MVE"),
+            None
+        );
+    }
+}
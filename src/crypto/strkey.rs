@@ -52,6 +52,10 @@ fn encode_check(version: u8, indata: &[u8]) -> Result<String> {
 
 fn decode_check(expected_version: u8, data: &str) -> Result<Vec<u8>> {
     let decoded = base32::decode(ALPHABET, &data).ok_or(Error::InvalidStrKey)?;
+    // version byte and checksum at least
+    if decoded.len() < 3 {
+        return Err(Error::InvalidStrKey);
+    }
     let decoded_len = decoded.len();
     let version_byte = decoded[0];
     if version_byte != expected_version {
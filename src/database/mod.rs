@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+pub use self::models::ban::Ban;
 pub use self::models::peer::Peer;
 
 mod models;
@@ -15,6 +16,15 @@ pub(crate) use lazy_static::lazy_static;
 pub(crate) use log::info;
 pub(crate) use std::env;
 
+embed_migrations!("migrations");
+
 pub(crate) fn init() {
     Peer::load_initial_peers();
 }
+
+/// Create tables of the database from DATABASE_URL and load initial peers into it
+pub(crate) fn new_db() -> Result<(), diesel_migrations::RunMigrationsError> {
+    embedded_migrations::run(&*db_conn())?;
+    init();
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use super::{db_conn, schema::ban};
+use diesel::prelude::*;
+
+/// Node ID banned by operator, stored as strkey
+#[derive(Queryable, Debug)]
+pub struct Ban {
+    pub nodeid: String,
+}
+
+type Result<T> = std::result::Result<T, diesel::result::Error>;
+
+impl Ban {
+    pub fn all() -> Result<Vec<Ban>> {
+        use self::ban::dsl::*;
+
+        ban.load::<Ban>(&*db_conn())
+    }
+
+    /// Banning already banned node is a no-op
+    pub fn create(node_id: &str) -> Result<usize> {
+        diesel::insert_or_ignore_into(ban::table)
+            .values(&NewBan { nodeid: node_id })
+            .execute(&*db_conn())
+    }
+
+    pub fn delete(node_id: &str) -> Result<usize> {
+        use self::ban::dsl::*;
+
+        diesel::delete(ban.filter(nodeid.eq(node_id))).execute(&*db_conn())
+    }
+
+    pub fn exists(node_id: &str) -> Result<bool> {
+        use self::ban::dsl::*;
+
+        let count: i64 = ban
+            .filter(nodeid.eq(node_id))
+            .count()
+            .get_result(&*db_conn())?;
+        Ok(count > 0)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "ban"]
+pub struct NewBan<'a> {
+    pub nodeid: &'a str,
+}
@@ -1,4 +1,5 @@
 #![allow(dead_code, unused_must_use)]
 
+pub(crate) mod ban;
 pub(crate) mod peer;
 pub(crate) use super::{db_conn, schema, CONFIG};
@@ -1,8 +1,15 @@
 use super::{db_conn, schema::peers, CONFIG};
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::prelude::*;
 use log::warn;
-use std::net::ToSocketAddrs;
+use rand::Rng;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+/// Unit of retry delay after failed connection
+const SECONDS_PER_BACKOFF: i64 = 10;
+/// Retry delay stops growing after this number of failures
+const MAX_BACKOFF_EXPONENT: i32 = 10;
 
 #[derive(Queryable, Debug)]
 pub struct Peer {
@@ -14,6 +21,12 @@ pub struct Peer {
 
 type Result<T> = std::result::Result<T, diesel::result::Error>;
 
+no_arg_sql_function!(
+    random,
+    diesel::sql_types::Integer,
+    "Represents the SQL RANDOM() function"
+);
+
 impl Peer {
     pub fn all() -> Result<Vec<Peer>> {
         use self::peers::dsl::*;
@@ -21,10 +34,10 @@ impl Peer {
         peers.load::<Peer>(&*db_conn())
     }
 
-    pub fn create(ip: &str, port: i32) -> Result<usize> {
+    pub fn create(address: &SocketAddr) -> Result<usize> {
         let new_peer = NewPeer {
-            ip,
-            port,
+            ip: &address.ip().to_string(),
+            port: i32::from(address.port()),
             nextattempt: diesel::dsl::now,
         };
         diesel::insert_into(peers::table)
@@ -32,19 +45,94 @@ impl Peer {
             .execute(&*db_conn())
     }
 
-    pub fn get(g_ip: &str, g_port: i32) -> Result<Vec<Peer>> {
+    /// Save discovered peer, keeping the existing record untouched
+    pub fn insert_if_missing(address: &SocketAddr) -> Result<usize> {
+        let new_peer = NewPeer {
+            ip: &address.ip().to_string(),
+            port: i32::from(address.port()),
+            nextattempt: diesel::dsl::now,
+        };
+        diesel::insert_or_ignore_into(peers::table)
+            .values(&new_peer)
+            .execute(&*db_conn())
+    }
+
+    /// Peers whose next attempt time has come, the least failed first
+    pub fn ready_to_connect(limit: i64) -> Result<Vec<Peer>> {
+        use self::peers::dsl::*;
+
+        peers
+            .filter(nextattempt.le(Utc::now().naive_utc()))
+            .order((numfailures.asc(), nextattempt.asc()))
+            .limit(limit)
+            .load::<Peer>(&*db_conn())
+    }
+
+    /// Random sample of peers failed at most given number of times,
+    /// excluding the requester IP
+    pub fn random_sample(max_failures: i32, except_ip: &IpAddr, limit: i64) -> Result<Vec<Peer>> {
         use self::peers::dsl::*;
 
         peers
-            .filter(ip.eq(g_ip))
-            .filter(port.eq(g_port))
+            .filter(numfailures.le(max_failures))
+            .filter(ip.ne(except_ip.to_string()))
+            .order(random)
+            .limit(limit)
             .load::<Peer>(&*db_conn())
     }
 
-    pub fn delete(g_ip: &str, g_port: i32) -> Result<usize> {
+    /// Increase number of failures and postpone next attempt with exponential backoff
+    pub fn record_failure(address: &SocketAddr) -> Result<usize> {
         use self::peers::dsl::*;
 
-        diesel::delete(peers.filter(ip.eq(g_ip)).filter(port.eq(g_port))).execute(&*db_conn())
+        let failures = match Self::get(address)?.pop() {
+            Some(peer) => peer.numfailures + 1,
+            None => return Ok(0),
+        };
+
+        diesel::update(
+            peers
+                .filter(ip.eq(address.ip().to_string()))
+                .filter(port.eq(i32::from(address.port()))),
+        )
+        .set((
+            numfailures.eq(failures),
+            nextattempt.eq(Utc::now().naive_utc() + backoff_delay(failures)),
+        ))
+        .execute(&*db_conn())
+    }
+
+    /// Reset failures after peer was connected
+    pub fn record_success(address: &SocketAddr) -> Result<usize> {
+        use self::peers::dsl::*;
+
+        diesel::update(
+            peers
+                .filter(ip.eq(address.ip().to_string()))
+                .filter(port.eq(i32::from(address.port()))),
+        )
+        .set((numfailures.eq(0), nextattempt.eq(Utc::now().naive_utc())))
+        .execute(&*db_conn())
+    }
+
+    pub fn get(address: &SocketAddr) -> Result<Vec<Peer>> {
+        use self::peers::dsl::*;
+
+        peers
+            .filter(ip.eq(address.ip().to_string()))
+            .filter(port.eq(i32::from(address.port())))
+            .load::<Peer>(&*db_conn())
+    }
+
+    pub fn delete(address: &SocketAddr) -> Result<usize> {
+        use self::peers::dsl::*;
+
+        diesel::delete(
+            peers
+                .filter(ip.eq(address.ip().to_string()))
+                .filter(port.eq(i32::from(address.port()))),
+        )
+        .execute(&*db_conn())
     }
 
     pub fn load_initial_peers() {
@@ -76,11 +164,21 @@ impl Peer {
         }
     }
 
-    pub fn address(&self) -> String {
-        format!("{}:{}", &self.ip, &self.port)
+    /// IP is stored without brackets, so IPv6 addresses can't be parsed as "ip:port" string
+    pub fn address(&self) -> Option<SocketAddr> {
+        let ip = self.ip.parse::<IpAddr>().ok()?;
+        let port = u16::try_from(self.port).ok()?;
+        Some(SocketAddr::new(ip, port))
     }
 }
 
+/// Random delay between 1 and 2^failures backoff units, like stellar-core does
+fn backoff_delay(failures: i32) -> Duration {
+    let max_backoffs = 1i64 << failures.max(0).min(MAX_BACKOFF_EXPONENT);
+    let backoffs = rand::thread_rng().gen_range(1, max_backoffs + 1);
+    Duration::seconds(backoffs * SECONDS_PER_BACKOFF)
+}
+
 #[derive(Insertable)]
 #[table_name = "peers"]
 pub struct NewPeer<'a> {
@@ -88,3 +186,28 @@ pub struct NewPeer<'a> {
     pub port: i32,
     pub nextattempt: diesel::dsl::now,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_grows_with_failures() {
+        for _ in 0..100 {
+            let delay = backoff_delay(1).num_seconds();
+            assert!(delay >= SECONDS_PER_BACKOFF && delay <= 2 * SECONDS_PER_BACKOFF);
+
+            let delay = backoff_delay(3).num_seconds();
+            assert!(delay >= SECONDS_PER_BACKOFF && delay <= 8 * SECONDS_PER_BACKOFF);
+        }
+    }
+
+    #[test]
+    fn backoff_delay_is_capped() {
+        let max_delay = (1 << MAX_BACKOFF_EXPONENT) * SECONDS_PER_BACKOFF;
+
+        for _ in 0..100 {
+            assert!(backoff_delay(50).num_seconds() <= max_delay);
+        }
+    }
+}
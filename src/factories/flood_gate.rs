@@ -1,6 +1,12 @@
 use crate::factories::internal_xdr::build_envelope;
 use crate::overlay::flood_gate::*;
-use crate::overlay::message_abbr;
+use crate::overlay::message_hash;
+use crate::xdr;
+use std::net::SocketAddr;
+
+fn sender(address: &str) -> Option<SocketAddr> {
+    address.parse().ok()
+}
 
 pub fn build_flood_gate() -> FloodGate {
     let mut flood_gate = FloodGate::new();
@@ -8,21 +14,21 @@ pub fn build_flood_gate() -> FloodGate {
     let message = build_envelope();
 
     flood_gate.flood_map.insert(
-        "123".to_string(),
-        FloodRecord::build(100, message.clone(), vec!["192.168.1.1".to_string()]),
+        xdr::Hash([1; 32]),
+        FloodRecord::build(100, message.clone(), sender("192.168.1.1:11625")),
     );
     flood_gate.flood_map.insert(
-        "345".to_string(),
-        FloodRecord::build(200, message.clone(), vec!["192.168.2.2".to_string()]),
+        xdr::Hash([2; 32]),
+        FloodRecord::build(200, message.clone(), sender("192.168.2.2:11625")),
     );
     flood_gate.flood_map.insert(
-        "678".to_string(),
-        FloodRecord::build(300, message.clone(), vec!["192.168.3.3".to_string()]),
+        xdr::Hash([3; 32]),
+        FloodRecord::build(300, message.clone(), sender("192.168.3.3:11625")),
     );
 
     flood_gate.flood_map.insert(
-        message_abbr(&message),
-        FloodRecord::build(400, message.clone(), vec!["192.168.4.4".to_string()]),
+        message_hash(&message),
+        FloodRecord::build(400, message.clone(), sender("192.168.4.4:11625")),
     );
 
     flood_gate
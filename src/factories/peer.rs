@@ -1,19 +1,34 @@
 #![allow(unused_variables)]
 
-use crate::overlay::peer::{MessageReceiveError, PeerInterface};
-use crate::scp::local_node::LocalNode;
+use crate::database;
+use crate::overlay::peer::{MessageReceiveError, PeerError, PeerInterface};
 use crate::xdr;
-use x25519_dalek::PublicKey;
+use chrono::NaiveDateTime;
+use std::net::SocketAddr;
 
 pub struct PeerMock {
-    pub address: String,
+    pub address: SocketAddr,
     pub is_authenticated: bool,
 }
 
 impl PeerInterface for PeerMock {
-    fn start_authentication(&mut self, we_called_remote: bool) {}
+    fn start_authentication(&mut self) {}
 
-    fn handle_hello(&mut self, received_hello: xdr::StellarMessage, we_called_remote: bool) {}
+    fn handle_handshake_message(&mut self, message: xdr::StellarMessage) -> Result<(), PeerError> {
+        Ok(())
+    }
+
+    fn continue_handshake(&mut self) {}
+
+    fn reject_handshake(&mut self, error: PeerError) {}
+
+    fn handle_hello(
+        &mut self,
+        received_hello: xdr::StellarMessage,
+        we_called_remote: bool,
+    ) -> Result<(), PeerError> {
+        Ok(())
+    }
 
     fn set_remote_keys(
         &mut self,
@@ -23,22 +38,15 @@ impl PeerInterface for PeerMock {
     ) {
     }
 
-    fn new_auth_cert(node_info: &LocalNode, auth_public_key: &PublicKey) -> xdr::AuthCert {
-        xdr::AuthCert::default()
-    }
-
     fn send_message(&mut self, message: xdr::StellarMessage) {}
 
-    fn send_header(&mut self, message_length: u32) {}
-
-    fn receive_message(&mut self) -> Result<xdr::AuthenticatedMessage, MessageReceiveError> {
+    fn receive_message(
+        &mut self,
+        record: &[u8],
+    ) -> Result<xdr::AuthenticatedMessage, MessageReceiveError> {
         Ok(xdr::AuthenticatedMessage::default())
     }
 
-    fn receive_header(&mut self) -> usize {
-        128
-    }
-
     fn increment_message_sequence(&mut self) {}
 
     fn set_authenticated(&mut self) {}
@@ -47,7 +55,16 @@ impl PeerInterface for PeerMock {
         true
     }
 
-    fn address(&self) -> &String {
+    fn address(&self) -> &SocketAddr {
         &self.address
     }
 }
+
+pub fn build_db_peer(ip: &str, port: i32, numfailures: i32) -> database::Peer {
+    database::Peer {
+        ip: ip.to_string(),
+        port,
+        nextattempt: NaiveDateTime::from_timestamp(0, 0),
+        numfailures,
+    }
+}
@@ -1,11 +1,15 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
 pub mod factories;
 pub mod xdr;
 
 pub(crate) mod actors;
 pub(crate) mod astro_protocol;
+pub(crate) mod cli;
+pub(crate) mod command_handler;
 pub(crate) mod config;
 pub(crate) mod crypto;
 pub(crate) mod database;
@@ -1,9 +1,13 @@
 #[macro_use]
 extern crate diesel;
-use std::{thread, time};
+#[macro_use]
+extern crate diesel_migrations;
+use std::process;
 
 mod actors;
 mod astro_protocol;
+mod cli;
+mod command_handler;
 mod config;
 mod crypto;
 mod database;
@@ -16,9 +20,13 @@ mod xdr;
 
 fn main() {
     env_logger::init();
-    database::init();
-    actors::start();
-    loop {
-        thread::sleep(time::Duration::from_millis(5));
+    let matches = cli::app().get_matches();
+    if let Some(path) = matches.value_of("conf") {
+        config::set_path(path);
+    }
+
+    if let Err(e) = cli::execute(&matches) {
+        eprintln!("{}", e);
+        process::exit(1);
     }
 }
@@ -16,14 +16,9 @@ impl Network {
         Network { passphrase }
     }
 
+    /// Network from config, known or custom one
     pub fn network() -> Network {
-        match CONFIG.network().as_str() {
-            "test" => Self::test_network(),
-            "public" => Self::public_network(),
-            _ => unreachable!(
-                "Unknown network. Check config file, network key should be public or test"
-            ),
-        }
+        Self::new(CONFIG.network_passphrase().to_owned())
     }
 
     /// Create new network with the same passphrase as SDF public network.
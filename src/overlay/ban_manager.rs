@@ -0,0 +1,37 @@
+use super::{crypto::node_id_to_strkey, database, error, xdr};
+
+/**
+ * BanManager keeps node IDs operator doesn't want us to talk to. Bans are
+ * stored in `ban` table, so they survive restart. Handshake with banned node
+ * is rejected right after its Hello is validated.
+ */
+pub(crate) struct BanManager;
+
+impl BanManager {
+    /// Returns false if ban can't be saved
+    pub fn ban_node(node_id: &xdr::NodeId) -> bool {
+        database::Ban::create(&node_id_to_strkey(node_id)).is_ok()
+    }
+
+    pub fn unban_node(node_id: &xdr::NodeId) -> bool {
+        database::Ban::delete(&node_id_to_strkey(node_id)).is_ok()
+    }
+
+    pub fn is_banned(node_id: &xdr::NodeId) -> bool {
+        match database::Ban::exists(&node_id_to_strkey(node_id)) {
+            Ok(is_banned) => is_banned,
+            Err(e) => {
+                error!("[Overlay][BanManager] Unable to check ban: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Strkeys of banned nodes
+    pub fn bans() -> Vec<String> {
+        match database::Ban::all() {
+            Ok(bans) => bans.into_iter().map(|ban| ban.nodeid).collect(),
+            Err(_) => vec![],
+        }
+    }
+}
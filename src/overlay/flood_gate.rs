@@ -1,5 +1,7 @@
-use super::{message_abbr, xdr};
-use std::collections::HashMap;
+use super::{message_hash, xdr};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Instant;
 
 /**
  * FloodGate keeps track of which peers have sent us which broadcast messages,
@@ -16,19 +18,23 @@ use std::collections::HashMap;
 #[derive(Debug, Default, Clone)]
 pub struct FloodGate {
     /// set of received messages
-    pub flood_map: HashMap<String, FloodRecord>,
+    pub flood_map: HashMap<xdr::Hash, FloodRecord>,
     /// shutdown flag
     pub m_shutting_down: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct FloodRecord {
-    /// current ledger block
+    /// ledger sequence current when the message was received
     pub m_ledger_seq: u32,
     /// received message
     pub m_message: xdr::StellarMessage,
-    /// list of peers that sent us the message
-    pub m_peers_told: Vec<String>,
+    /// peers that sent us the message or got it from us
+    pub m_peers_told: HashSet<SocketAddr>,
+    /// peers that sent us the message
+    pub m_senders: HashSet<SocketAddr>,
+    /// when the record was added, see `clear_received_before`
+    pub m_received_at: Instant,
 }
 
 impl FloodGate {
@@ -39,17 +45,24 @@ impl FloodGate {
         }
     }
 
-    // Floodgate will be cleared after every ledger close
+    /// Floodgate is cleared after every ledger close
     pub fn clear_below(&mut self, current_ledger: u32) {
         self.flood_map
             .retain(|_, record| record.m_ledger_seq + 10 > current_ledger);
     }
 
-    // returns true if this is a new record
+    /// Fallback clear while no ledger closes, so records don't pile up
+    pub fn clear_received_before(&mut self, deadline: Instant) {
+        self.flood_map
+            .retain(|_, record| record.m_received_at >= deadline);
+    }
+
+    // returns true if this is a new record. Sender is None for message
+    // originated locally
     pub fn add_record(
         &mut self,
         message: &xdr::StellarMessage,
-        from_peer_addr: String,
+        sender: Option<SocketAddr>,
         current_ledger: u32,
     ) -> bool {
         if self.m_shutting_down {
@@ -61,24 +74,48 @@ impl FloodGate {
             _ => return false,
         };
 
-        let index = message_abbr(message);
+        let index = message_hash(message);
 
         if let Some(record) = self.flood_map.get_mut(&index) {
-            record.m_peers_told.push(from_peer_addr);
+            if let Some(sender) = sender {
+                record.m_senders.insert(sender);
+                record.m_peers_told.insert(sender);
+            }
             false
         } else {
             self.flood_map.insert(
                 index,
-                FloodRecord::build(
-                    current_ledger,
-                    message.clone(),
-                    vec![from_peer_addr.clone()],
-                ),
+                FloodRecord::build(current_ledger, message.clone(), sender),
             );
             true
         }
     }
 
+    /// Peer already sent us this message
+    pub fn is_duplicate(&self, message: &xdr::StellarMessage, sender: &SocketAddr) -> bool {
+        self.flood_map
+            .get(&message_hash(message))
+            .map_or(false, |record| record.m_senders.contains(sender))
+    }
+
+    /// Peers which neither sent the message to us nor got it from us.
+    /// They are recorded as told, so the message is never sent to them again
+    pub fn peers_to_tell(
+        &mut self,
+        message: &xdr::StellarMessage,
+        peers: &HashSet<SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        let record = match self.flood_map.get_mut(&message_hash(message)) {
+            Some(record) => record,
+            None => return vec![],
+        };
+
+        let peers: Vec<SocketAddr> = peers.difference(&record.m_peers_told).cloned().collect();
+        record.m_peers_told.extend(&peers);
+
+        peers
+    }
+
     pub fn shutdown(&mut self) {
         self.m_shutting_down = true;
         self.flood_map.clear();
@@ -89,12 +126,15 @@ impl FloodRecord {
     pub fn build(
         m_ledger_seq: u32,
         m_message: xdr::StellarMessage,
-        m_peers_told: Vec<String>,
+        sender: Option<SocketAddr>,
     ) -> Self {
+        let m_senders: HashSet<SocketAddr> = sender.into_iter().collect();
         FloodRecord {
             m_ledger_seq,
             m_message,
-            m_peers_told,
+            m_peers_told: m_senders.clone(),
+            m_senders,
+            m_received_at: Instant::now(),
         }
     }
 }
@@ -104,6 +144,15 @@ mod tests {
     use super::*;
     use crate::factories::flood_gate::build_flood_gate;
     use crate::factories::internal_xdr::{build_envelope, build_transaction};
+    use std::time::Duration;
+
+    fn peer(address: &str) -> Option<SocketAddr> {
+        address.parse().ok()
+    }
+
+    fn peers_told(peers: &[&str]) -> HashSet<SocketAddr> {
+        peers.iter().filter_map(|address| peer(address)).collect()
+    }
 
     mod clear_below {
         use super::*;
@@ -116,8 +165,8 @@ mod tests {
             flood_gate.clear_below(200 + offset);
 
             assert_eq!(flood_gate.flood_map.len(), 3);
-            assert!(flood_gate.flood_map.get("345").is_some());
-            assert!(flood_gate.flood_map.get("678").is_some());
+            assert!(flood_gate.flood_map.get(&xdr::Hash([2; 32])).is_some());
+            assert!(flood_gate.flood_map.get(&xdr::Hash([3; 32])).is_some());
         }
 
         #[test]
@@ -128,10 +177,23 @@ mod tests {
             flood_gate.clear_below(200 + offset);
 
             assert_eq!(flood_gate.flood_map.len(), 2);
-            assert!(flood_gate.flood_map.get("678").is_some());
+            assert!(flood_gate.flood_map.get(&xdr::Hash([3; 32])).is_some());
         }
     }
 
+    #[test]
+    fn clear_received_before() {
+        let mut flood_gate = build_flood_gate();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let index = message_hash(&build_envelope());
+        flood_gate.flood_map.get_mut(&index).unwrap().m_received_at = deadline;
+
+        flood_gate.clear_received_before(deadline);
+
+        assert_eq!(flood_gate.flood_map.len(), 1);
+        assert!(flood_gate.flood_map.get(&index).is_some());
+    }
+
     mod add_record {
         use super::*;
 
@@ -142,7 +204,7 @@ mod tests {
 
             let message = build_transaction();
 
-            let result = flood_gate.add_record(&message, "192.168.5.5".to_string(), 500);
+            let result = flood_gate.add_record(&message, peer("192.168.5.5:11625"), 500);
 
             assert!(result);
             assert_eq!(flood_gate.flood_map.len(), before_add + 1);
@@ -154,20 +216,20 @@ mod tests {
             let before_add = flood_gate.flood_map.len();
 
             let message = build_envelope();
-            let index = message_abbr(&message);
+            let index = message_hash(&message);
 
             assert_eq!(
                 flood_gate.flood_map[&index].m_peers_told,
-                vec!["192.168.4.4".to_string()]
+                peers_told(&["192.168.4.4:11625"])
             );
 
-            let result = flood_gate.add_record(&message, "192.168.99.99".to_string(), 500);
+            let result = flood_gate.add_record(&message, peer("192.168.99.99:11625"), 500);
 
             assert_eq!(result, false);
             assert_eq!(flood_gate.flood_map.len(), before_add);
             assert_eq!(
                 flood_gate.flood_map[&index].m_peers_told,
-                vec!["192.168.4.4".to_string(), "192.168.99.99".to_string()]
+                peers_told(&["192.168.4.4:11625", "192.168.99.99:11625"])
             );
         }
 
@@ -176,12 +238,65 @@ mod tests {
             let mut flood_gate = build_flood_gate();
             flood_gate.shutdown();
             let result =
-                flood_gate.add_record(&build_transaction(), "192.168.5.5".to_string(), 500);
+                flood_gate.add_record(&build_transaction(), peer("192.168.5.5:11625"), 500);
 
             assert_eq!(result, false);
         }
     }
 
+    mod peers_to_tell {
+        use super::*;
+
+        fn peers() -> HashSet<SocketAddr> {
+            vec!["192.168.4.4:11625", "192.168.5.5:11625"]
+                .into_iter()
+                .map(|peer| peer.parse().unwrap())
+                .collect()
+        }
+
+        #[test]
+        fn skips_sender() {
+            let mut flood_gate = FloodGate::new();
+            let message = build_transaction();
+            flood_gate.add_record(&message, peer("192.168.4.4:11625"), 500);
+
+            assert_eq!(
+                flood_gate.peers_to_tell(&message, &peers()),
+                vec!["192.168.5.5:11625".parse::<SocketAddr>().unwrap()]
+            );
+        }
+
+        #[test]
+        fn only_sender_repeating_message_is_duplicate() {
+            let mut flood_gate = FloodGate::new();
+            let message = build_transaction();
+            flood_gate.add_record(&message, peer("192.168.4.4:11625"), 500);
+            flood_gate.peers_to_tell(&message, &peers());
+
+            assert!(flood_gate.is_duplicate(&message, &peer("192.168.4.4:11625").unwrap()));
+            assert!(!flood_gate.is_duplicate(&message, &peer("192.168.5.5:11625").unwrap()));
+        }
+
+        #[test]
+        fn tells_each_peer_once() {
+            let mut flood_gate = FloodGate::new();
+            let message = build_transaction();
+            flood_gate.add_record(&message, None, 500);
+
+            assert_eq!(flood_gate.peers_to_tell(&message, &peers()).len(), 2);
+            assert!(flood_gate.peers_to_tell(&message, &peers()).is_empty());
+        }
+
+        #[test]
+        fn without_record() {
+            let mut flood_gate = FloodGate::new();
+
+            assert!(flood_gate
+                .peers_to_tell(&build_transaction(), &peers())
+                .is_empty());
+        }
+    }
+
     #[test]
     fn shutdown() {
         let mut flood_gate = build_flood_gate();
@@ -0,0 +1,285 @@
+use super::{BigEndian, WriteBytesExt};
+use byteorder::ByteOrder;
+use std::fmt;
+use std::mem;
+
+/// Length of record marking header
+const HEADER_LENGTH: usize = 4;
+/// In RPC (see RFC5531 section 11), the high bit means this is the
+/// last record fragment in a record. If the high bit is clear, it
+/// means another fragment follows.
+const LAST_FRAGMENT_BIT: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    /// Record announced by fragment headers is larger than we accept
+    Oversized { length: usize, max_length: usize },
+    /// Connection closed in the middle of fragment header
+    IncompleteHeader { received: usize },
+    /// Connection closed before the whole record was received. Expected
+    /// length is unknown when it closed between continuation fragments
+    Truncated {
+        expected: Option<usize>,
+        received: usize,
+    },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Oversized { length, max_length } => write!(
+                f,
+                "record of {} bytes exceeds max size of {} bytes",
+                length, max_length
+            ),
+            FrameError::IncompleteHeader { received } => write!(
+                f,
+                "unable to read fragment header: received {} of {} bytes",
+                received, HEADER_LENGTH
+            ),
+            FrameError::Truncated {
+                expected: Some(expected),
+                received,
+            } => write!(
+                f,
+                "truncated record: expected {} bytes, received {}",
+                expected, received
+            ),
+            FrameError::Truncated {
+                expected: None,
+                received,
+            } => write!(
+                f,
+                "truncated record: received {} bytes before its last fragment",
+                received
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Wrap packed message into record, prefixed with its length.
+pub(crate) fn encode_record(message: &[u8]) -> Vec<u8> {
+    // We don't currently implement continuation fragments, and instead
+    // always set the last-record bit to produce a single-fragment record.
+    let mut record = Vec::with_capacity(HEADER_LENGTH + message.len());
+    record
+        .write_u32::<BigEndian>(message.len() as u32 | LAST_FRAGMENT_BIT)
+        .unwrap();
+    record.extend_from_slice(message);
+    record
+}
+
+/**
+ * RecordReader collects bytes read from socket and splits them into records,
+ * joining continuation fragments. Fragment length is checked against the limit
+ * before its body is buffered, so a peer can't make us keep more than one
+ * record of max size in memory.
+ */
+#[derive(Debug)]
+pub(crate) struct RecordReader {
+    max_record_length: usize,
+    /// Bytes read from socket, not split into fragments yet
+    buffer: Vec<u8>,
+    /// Fragments of the current record received so far
+    record: Vec<u8>,
+}
+
+impl RecordReader {
+    pub fn new(max_record_length: usize) -> Self {
+        RecordReader {
+            max_record_length,
+            buffer: Vec::new(),
+            record: Vec::new(),
+        }
+    }
+
+    /// Append bytes read from socket
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Return next complete record, if it was already read
+    pub fn next_record(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        loop {
+            if self.buffer.len() < HEADER_LENGTH {
+                return Ok(None);
+            }
+
+            let header = BigEndian::read_u32(&self.buffer[..HEADER_LENGTH]);
+            let is_last_fragment = header & LAST_FRAGMENT_BIT != 0;
+            let fragment_length = (header & !LAST_FRAGMENT_BIT) as usize;
+
+            let record_length = self.record.len() + fragment_length;
+            if record_length > self.max_record_length {
+                return Err(FrameError::Oversized {
+                    length: record_length,
+                    max_length: self.max_record_length,
+                });
+            }
+
+            let fragment_end = HEADER_LENGTH + fragment_length;
+            if self.buffer.len() < fragment_end {
+                return Ok(None);
+            }
+
+            self.record
+                .extend_from_slice(&self.buffer[HEADER_LENGTH..fragment_end]);
+            self.buffer.drain(..fragment_end);
+
+            if is_last_fragment {
+                return Ok(Some(mem::replace(&mut self.record, Vec::new())));
+            }
+        }
+    }
+
+    /// Check nothing is left unread when remote peer closes connection
+    pub fn finish(&self) -> Result<(), FrameError> {
+        if self.buffer.is_empty() && self.record.is_empty() {
+            return Ok(());
+        }
+
+        if self.buffer.len() < HEADER_LENGTH {
+            // Earlier fragments are complete, so the record is what was cut off
+            if !self.record.is_empty() {
+                return Err(FrameError::Truncated {
+                    expected: None,
+                    received: self.record.len() + self.buffer.len(),
+                });
+            }
+            return Err(FrameError::IncompleteHeader {
+                received: self.buffer.len(),
+            });
+        }
+
+        let header = BigEndian::read_u32(&self.buffer[..HEADER_LENGTH]);
+        let body_length = self.buffer.len() - HEADER_LENGTH;
+        Err(FrameError::Truncated {
+            expected: Some(self.record.len() + (header & !LAST_FRAGMENT_BIT) as usize),
+            received: self.record.len() + body_length,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(bytes: &[u8]) -> Vec<u8> {
+        let mut fragment = Vec::new();
+        fragment.write_u32::<BigEndian>(bytes.len() as u32).unwrap();
+        fragment.extend_from_slice(bytes);
+        fragment
+    }
+
+    #[test]
+    fn encode_record_sets_last_fragment_bit() {
+        assert_eq!(encode_record(&[1, 2, 3]), vec![0x80, 0, 0, 3, 1, 2, 3]);
+    }
+
+    mod next_record {
+        use super::*;
+
+        #[test]
+        fn records_split_between_reads() {
+            let mut bytes = encode_record(&[1, 2, 3]);
+            bytes.extend(encode_record(&[4, 5]));
+
+            let mut reader = RecordReader::new(1024);
+            reader.extend(&bytes[..5]);
+            assert_eq!(reader.next_record(), Ok(None));
+
+            reader.extend(&bytes[5..]);
+            assert_eq!(reader.next_record(), Ok(Some(vec![1, 2, 3])));
+            assert_eq!(reader.next_record(), Ok(Some(vec![4, 5])));
+            assert_eq!(reader.next_record(), Ok(None));
+        }
+
+        #[test]
+        fn joins_continuation_fragments() {
+            let mut bytes = fragment(&[1, 2]);
+            bytes.extend(fragment(&[3]));
+            bytes.extend(encode_record(&[4]));
+
+            let mut reader = RecordReader::new(1024);
+            reader.extend(&bytes);
+            assert_eq!(reader.next_record(), Ok(Some(vec![1, 2, 3, 4])));
+        }
+
+        #[test]
+        fn oversized_fragment() {
+            let mut reader = RecordReader::new(2);
+            reader.extend(&[0xff, 0xff, 0xff, 0xff]);
+
+            assert_eq!(
+                reader.next_record(),
+                Err(FrameError::Oversized {
+                    length: 0x7fff_ffff,
+                    max_length: 2
+                })
+            );
+        }
+
+        #[test]
+        fn oversized_record_of_small_fragments() {
+            let mut bytes = fragment(&[1, 2]);
+            bytes.extend(encode_record(&[3]));
+
+            let mut reader = RecordReader::new(2);
+            reader.extend(&bytes);
+            assert_eq!(
+                reader.next_record(),
+                Err(FrameError::Oversized {
+                    length: 3,
+                    max_length: 2
+                })
+            );
+        }
+    }
+
+    mod finish {
+        use super::*;
+
+        #[test]
+        fn incomplete_header() {
+            let mut reader = RecordReader::new(1024);
+            reader.extend(&[0x80, 0]);
+
+            assert_eq!(
+                reader.finish(),
+                Err(FrameError::IncompleteHeader { received: 2 })
+            );
+        }
+
+        #[test]
+        fn truncated_record() {
+            let mut reader = RecordReader::new(1024);
+            reader.extend(&encode_record(&[1, 2, 3])[..5]);
+
+            assert_eq!(reader.next_record(), Ok(None));
+            assert_eq!(
+                reader.finish(),
+                Err(FrameError::Truncated {
+                    expected: Some(3),
+                    received: 1
+                })
+            );
+        }
+
+        #[test]
+        fn closed_between_fragments() {
+            let mut reader = RecordReader::new(1024);
+            reader.extend(&fragment(&[1, 2]));
+
+            assert_eq!(reader.next_record(), Ok(None));
+            assert_eq!(
+                reader.finish(),
+                Err(FrameError::Truncated {
+                    expected: None,
+                    received: 2
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use super::{serde_xdr, xdr};
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long we wait for peer reply before asking the next one, as stellar-core does
+pub(crate) const FETCH_TIMEOUT: Duration = Duration::from_millis(1500);
+/// Rounds of asking every peer after which fetch is given up, so an item nobody
+/// has isn't requested forever
+const MAX_FETCH_ROUNDS: u32 = 3;
+/// Max number of items kept to answer requests of other peers
+const MAX_CACHED_ITEMS: usize = 1000;
+
+/// Request to send to peer
+pub(crate) type FetchRequest = (SocketAddr, xdr::StellarMessage);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemKind {
+    TxSet,
+    QuorumSet,
+}
+
+impl ItemKind {
+    fn request(self, hash: &xdr::Hash) -> xdr::StellarMessage {
+        match self {
+            ItemKind::TxSet => xdr::StellarMessage::TxSetHash(xdr::Uint256(hash.0)),
+            ItemKind::QuorumSet => xdr::StellarMessage::QSetHash(xdr::Uint256(hash.0)),
+        }
+    }
+
+    /// Type DONT_HAVE message refers to
+    pub fn message_type(self) -> xdr::MessageType {
+        match self {
+            ItemKind::TxSet => xdr::MessageType::TxSet,
+            ItemKind::QuorumSet => xdr::MessageType::ScpQuorumset,
+        }
+    }
+
+    pub fn from_message_type(message_type: xdr::MessageType) -> Option<Self> {
+        match message_type {
+            xdr::MessageType::TxSet => Some(ItemKind::TxSet),
+            xdr::MessageType::ScpQuorumset => Some(ItemKind::QuorumSet),
+            _ => None,
+        }
+    }
+}
+
+/// Item peers request from each other by hash
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    TxSet(xdr::TransactionSet),
+    QuorumSet(xdr::ScpQuorumSet),
+}
+
+impl Item {
+    pub fn kind(&self) -> ItemKind {
+        match self {
+            Item::TxSet(_) => ItemKind::TxSet,
+            Item::QuorumSet(_) => ItemKind::QuorumSet,
+        }
+    }
+
+    /// Hash the item is requested by
+    pub fn hash(&self) -> xdr::Hash {
+        match self {
+            Item::TxSet(tx_set) => tx_set_hash(tx_set),
+            Item::QuorumSet(quorum_set) => sha256(&serde_xdr::to_bytes(quorum_set).unwrap()),
+        }
+    }
+
+    pub fn to_message(&self) -> xdr::StellarMessage {
+        match self {
+            Item::TxSet(tx_set) => xdr::StellarMessage::TxSet(tx_set.clone()),
+            Item::QuorumSet(quorum_set) => xdr::StellarMessage::QSet(quorum_set.clone()),
+        }
+    }
+}
+
+/// Contents hash of TxSet, the same as stellar-core TxSetFrame::getContentsHash:
+/// previous ledger hash followed by transactions sorted by their hashes
+fn tx_set_hash(tx_set: &xdr::TransactionSet) -> xdr::Hash {
+    let mut txs: Vec<Vec<u8>> = tx_set
+        .txs
+        .iter()
+        .map(|tx| serde_xdr::to_bytes(tx).unwrap())
+        .collect();
+    txs.sort_by_cached_key(|tx| Sha256::digest(tx).to_vec());
+
+    let mut hasher = Sha256::new();
+    hasher.input(&tx_set.previous_ledger_hash.0);
+    for tx in txs {
+        hasher.input(&tx);
+    }
+
+    let mut hash: [u8; 32] = Default::default();
+    hash.copy_from_slice(&hasher.result());
+    xdr::Hash(hash)
+}
+
+fn sha256(bytes: &[u8]) -> xdr::Hash {
+    let mut hash: [u8; 32] = Default::default();
+    hash.copy_from_slice(&Sha256::digest(bytes));
+    xdr::Hash(hash)
+}
+
+/// Fetch of a single item
+#[derive(Clone, Debug)]
+struct Tracker<W> {
+    waiters: Vec<W>,
+    /// Peers not asked in the current round
+    peers_to_ask: Vec<SocketAddr>,
+    /// Peer we wait reply from, None when the round is over
+    asking: Option<SocketAddr>,
+    last_request_at: Instant,
+    /// Rounds started, see MAX_FETCH_ROUNDS
+    rounds: u32,
+}
+
+impl<W> Tracker<W> {
+    /// Ask the next peer of the round. When everyone was asked, a new round
+    /// starts only if `start_new_round` is set, so peers aren't asked in a tight loop
+    fn next_request(
+        &mut self,
+        kind: ItemKind,
+        hash: &xdr::Hash,
+        peers: &[SocketAddr],
+        now: Instant,
+        start_new_round: bool,
+    ) -> Option<FetchRequest> {
+        self.last_request_at = now;
+        self.asking = self.next_peer(peers);
+
+        if self.asking.is_none() && start_new_round {
+            self.rounds += 1;
+            self.peers_to_ask = peers.to_vec();
+            self.peers_to_ask.shuffle(&mut rand::thread_rng());
+            self.asking = self.next_peer(peers);
+        }
+
+        self.asking.map(|peer| (peer, kind.request(hash)))
+    }
+
+    /// Peers disconnected since the round started are skipped
+    fn next_peer(&mut self, peers: &[SocketAddr]) -> Option<SocketAddr> {
+        while let Some(peer) = self.peers_to_ask.pop() {
+            if peers.contains(&peer) {
+                return Some(peer);
+            }
+        }
+        None
+    }
+}
+
+/**
+ * ItemFetcher retrieves items of one kind (TxSet or QuorumSet) by their hash.
+ * Connected peers are asked one at a time: the next peer is asked after
+ * DONT_HAVE or when reply doesn't arrive in FETCH_TIMEOUT. Received item is
+ * accepted only if it hashes to the requested value, then waiters of the item
+ * are returned to be notified. Known items are cached to answer other peers.
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct ItemFetcher<W> {
+    kind: ItemKind,
+    trackers: HashMap<xdr::Hash, Tracker<W>>,
+    cache: HashMap<xdr::Hash, Item>,
+    /// Cached hashes from the oldest one
+    cache_order: VecDeque<xdr::Hash>,
+}
+
+impl<W> ItemFetcher<W> {
+    pub fn new(kind: ItemKind) -> Self {
+        ItemFetcher {
+            kind,
+            trackers: HashMap::new(),
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+        }
+    }
+
+    /// Start fetching item, or join the running fetch of it
+    pub fn fetch(
+        &mut self,
+        hash: xdr::Hash,
+        waiter: Option<W>,
+        peers: &[SocketAddr],
+        now: Instant,
+    ) -> Option<FetchRequest> {
+        let kind = self.kind;
+        let mut request = None;
+
+        let tracker = self.trackers.entry(hash).or_insert_with(|| {
+            let mut tracker = Tracker {
+                waiters: vec![],
+                peers_to_ask: vec![],
+                asking: None,
+                last_request_at: now,
+                rounds: 0,
+            };
+            request = tracker.next_request(kind, &hash, peers, now, true);
+            tracker
+        });
+
+        if let Some(waiter) = waiter {
+            tracker.waiters.push(waiter);
+        }
+        request
+    }
+
+    pub fn is_fetching(&self, hash: &xdr::Hash) -> bool {
+        self.trackers.contains_key(hash)
+    }
+
+    /// Peer we asked doesn't have the item, ask the next one
+    pub fn doesnt_have(
+        &mut self,
+        hash: &xdr::Hash,
+        peer: &SocketAddr,
+        peers: &[SocketAddr],
+        now: Instant,
+    ) -> Option<FetchRequest> {
+        let kind = self.kind;
+        match self.trackers.get_mut(hash) {
+            Some(tracker) if tracker.asking == Some(*peer) => {
+                tracker.next_request(kind, hash, peers, now, false)
+            }
+            _ => None,
+        }
+    }
+
+    /// Ask the next peer for items whose request timed out. Fetches whose
+    /// last round is over are given up. Returns requests to send and waiters
+    /// of given up items
+    pub fn check_timeouts(
+        &mut self,
+        peers: &[SocketAddr],
+        now: Instant,
+    ) -> (Vec<FetchRequest>, Vec<W>) {
+        let kind = self.kind;
+        let mut requests = vec![];
+        let mut exhausted = vec![];
+
+        for (hash, tracker) in self
+            .trackers
+            .iter_mut()
+            .filter(|(_, tracker)| now.duration_since(tracker.last_request_at) >= FETCH_TIMEOUT)
+        {
+            let start_new_round = tracker.rounds < MAX_FETCH_ROUNDS;
+            match tracker.next_request(kind, hash, peers, now, start_new_round) {
+                Some(request) => requests.push(request),
+                None if !start_new_round => exhausted.push(*hash),
+                None => (),
+            }
+        }
+
+        let waiters = exhausted
+            .iter()
+            .filter_map(|hash| self.trackers.remove(hash))
+            .flat_map(|tracker| tracker.waiters)
+            .collect();
+        (requests, waiters)
+    }
+
+    /// Stop fetching item which isn't needed anymore, e.g. its ledger is closed.
+    /// Returns waiters of the item
+    pub fn stop_fetching(&mut self, hash: &xdr::Hash) -> Option<Vec<W>> {
+        self.trackers.remove(hash).map(|tracker| tracker.waiters)
+    }
+
+    /// Received item is accepted only if we are fetching item with its hash.
+    /// Returns waiters of the item
+    pub fn recv_item(&mut self, item: Item) -> Option<Vec<W>> {
+        if item.kind() != self.kind {
+            return None;
+        }
+
+        let hash = item.hash();
+        let tracker = self.trackers.remove(&hash)?;
+        self.add_to_cache(item);
+        Some(tracker.waiters)
+    }
+
+    pub fn add_to_cache(&mut self, item: Item) {
+        let hash = item.hash();
+        if self.cache.insert(hash, item).is_some() {
+            return;
+        }
+
+        self.cache_order.push_back(hash);
+        if self.cache_order.len() > MAX_CACHED_ITEMS {
+            if let Some(oldest) = self.cache_order.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, hash: &xdr::Hash) -> Option<&Item> {
+        self.cache.get(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factories::internal_xdr::build_public_key;
+
+    fn quorum_set(threshold: u32) -> Item {
+        Item::QuorumSet(xdr::ScpQuorumSet {
+            threshold,
+            validators: vec![build_public_key()],
+            inner_sets: vec![],
+        })
+    }
+
+    fn peers() -> Vec<SocketAddr> {
+        vec![
+            "54.1.2.3:11625".parse().unwrap(),
+            "54.1.2.4:11625".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn asks_peers_one_at_a_time() {
+        let now = Instant::now();
+        let item = quorum_set(1);
+        let mut fetcher = ItemFetcher::<u32>::new(ItemKind::QuorumSet);
+
+        let (first, request) = fetcher.fetch(item.hash(), Some(1), &peers(), now).unwrap();
+        assert_eq!(
+            request,
+            xdr::StellarMessage::QSetHash(xdr::Uint256(item.hash().0))
+        );
+        // joining the running fetch doesn't send one more request
+        assert_eq!(fetcher.fetch(item.hash(), Some(2), &peers(), now), None);
+
+        let (second, _) = fetcher
+            .doesnt_have(&item.hash(), &first, &peers(), now)
+            .unwrap();
+        assert_ne!(first, second);
+
+        // everyone was asked, next round starts after timeout
+        assert_eq!(
+            fetcher.doesnt_have(&item.hash(), &second, &peers(), now),
+            None
+        );
+        assert!(fetcher.check_timeouts(&peers(), now).0.is_empty());
+        assert_eq!(
+            fetcher
+                .check_timeouts(&peers(), now + FETCH_TIMEOUT)
+                .0
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn asks_next_peer_after_timeout() {
+        let now = Instant::now();
+        let item = quorum_set(1);
+        let mut fetcher = ItemFetcher::<u32>::new(ItemKind::QuorumSet);
+
+        let (first, _) = fetcher.fetch(item.hash(), None, &peers(), now).unwrap();
+        let (requests, _) = fetcher.check_timeouts(&peers(), now + FETCH_TIMEOUT);
+        assert_eq!(requests.len(), 1);
+        assert_ne!(requests[0].0, first);
+    }
+
+    #[test]
+    fn gives_up_after_last_round() {
+        let mut now = Instant::now();
+        let item = quorum_set(1);
+        let mut fetcher = ItemFetcher::<u32>::new(ItemKind::QuorumSet);
+        fetcher.fetch(item.hash(), Some(1), &peers(), now);
+
+        // every round asks both peers
+        for _ in 0..(MAX_FETCH_ROUNDS * 2 - 1) {
+            now += FETCH_TIMEOUT;
+            assert_eq!(fetcher.check_timeouts(&peers(), now).0.len(), 1);
+        }
+
+        now += FETCH_TIMEOUT;
+        assert!(fetcher.check_timeouts(&peers(), now).0.is_empty());
+        assert!(!fetcher.is_fetching(&item.hash()));
+    }
+
+    #[test]
+    fn reports_waiters_of_given_up_fetch() {
+        let mut now = Instant::now();
+        let item = quorum_set(1);
+        let mut fetcher = ItemFetcher::new(ItemKind::QuorumSet);
+        fetcher.fetch(item.hash(), Some(1), &peers(), now);
+        fetcher.fetch(item.hash(), Some(2), &peers(), now);
+
+        for _ in 0..(MAX_FETCH_ROUNDS * 2 - 1) {
+            now += FETCH_TIMEOUT;
+            assert!(fetcher.check_timeouts(&peers(), now).1.is_empty());
+        }
+
+        now += FETCH_TIMEOUT;
+        assert_eq!(fetcher.check_timeouts(&peers(), now), (vec![], vec![1, 2]));
+    }
+
+    #[test]
+    fn stops_fetching_on_request() {
+        let now = Instant::now();
+        let item = quorum_set(1);
+        let mut fetcher = ItemFetcher::new(ItemKind::QuorumSet);
+        fetcher.fetch(item.hash(), Some(1), &peers(), now);
+
+        assert_eq!(fetcher.stop_fetching(&item.hash()), Some(vec![1]));
+        assert_eq!(
+            fetcher.check_timeouts(&peers(), now + FETCH_TIMEOUT),
+            (vec![], vec![])
+        );
+        assert_eq!(fetcher.recv_item(item), None);
+    }
+
+    #[test]
+    fn ignores_dont_have_from_peer_not_asked() {
+        let now = Instant::now();
+        let item = quorum_set(1);
+        let mut fetcher = ItemFetcher::<u32>::new(ItemKind::QuorumSet);
+
+        let (first, _) = fetcher.fetch(item.hash(), None, &peers(), now).unwrap();
+        let other = peers().into_iter().find(|peer| *peer != first).unwrap();
+        assert_eq!(
+            fetcher.doesnt_have(&item.hash(), &other, &peers(), now),
+            None
+        );
+    }
+
+    #[test]
+    fn received_item_notifies_waiters_and_is_cached() {
+        let now = Instant::now();
+        let item = quorum_set(1);
+        let mut fetcher = ItemFetcher::new(ItemKind::QuorumSet);
+        fetcher.fetch(item.hash(), Some(1), &peers(), now);
+        fetcher.fetch(item.hash(), Some(2), &peers(), now);
+
+        assert_eq!(fetcher.recv_item(item.clone()), Some(vec![1, 2]));
+        assert!(!fetcher.is_fetching(&item.hash()));
+        assert_eq!(fetcher.get(&item.hash()), Some(&item));
+    }
+
+    #[test]
+    fn rejects_item_not_matching_requested_hash() {
+        let now = Instant::now();
+        let mut fetcher = ItemFetcher::<u32>::new(ItemKind::QuorumSet);
+        fetcher.fetch(quorum_set(1).hash(), Some(1), &peers(), now);
+
+        assert_eq!(fetcher.recv_item(quorum_set(2)), None);
+        assert!(fetcher.is_fetching(&quorum_set(1).hash()));
+        assert_eq!(fetcher.get(&quorum_set(2).hash()), None);
+    }
+
+    #[test]
+    fn tx_set_hash_doesnt_depend_on_transactions_order() {
+        let tx = match crate::factories::internal_xdr::build_transaction() {
+            xdr::StellarMessage::Transaction(tx) => tx,
+            _ => unreachable!(),
+        };
+        let mut other_tx = tx.clone();
+        other_tx.tx.fee += 1;
+
+        let tx_set = xdr::TransactionSet {
+            previous_ledger_hash: xdr::Hash([1; 32]),
+            txs: vec![tx.clone(), other_tx.clone()],
+        };
+        let reordered = xdr::TransactionSet {
+            txs: vec![other_tx, tx],
+            ..tx_set.clone()
+        };
+
+        assert_eq!(tx_set_hash(&tx_set), tx_set_hash(&reordered));
+    }
+}
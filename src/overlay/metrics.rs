@@ -0,0 +1,452 @@
+use super::xdr;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+lazy_static! {
+    /// Overlay metrics shared by all actors
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+/// Upper bounds of connection duration histogram buckets, in seconds
+const CONNECTION_DURATION_BUCKETS: [f64; 8] =
+    [1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 21600.0, 86400.0];
+
+const MESSAGES: &str = "overlay_messages_total";
+const BYTES: &str = "overlay_bytes_total";
+const PEER_MESSAGES: &str = "overlay_peer_messages_total";
+const PEER_BYTES: &str = "overlay_peer_bytes_total";
+const HANDSHAKES: &str = "overlay_handshakes_total";
+const FLOOD_MESSAGES: &str = "overlay_flood_messages_total";
+const CONNECTION_DURATION: &str = "overlay_connection_duration_seconds";
+
+/// HELP text of each metric, in the order metrics are exported
+const DESCRIPTIONS: [(&str, &str); 7] = [
+    (
+        MESSAGES,
+        "Messages sent and received, by direction and type",
+    ),
+    (BYTES, "Bytes sent and received, by direction and type"),
+    (
+        PEER_MESSAGES,
+        "Messages sent to and received from connected peer, by direction and type",
+    ),
+    (
+        PEER_BYTES,
+        "Bytes sent to and received from connected peer, by direction and type",
+    ),
+    (HANDSHAKES, "Finished handshakes, by result"),
+    (
+        FLOOD_MESSAGES,
+        "Flooded messages received, unique or already seen",
+    ),
+    (CONNECTION_DURATION, "Duration of closed peer connections"),
+];
+
+const MESSAGE_TYPE_COUNT: usize = 14;
+
+/// Message type names, the same as in stellar-core XDR, see `message_type_index`
+const MESSAGE_TYPES: [&str; MESSAGE_TYPE_COUNT] = [
+    "ERROR_MSG",
+    "VOID",
+    "AUTH",
+    "DONT_HAVE",
+    "GET_PEERS",
+    "PEERS",
+    "GET_TX_SET",
+    "TX_SET",
+    "TRANSACTION",
+    "GET_SCP_QUORUMSET",
+    "SCP_QUORUMSET",
+    "SCP_MESSAGE",
+    "GET_SCP_STATE",
+    "HELLO",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    const ALL: [Direction; 2] = [Direction::Sent, Direction::Received];
+
+    fn label(self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+}
+
+/// Label names and values of one series
+type Labels = Vec<(&'static str, String)>;
+
+/// Counters by direction and message type, indexed the same as `MESSAGE_TYPES`
+type TrafficCounters = [[AtomicU64; MESSAGE_TYPE_COUNT]; 2];
+
+/// Messages and bytes sent and received, in total or over one connection.
+/// Counters are atomic, so traffic is recorded without taking a lock
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    messages: TrafficCounters,
+    bytes: TrafficCounters,
+}
+
+impl Traffic {
+    fn add(&self, direction: Direction, message_type: usize, bytes: usize) {
+        let direction = direction as usize;
+        self.messages[direction][message_type].fetch_add(1, Ordering::Relaxed);
+        self.bytes[direction][message_type].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Message queued to peer. It is counted as sent once reactor writes it to
+/// socket, so messages dropped from full outbound queue aren't reported
+#[derive(Debug, Clone)]
+pub(crate) struct SentMessage {
+    peer_traffic: Arc<Traffic>,
+    message_type: usize,
+    bytes: usize,
+}
+
+impl SentMessage {
+    pub fn new(peer_traffic: &Arc<Traffic>, message: &xdr::StellarMessage, bytes: usize) -> Self {
+        SentMessage {
+            peer_traffic: Arc::clone(peer_traffic),
+            message_type: message_type_index(message),
+            bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    /// Observations in each of `CONNECTION_DURATION_BUCKETS`, not cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, bounds: &[f64], value: f64) {
+        if let Some(index) = bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Series recorded rarely enough to be kept under a lock
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<&'static str, Histogram>,
+}
+
+impl Registry {
+    fn increment(&mut self, name: &'static str, labels: Labels, value: u64) {
+        *self.counters.entry((name, labels)).or_insert(0) += value;
+    }
+
+    fn observe(&mut self, name: &'static str, bounds: &[f64], value: f64) {
+        self.histograms
+            .entry(name)
+            .or_insert_with(|| Histogram::new(bounds))
+            .observe(bounds, value);
+    }
+}
+
+/**
+ * Metrics counts overlay traffic in total and per connected peer: messages
+ * and bytes by message type, handshake results, flooded messages and durations
+ * of connections. They are exported in Prometheus text format by the command
+ * handler /metrics endpoint.
+ */
+#[derive(Debug, Default)]
+pub struct Metrics {
+    traffic: Traffic,
+    /// Traffic of connected peers, shared with their connections
+    peer_traffic: Mutex<BTreeMap<SocketAddr, Arc<Traffic>>>,
+    /// Flooded messages, unique and already seen
+    flood_messages: [AtomicU64; 2],
+    registry: Mutex<Registry>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Traffic counters of peer connection. Connection keeps them,
+    /// so the lock is taken once per connection, not per message
+    pub(crate) fn peer_traffic(&self, peer: &SocketAddr) -> Arc<Traffic> {
+        let mut peer_traffic = self.peer_traffic.lock().unwrap();
+        Arc::clone(peer_traffic.entry(*peer).or_insert_with(Default::default))
+    }
+
+    pub(crate) fn record_received(
+        &self,
+        peer_traffic: &Traffic,
+        message: &xdr::StellarMessage,
+        bytes: usize,
+    ) {
+        let message_type = message_type_index(message);
+        self.traffic.add(Direction::Received, message_type, bytes);
+        peer_traffic.add(Direction::Received, message_type, bytes);
+    }
+
+    /// Message was written to socket
+    pub(crate) fn record_sent(&self, message: &SentMessage) {
+        self.traffic
+            .add(Direction::Sent, message.message_type, message.bytes);
+        message
+            .peer_traffic
+            .add(Direction::Sent, message.message_type, message.bytes);
+    }
+
+    /// `result` is "success" or the reason handshake failed
+    pub fn record_handshake(&self, result: &str) {
+        let labels = vec![("result", result.to_string())];
+        self.registry
+            .lock()
+            .unwrap()
+            .increment(HANDSHAKES, labels, 1);
+    }
+
+    pub fn record_flood_message(&self, unique: bool) {
+        self.flood_messages[flood_kind(unique)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Connection is closed: its duration is recorded and per peer series are removed
+    pub fn record_connection_closed(&self, peer: &SocketAddr, duration: Duration) {
+        self.peer_traffic.lock().unwrap().remove(peer);
+        self.registry.lock().unwrap().observe(
+            CONNECTION_DURATION,
+            &CONNECTION_DURATION_BUCKETS,
+            duration.as_secs() as f64,
+        );
+    }
+
+    /// Metrics in Prometheus text format. When `peer` is given, only series
+    /// of that peer are exported
+    pub fn export(&self, peer: Option<&str>) -> String {
+        let registry = self.registry.lock().unwrap();
+        let peer_traffic = self.peer_traffic.lock().unwrap();
+        let mut output = String::new();
+
+        for (name, help) in DESCRIPTIONS.iter() {
+            let series = match *name {
+                PEER_MESSAGES | PEER_BYTES => peer_traffic
+                    .iter()
+                    .filter(|(address, _)| peer.map_or(true, |peer| address.to_string() == peer))
+                    .flat_map(|(address, traffic)| traffic_series(name, traffic, Some(address)))
+                    .collect(),
+                _ if peer.is_some() => vec![],
+                MESSAGES | BYTES => traffic_series(name, &self.traffic, None),
+                FLOOD_MESSAGES => self.flood_series(),
+                _ => registry
+                    .counters
+                    .iter()
+                    .filter(|((series_name, _), _)| series_name == name)
+                    .map(|((_, labels), value)| (labels.clone(), *value))
+                    .collect(),
+            };
+            let histogram = registry.histograms.get(name).filter(|_| peer.is_none());
+
+            if series.is_empty() && histogram.is_none() {
+                continue;
+            }
+
+            if let Some(histogram) = histogram {
+                writeln!(output, "# HELP {} {}", name, help).unwrap();
+                writeln!(output, "# TYPE {} histogram", name).unwrap();
+                write_histogram(&mut output, name, histogram);
+                continue;
+            }
+
+            writeln!(output, "# HELP {} {}", name, help).unwrap();
+            writeln!(output, "# TYPE {} counter", name).unwrap();
+            for (labels, value) in series {
+                writeln!(output, "{}{} {}", name, format_labels(&labels), value).unwrap();
+            }
+        }
+
+        output
+    }
+
+    fn flood_series(&self) -> Vec<(Labels, u64)> {
+        [true, false]
+            .iter()
+            .map(|unique| {
+                let kind = if *unique { "unique" } else { "duplicate" };
+                let count = self.flood_messages[flood_kind(*unique)].load(Ordering::Relaxed);
+                (vec![("kind", kind.to_string())], count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+
+fn flood_kind(unique: bool) -> usize {
+    if unique {
+        0
+    } else {
+        1
+    }
+}
+
+/// Non-zero message or byte counters of the traffic, depending on metric name
+fn traffic_series(name: &str, traffic: &Traffic, peer: Option<&SocketAddr>) -> Vec<(Labels, u64)> {
+    let counters = match name {
+        MESSAGES | PEER_MESSAGES => &traffic.messages,
+        _ => &traffic.bytes,
+    };
+
+    let mut series = vec![];
+    for direction in Direction::ALL.iter() {
+        for (message_type, counter) in counters[*direction as usize].iter().enumerate() {
+            let value = counter.load(Ordering::Relaxed);
+            if value == 0 {
+                continue;
+            }
+
+            let mut labels = vec![
+                ("direction", direction.label().to_string()),
+                ("type", MESSAGE_TYPES[message_type].to_string()),
+            ];
+            if let Some(peer) = peer {
+                labels.push(("peer", peer.to_string()));
+            }
+            series.push((labels, value));
+        }
+    }
+    series
+}
+
+fn write_histogram(output: &mut String, name: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, count) in CONNECTION_DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += count;
+        writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+    }
+    writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count).unwrap();
+    writeln!(output, "{}_sum {}", name, histogram.sum).unwrap();
+    writeln!(output, "{}_count {}", name, histogram.count).unwrap();
+}
+
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Message type name, the same as in stellar-core XDR
+pub fn message_type_name(message: &xdr::StellarMessage) -> &'static str {
+    MESSAGE_TYPES[message_type_index(message)]
+}
+
+fn message_type_index(message: &xdr::StellarMessage) -> usize {
+    match message {
+        xdr::StellarMessage::Error(_) => 0,
+        xdr::StellarMessage::Void => 1,
+        xdr::StellarMessage::Auth(_) => 2,
+        xdr::StellarMessage::DontHave(_) => 3,
+        xdr::StellarMessage::GetPeers => 4,
+        xdr::StellarMessage::Peers(_) => 5,
+        xdr::StellarMessage::TxSetHash(_) => 6,
+        xdr::StellarMessage::TxSet(_) => 7,
+        xdr::StellarMessage::Transaction(_) => 8,
+        xdr::StellarMessage::QSetHash(_) => 9,
+        xdr::StellarMessage::QSet(_) => 10,
+        xdr::StellarMessage::Envelope(_) => 11,
+        xdr::StellarMessage::GetScpLedgerSeq(_) => 12,
+        xdr::StellarMessage::Hello(_) => 13,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    fn record_sent(metrics: &Metrics, address: &str, message: &xdr::StellarMessage, bytes: usize) {
+        let peer_traffic = metrics.peer_traffic(&peer(address));
+        metrics.record_sent(&SentMessage::new(&peer_traffic, message, bytes));
+    }
+
+    #[test]
+    fn counts_messages_in_total_and_per_peer() {
+        let metrics = Metrics::new();
+        let message = xdr::StellarMessage::GetPeers;
+        record_sent(&metrics, "54.1.2.3:11625", &message, 40);
+        record_sent(&metrics, "54.1.2.4:11625", &message, 40);
+
+        let output = metrics.export(None);
+        assert!(output.contains("# TYPE overlay_bytes_total counter\n"));
+        assert!(output.contains("overlay_bytes_total{direction=\"sent\",type=\"GET_PEERS\"} 80\n"));
+        assert!(output.contains(
+            "overlay_peer_messages_total{direction=\"sent\",type=\"GET_PEERS\",peer=\"54.1.2.3:11625\"} 1\n"
+        ));
+
+        let output = metrics.export(Some("54.1.2.4:11625"));
+        assert!(output.contains("peer=\"54.1.2.4:11625\"} 1\n"));
+        assert!(!output.contains("54.1.2.3:11625"));
+        assert!(!output.contains("overlay_bytes_total{"));
+    }
+
+    #[test]
+    fn closed_connection_is_observed_and_its_series_removed() {
+        let metrics = Metrics::new();
+        let address = peer("54.1.2.3:11625");
+        metrics.record_received(
+            &metrics.peer_traffic(&address),
+            &xdr::StellarMessage::GetPeers,
+            40,
+        );
+        assert!(metrics
+            .export(None)
+            .contains("overlay_messages_total{direction=\"received\",type=\"GET_PEERS\"} 1\n"));
+
+        metrics.record_connection_closed(&address, Duration::from_secs(30));
+
+        let output = metrics.export(None);
+        assert!(!output.contains("54.1.2.3:11625"));
+        assert!(output.contains("overlay_connection_duration_seconds_bucket{le=\"10\"} 0\n"));
+        assert!(output.contains("overlay_connection_duration_seconds_bucket{le=\"60\"} 1\n"));
+        assert!(output.contains("overlay_connection_duration_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(
+            format_labels(&[("result", "bad \"cert\"".to_string())]),
+            "{result=\"bad \\\"cert\\\"\"}"
+        );
+    }
+}
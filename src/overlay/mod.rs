@@ -1,30 +1,52 @@
-#![allow(dead_code, unused_must_use)]
+#![allow(dead_code)]
 
 use sha2::digest::Digest;
 use sha2::Sha256;
 
+pub(crate) mod ban_manager;
 pub(crate) mod flood_gate;
+pub(crate) mod framing;
+pub(crate) mod item_fetcher;
+pub(crate) mod metrics;
+pub(crate) mod outbound_queue;
 pub(crate) mod overlay_manager;
 pub(crate) mod peer;
+pub(crate) mod peer_activity;
+pub(crate) mod peer_auth;
+pub(crate) mod peer_misbehavior;
+pub(crate) mod reactor;
 
 pub(crate) use crate::{
     config::CONFIG,
-    database,
-    scp::local_node::{LocalNode, LOCAL_NODE},
+    crypto, database,
+    scp::local_node::{LocalNode, LOCAL_NODE, PEER_AUTH},
     xdr,
 };
 pub(crate) use byteorder::{BigEndian, WriteBytesExt};
 pub(crate) use itertools;
-pub(crate) use log::{debug, error, info};
+pub(crate) use log::{debug, error, info, warn};
 pub(crate) use rand::Rng;
 pub(crate) use serde_xdr;
 pub(crate) use sha2;
 
+pub(crate) use self::ban_manager::BanManager;
 pub(crate) use self::flood_gate::FloodGate;
-pub(crate) use self::overlay_manager::OverlayManager;
-pub(crate) use self::peer::{Peer, PeerInterface};
+pub(crate) use self::item_fetcher::{Item, ItemFetcher, ItemKind};
+pub(crate) use self::metrics::METRICS;
+pub(crate) use self::outbound_queue::{MessagePriority, OutboundQueue};
+pub(crate) use self::overlay_manager::{Admission, HelloVerdict, NodeConnection, OverlayManager};
+pub(crate) use self::peer::{Peer, PeerError, PeerInterface};
+pub(crate) use self::peer_activity::{PeerTimeout, PeerTimeouts};
+pub(crate) use self::peer_auth::PeerAuth;
+pub(crate) use self::peer_misbehavior::{
+    MessageCategory, Misbehavior, MisbehaviorScore, PeerRateLimiter, RateCheck,
+};
+pub(crate) use self::reactor::{Connection, ConnectionEvent, Reactor};
 
-pub(crate) fn message_abbr(message: &xdr::StellarMessage) -> String {
+/// SHA-256 of message XDR, identifies flooded messages
+pub(crate) fn message_hash(message: &xdr::StellarMessage) -> xdr::Hash {
     let bytes = serde_xdr::to_bytes(message).unwrap();
-    String::from_utf8_lossy(Sha256::digest(&bytes.as_slice()).as_slice()).to_string()
+    let mut hash: [u8; 32] = Default::default();
+    hash.copy_from_slice(&Sha256::digest(&bytes));
+    xdr::Hash(hash)
 }
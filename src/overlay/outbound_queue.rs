@@ -0,0 +1,242 @@
+use super::{metrics::SentMessage, xdr, CONFIG};
+use std::collections::VecDeque;
+
+/// Priority of queued message. When queue is full, lower priority messages are dropped first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum MessagePriority {
+    /// Flooded transactions, any of our peers could relay them
+    Low,
+    /// Requests and responses between peers
+    Normal,
+    /// SCP messages and handshake, losing them stalls consensus or connection
+    High,
+}
+
+impl MessagePriority {
+    pub fn of(message: &xdr::StellarMessage) -> Self {
+        match message {
+            xdr::StellarMessage::Transaction(_) => MessagePriority::Low,
+            xdr::StellarMessage::Hello(_)
+            | xdr::StellarMessage::Auth(_)
+            | xdr::StellarMessage::Error(_)
+            | xdr::StellarMessage::Envelope(_) => MessagePriority::High,
+            _ => MessagePriority::Normal,
+        }
+    }
+}
+
+/**
+ * OutboundQueue keeps records waiting to be written to peer socket.
+ * It is bounded both by number of records and by number of bytes. When a new
+ * record doesn't fit, queued records of lower priority are dropped to make room,
+ * so flooded transactions go away before SCP messages.
+ */
+#[derive(Debug)]
+pub(crate) struct OutboundQueue {
+    max_bytes: usize,
+    max_messages: usize,
+    records: VecDeque<(MessagePriority, Vec<u8>, Option<SentMessage>)>,
+    /// Bytes of queued records not written yet
+    queued_bytes: usize,
+    /// Bytes of the front record already written to socket
+    write_offset: usize,
+}
+
+impl OutboundQueue {
+    pub fn new(max_bytes: usize, max_messages: usize) -> Self {
+        OutboundQueue {
+            max_bytes,
+            max_messages,
+            records: VecDeque::new(),
+            queued_bytes: 0,
+            write_offset: 0,
+        }
+    }
+
+    pub fn from_config() -> Self {
+        Self::new(
+            *CONFIG.outbound_queue_max_bytes(),
+            *CONFIG.outbound_queue_max_messages(),
+        )
+    }
+
+    /// Queue record. Returns false if there is no room for it even after
+    /// dropping records of lower priority. Sent message is returned by
+    /// `consume` once the whole record is written
+    pub fn push(
+        &mut self,
+        priority: MessagePriority,
+        record: Vec<u8>,
+        sent: Option<SentMessage>,
+    ) -> bool {
+        while self.is_full_for(record.len()) {
+            if !self.drop_lower_priority(priority) {
+                return false;
+            }
+        }
+
+        self.queued_bytes += record.len();
+        self.records.push_back((priority, record, sent));
+        true
+    }
+
+    /// Unwritten part of the front record
+    pub fn front(&self) -> Option<&[u8]> {
+        self.records
+            .front()
+            .map(|(_, record, _)| &record[self.write_offset..])
+    }
+
+    /// Mark bytes of the front record as written to socket. Returns sent
+    /// message of the record, if it is written completely
+    pub fn consume(&mut self, written: usize) -> Option<SentMessage> {
+        self.write_offset += written;
+        self.queued_bytes -= written;
+
+        let is_front_written = match self.records.front() {
+            Some((_, record, _)) => self.write_offset >= record.len(),
+            None => false,
+        };
+
+        if !is_front_written {
+            return None;
+        }
+        self.write_offset = 0;
+        self.records.pop_front().and_then(|(_, _, sent)| sent)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Record larger than byte limit is still accepted into empty queue,
+    /// otherwise it could never be sent
+    fn is_full_for(&self, record_length: usize) -> bool {
+        self.records.len() >= self.max_messages
+            || (!self.records.is_empty() && self.queued_bytes + record_length > self.max_bytes)
+    }
+
+    /// Drop the oldest record with the lowest priority below `priority`
+    fn drop_lower_priority(&mut self, priority: MessagePriority) -> bool {
+        // partially written record can't be dropped without breaking framing
+        let first_droppable = if self.write_offset > 0 { 1 } else { 0 };
+
+        let victim = self
+            .records
+            .iter()
+            .enumerate()
+            .skip(first_droppable)
+            .filter(|(_, (record_priority, _, _))| *record_priority < priority)
+            .min_by_key(|(index, (record_priority, _, _))| (*record_priority, *index))
+            .map(|(index, _)| index);
+
+        match victim {
+            Some(index) => {
+                if let Some((_, record, _)) = self.records.remove(index) {
+                    self.queued_bytes -= record.len();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay::metrics::Traffic;
+    use std::sync::Arc;
+
+    mod push {
+        use super::*;
+
+        #[test]
+        fn drops_transactions_before_scp_messages() {
+            let mut queue = OutboundQueue::new(1024, 2);
+            assert!(queue.push(MessagePriority::Low, vec![1], None));
+            assert!(queue.push(MessagePriority::High, vec![2], None));
+            assert!(queue.push(MessagePriority::High, vec![3], None));
+
+            assert_eq!(queue.len(), 2);
+            assert_eq!(queue.front(), Some(&[2][..]));
+        }
+
+        #[test]
+        fn rejects_record_without_lower_priority_to_drop() {
+            let mut queue = OutboundQueue::new(1024, 1);
+            assert!(queue.push(MessagePriority::High, vec![1], None));
+
+            assert!(!queue.push(MessagePriority::Low, vec![2], None));
+            assert!(!queue.push(MessagePriority::High, vec![3], None));
+            assert_eq!(queue.front(), Some(&[1][..]));
+        }
+
+        #[test]
+        fn respects_byte_limit() {
+            let mut queue = OutboundQueue::new(4, 10);
+            assert!(queue.push(MessagePriority::Low, vec![1, 1, 1], None));
+            assert!(queue.push(MessagePriority::Normal, vec![2, 2], None));
+
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue.queued_bytes(), 2);
+        }
+
+        #[test]
+        fn keeps_partially_written_record() {
+            let mut queue = OutboundQueue::new(1024, 1);
+            assert!(queue.push(MessagePriority::Low, vec![1, 1], None));
+            queue.consume(1);
+
+            assert!(!queue.push(MessagePriority::High, vec![2], None));
+            assert_eq!(queue.front(), Some(&[1][..]));
+        }
+    }
+
+    #[test]
+    fn consume_partial_writes() {
+        let mut queue = OutboundQueue::new(1024, 10);
+        queue.push(MessagePriority::Normal, vec![1, 2, 3], None);
+        queue.push(MessagePriority::Normal, vec![4], None);
+
+        queue.consume(2);
+        assert_eq!(queue.front(), Some(&[3][..]));
+
+        queue.consume(1);
+        assert_eq!(queue.front(), Some(&[4][..]));
+        assert_eq!(queue.queued_bytes(), 1);
+
+        queue.consume(1);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn consume_returns_sent_message_of_written_record() {
+        let traffic = Arc::new(Traffic::default());
+        let sent = || {
+            Some(SentMessage::new(
+                &traffic,
+                &xdr::StellarMessage::GetPeers,
+                2,
+            ))
+        };
+        let mut queue = OutboundQueue::new(1024, 2);
+        queue.push(MessagePriority::Low, vec![1, 1], sent());
+        queue.push(MessagePriority::Normal, vec![2, 2], sent());
+        // the first record is dropped, so only two are reported as sent
+        queue.push(MessagePriority::High, vec![3], sent());
+
+        assert!(queue.consume(1).is_none());
+        assert!(queue.consume(1).is_some());
+        assert!(queue.consume(1).is_some());
+        assert!(queue.is_empty());
+    }
+}
@@ -1,6 +1,20 @@
-use super::{database, itertools::join, xdr, CONFIG};
-use std::collections::HashSet;
-use std::time::{SystemTime, UNIX_EPOCH};
+use super::{
+    crypto::node_id_from_strkey, database, peer::PeerError, warn, xdr, Misbehavior,
+    MisbehaviorScore, CONFIG,
+};
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Instant;
+
+/// Max number of addresses we send in PEERS message
+const MAX_PEERS_TO_SEND: usize = 50;
+/// Peers failed more times than this are not shared with others
+const MAX_FAILURES_TO_SEND: i32 = 10;
+/// Sample taken from database is larger than we send, as private
+/// addresses are filtered out afterwards
+const PEERS_SAMPLE_SIZE: i64 = 4 * MAX_PEERS_TO_SEND as i64;
 
 /**
  * OverlayManager maintains a virtual broadcast network, consisting of a set of
@@ -39,10 +53,51 @@ use std::time::{SystemTime, UNIX_EPOCH};
  */
 #[derive(Clone, Debug)]
 pub struct OverlayManager {
-    known_peer_adresses: HashSet<String>,
-    authenticated_peers: HashSet<String>,
-    pending_peers: HashSet<String>,
-    failed_peers: HashSet<String>,
+    known_peer_adresses: HashSet<SocketAddr>,
+    authenticated_peers: HashSet<SocketAddr>,
+    /// When each of authenticated peers completed handshake
+    authenticated_at: HashMap<SocketAddr, Instant>,
+    pending_peers: HashSet<SocketAddr>,
+    failed_peers: HashSet<SocketAddr>,
+    /// The only connection we keep with each remote node, registered after its Hello
+    node_connections: HashMap<xdr::NodeId, NodeConnection>,
+    /// Addresses of `preferred_peers` from config
+    preferred_addresses: HashSet<SocketAddr>,
+    /// Node IDs of `preferred_peer_keys` from config
+    preferred_node_ids: HashSet<xdr::NodeId>,
+    /// Connections with preferred nodes, they are never rejected or evicted
+    preferred_connections: HashSet<SocketAddr>,
+    /// Scores are kept per node, so reconnecting doesn't reset them
+    misbehavior_scores: HashMap<xdr::NodeId, MisbehaviorScore>,
+}
+
+/// Connection with remote node whose Hello we received
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct NodeConnection {
+    pub address: SocketAddr,
+    /// Address remote node accepts connections on
+    pub listening_address: SocketAddr,
+    pub we_called_remote: bool,
+}
+
+/// Decision about connection with node which sent us Hello
+#[derive(Debug, PartialEq)]
+pub(crate) enum HelloVerdict {
+    Accept,
+    /// Another connection with this node is kept
+    Reject,
+    /// Accept connection and drop the existing one with this node
+    ReplaceExisting(SocketAddr),
+}
+
+/// Decision about authenticated connection taking a slot
+#[derive(Debug, PartialEq)]
+pub(crate) enum Admission {
+    Accept,
+    /// Accept connection and drop the lower value one to make room
+    Evict(SocketAddr),
+    /// No room, remote peer gets a list of other peers to try
+    Reject,
 }
 
 #[derive(Debug)]
@@ -57,149 +112,953 @@ impl OverlayManager {
         OverlayManager {
             known_peer_adresses: HashSet::new(),
             authenticated_peers: HashSet::new(),
+            authenticated_at: HashMap::new(),
             pending_peers: HashSet::new(),
             failed_peers: HashSet::new(),
+            node_connections: HashMap::new(),
+            preferred_addresses: HashSet::new(),
+            preferred_node_ids: HashSet::new(),
+            preferred_connections: HashSet::new(),
+            misbehavior_scores: HashMap::new(),
+        }
+    }
+
+    /// Resolve preferred peers from config. They are saved to database,
+    /// so failed ones are re-dialed with the same backoff as others
+    pub(crate) fn load_preferred_peers(&mut self) {
+        for preferred_peer in CONFIG.preferred_peers() {
+            match preferred_peer.address().to_socket_addrs() {
+                Ok(mut addrs) => {
+                    if let Some(address) = addrs.next() {
+                        if let Err(e) = database::Peer::insert_if_missing(&address) {
+                            warn!(
+                                "[Overlay] unable to save preferred peer {}, cause: {}",
+                                address, e
+                            );
+                        }
+                        self.preferred_addresses.insert(address);
+                    }
+                }
+                Err(e) => warn!(
+                    "[Overlay] DNS lookup for the preferred peer {} failed: {}",
+                    preferred_peer.address(),
+                    e
+                ),
+            }
+        }
+
+        for key in CONFIG.preferred_peer_keys() {
+            match node_id_from_strkey(key) {
+                Some(node_id) => {
+                    self.preferred_node_ids.insert(node_id);
+                }
+                None => warn!("[Overlay] Invalid preferred peer key {}", key),
+            }
+        }
+    }
+
+    pub(crate) fn add_preferred_address(&mut self, address: SocketAddr) {
+        self.preferred_addresses.insert(address);
+    }
+
+    pub(crate) fn add_preferred_node_id(&mut self, node_id: xdr::NodeId) {
+        self.preferred_node_ids.insert(node_id);
+    }
+
+    pub(crate) fn is_preferred(&self, node_id: &xdr::NodeId, connection: &NodeConnection) -> bool {
+        self.preferred_node_ids.contains(node_id)
+            || self
+                .preferred_addresses
+                .contains(&connection.listening_address)
+    }
+
+    /// Node ID of incoming connection is unknown until Hello,
+    /// so only its IP can rule it out of preferred peers
+    pub(crate) fn may_be_preferred(&self, ip: &IpAddr) -> bool {
+        !self.preferred_node_ids.is_empty()
+            || self
+                .preferred_addresses
+                .iter()
+                .any(|address| address.ip() == *ip)
+    }
+
+    /// In preferred only mode other nodes are refused right after Hello
+    pub(crate) fn check_admission(
+        &self,
+        node_id: &xdr::NodeId,
+        connection: &NodeConnection,
+        preferred_only: bool,
+    ) -> Result<(), PeerError> {
+        if preferred_only && !self.is_preferred(node_id, connection) {
+            return Err(PeerError::NotPreferred);
         }
+
+        Ok(())
+    }
+
+    /// Inbound and outbound connections have separate limits. Preferred peer is
+    /// never rejected, the lowest value peer is evicted to make room for it
+    pub(crate) fn admit_authenticated(&self, address: &SocketAddr) -> Admission {
+        let connection = match self.connection_by_address(address) {
+            Some(connection) => connection,
+            None => return Admission::Reject,
+        };
+
+        let max = if connection.we_called_remote {
+            self.max_outbound_peers()
+        } else {
+            self.max_inbound_peers()
+        };
+
+        let occupied = self
+            .authenticated_connections(connection.we_called_remote)
+            .filter(|other| other.address != *address)
+            .count();
+
+        if occupied < max {
+            return Admission::Accept;
+        }
+
+        if !self.preferred_connections.contains(address) {
+            return Admission::Reject;
+        }
+
+        match self.lowest_value_peer(connection.we_called_remote) {
+            Some(victim) => Admission::Evict(victim),
+            None => Admission::Accept,
+        }
+    }
+
+    /// Non-preferred peer authenticated most recently, long living connections are kept
+    fn lowest_value_peer(&self, we_called_remote: bool) -> Option<SocketAddr> {
+        self.authenticated_connections(we_called_remote)
+            .filter(|connection| !self.preferred_connections.contains(&connection.address))
+            .max_by_key(|connection| self.authenticated_at.get(&connection.address))
+            .map(|connection| connection.address)
     }
 
-    /// Accept llist of xdr::PeerAddress and move each of them in known_peer_adresses
-    /// in parseable format
+    fn authenticated_connections(
+        &self,
+        we_called_remote: bool,
+    ) -> impl Iterator<Item = &NodeConnection> {
+        self.node_connections.values().filter(move |connection| {
+            connection.we_called_remote == we_called_remote
+                && self.authenticated_peers.contains(&connection.address)
+        })
+    }
+
+    fn connection_by_address(&self, address: &SocketAddr) -> Option<&NodeConnection> {
+        self.node_connections
+            .values()
+            .find(|connection| connection.address == *address)
+    }
+
+    pub(crate) fn add_preferred_connection(&mut self, address: SocketAddr) {
+        self.preferred_connections.insert(address);
+    }
+
+    /// Preferred peers we are not connected with and whose backoff has passed
+    pub(crate) fn preferred_peers_to_connect(&self) -> Vec<SocketAddr> {
+        let now = Utc::now().naive_utc();
+
+        self.preferred_addresses
+            .iter()
+            .filter(|address| !self.is_connected_to(address))
+            .filter(|address| match database::Peer::get(address) {
+                Ok(mut peers) => peers.pop().map_or(true, |peer| peer.nextattempt <= now),
+                Err(_) => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Either we dialed this address or node listening on it connected to us
+    fn is_connected_to(&self, address: &SocketAddr) -> bool {
+        self.authenticated_peers.contains(address)
+            || self.pending_peers.contains(address)
+            || self
+                .node_connections
+                .values()
+                .any(|connection| connection.listening_address == *address)
+    }
+
+    /// Accept llist of xdr::PeerAddress, move each of them in known_peer_adresses
+    /// and save them to database
     pub(crate) fn add_known_peers(&mut self, peers_addresses: &[xdr::PeerAddress]) {
-        for peer_addres in peers_addresses {
-            if let xdr::PeerAddress {
-                ip: xdr::PeerAddressIp::Ipv4(ref addr),
-                ..
-            } = peer_addres
-            {
-                let peer_format_name = format!("{}:{}", join(addr, "."), peer_addres.port);
-                self.add_known_peer(peer_format_name);
+        for address in parse_peer_addresses(peers_addresses) {
+            if let Err(e) = database::Peer::insert_if_missing(&address) {
+                warn!("[Overlay] unable to save peer {}, cause: {}", address, e);
             }
+            self.add_known_peer(address);
         }
     }
 
     /// Add new peer address to known_peer_adresses list
-    pub(crate) fn add_known_peer(&mut self, peer_address: String) {
+    pub(crate) fn add_known_peer(&mut self, peer_address: SocketAddr) {
         if self.is_new_peer(&peer_address) {
             self.known_peer_adresses.insert(peer_address);
         }
     }
 
     /// Remove single peer address from known_peer_adresses list
-    pub(crate) fn remove_known_peer(&mut self, peer_address: &str) {
+    pub(crate) fn remove_known_peer(&mut self, peer_address: &SocketAddr) {
         self.known_peer_adresses.remove(peer_address);
     }
 
     /// Add single peer address to pending_peers list
-    pub(crate) fn add_pending_peer(&mut self, peer_address: String) {
+    pub(crate) fn add_pending_peer(&mut self, peer_address: SocketAddr) {
         self.pending_peers.insert(peer_address);
     }
 
     /// Remove single peer address from pending_peers list
-    pub(crate) fn remove_pending_peer(&mut self, peer_address: &str) {
+    pub(crate) fn remove_pending_peer(&mut self, peer_address: &SocketAddr) {
         self.pending_peers.remove(peer_address);
     }
 
     /// Add single peer address to authenticated_peers list
-    pub(crate) fn add_authenticated_peer(&mut self, peer_address: String) {
+    pub(crate) fn add_authenticated_peer(&mut self, peer_address: SocketAddr) {
+        self.authenticated_at.insert(peer_address, Instant::now());
         self.authenticated_peers.insert(peer_address);
     }
 
     /// Remove single peer address from authenticated_peers list
-    pub(crate) fn remove_authenticated_peer(&mut self, peer_address: &str) {
+    pub(crate) fn remove_authenticated_peer(&mut self, peer_address: &SocketAddr) {
+        self.authenticated_at.remove(peer_address);
         self.authenticated_peers.remove(peer_address);
     }
 
     /// Add single peer address to failed_peer list
-    pub(crate) fn add_failed_peer(&mut self, peer_address: String) {
+    pub(crate) fn add_failed_peer(&mut self, peer_address: SocketAddr) {
         self.failed_peers.insert(peer_address);
     }
 
     /// Remove single peer address from failed_peer list
-    pub(crate) fn remove_failed_peer(&mut self, peer_address: &str) {
+    pub(crate) fn remove_failed_peer(&mut self, peer_address: &SocketAddr) {
         self.failed_peers.remove(peer_address);
     }
 
-    pub(crate) fn is_new_peer(&self, peer_address: &str) -> bool {
+    pub(crate) fn is_new_peer(&self, peer_address: &SocketAddr) -> bool {
         !self.is_peer_exist(peer_address)
     }
 
-    pub(crate) fn is_peer_exist(&self, peer_address: &str) -> bool {
+    pub(crate) fn is_peer_exist(&self, peer_address: &SocketAddr) -> bool {
         self.known_peer_adresses.contains(peer_address)
             || self.authenticated_peers.contains(peer_address)
             || self.pending_peers.contains(peer_address)
             || self.failed_peers.contains(peer_address)
     }
 
-    /// Move peer to authenticated_peers list
-    pub(crate) fn move_peer_to_authenticated_list(&mut self, peers_addresses: String) {
+    /// Move peer to authenticated_peers list. Success is recorded for the address
+    /// it can be dialed on, see `dialable_address`
+    pub(crate) fn move_peer_to_authenticated_list(&mut self, peers_addresses: SocketAddr) {
+        if let Some(address) = self.dialable_address(&peers_addresses) {
+            if let Err(e) = database::Peer::insert_if_missing(&address)
+                .and_then(|_| database::Peer::record_success(&address))
+            {
+                warn!(
+                    "[Overlay] unable to record success of peer {}, cause: {}",
+                    address, e
+                );
+            }
+        }
         self.remove_known_peer(&peers_addresses);
         self.remove_pending_peer(&peers_addresses);
         self.add_authenticated_peer(peers_addresses);
     }
 
-    pub(crate) fn move_peer_to_pending_list(&mut self, peers_address: String) {
+    pub(crate) fn move_peer_to_pending_list(&mut self, peers_address: SocketAddr) {
         self.remove_known_peer(&peers_address);
         self.remove_authenticated_peer(&peers_address);
         self.remove_failed_peer(&peers_address);
         self.add_pending_peer(peers_address);
     }
 
-    pub(crate) fn move_peer_to_failed_list(&mut self, peers_address: String) {
+    /// Failed peer is retried after backoff, see `peers_ready_to_connect`.
+    /// Only peers we dialed are retried, inbound ones are just removed, so
+    /// their temporary source ports don't get to database and failed list.
+    /// Must be called before `forget_node_connection`
+    pub(crate) fn move_peer_to_failed_list(&mut self, peers_address: SocketAddr) {
+        let is_dialed = self.is_dialed(&peers_address);
         self.remove_known_peer(&peers_address);
         self.remove_authenticated_peer(&peers_address);
         self.remove_pending_peer(&peers_address);
-        self.add_failed_peer(peers_address);
+        if is_dialed {
+            if let Err(e) = database::Peer::record_failure(&peers_address) {
+                warn!(
+                    "[Overlay] unable to record failure of peer {}, cause: {}",
+                    peers_address, e
+                );
+            }
+            self.add_failed_peer(peers_address);
+        }
+    }
+
+    /// We dialed the address: connection is pending or registered as initiated by us
+    fn is_dialed(&self, address: &SocketAddr) -> bool {
+        self.pending_peers.contains(address)
+            || self
+                .connection_by_address(address)
+                .map_or(false, |connection| connection.we_called_remote)
+    }
+
+    /// Address peer accepts connections on: the one we dialed, or the listening
+    /// address from Hello of node which connected to us. None for inbound peer before Hello
+    fn dialable_address(&self, address: &SocketAddr) -> Option<SocketAddr> {
+        if self.is_dialed(address) {
+            return Some(*address);
+        }
+        self.connection_by_address(address)
+            .map(|connection| connection.listening_address)
+    }
+
+    /// Register connection with node which sent us Hello. A second connection
+    /// with already authenticated node is rejected, simultaneous pending ones
+    /// are resolved by `prefer_new_connection`
+    pub(crate) fn register_node_connection(
+        &mut self,
+        local_node_id: &xdr::NodeId,
+        node_id: xdr::NodeId,
+        connection: NodeConnection,
+    ) -> HelloVerdict {
+        let verdict = match self.node_connections.get(&node_id) {
+            None => HelloVerdict::Accept,
+            Some(existing) if existing.address == connection.address => HelloVerdict::Accept,
+            Some(existing) if self.authenticated_peers.contains(&existing.address) => {
+                HelloVerdict::Reject
+            }
+            Some(existing) => {
+                if prefer_new_connection(local_node_id, &node_id, existing, &connection) {
+                    HelloVerdict::ReplaceExisting(existing.address)
+                } else {
+                    HelloVerdict::Reject
+                }
+            }
+        };
+
+        if verdict != HelloVerdict::Reject {
+            self.node_connections.insert(node_id, connection);
+        }
+        verdict
+    }
+
+    pub(crate) fn node_connection(&self, node_id: &xdr::NodeId) -> Option<&NodeConnection> {
+        self.node_connections.get(node_id)
+    }
+
+    /// Connection with this address is closed, its node could connect again
+    pub(crate) fn forget_node_connection(&mut self, address: &SocketAddr) {
+        self.node_connections
+            .retain(|_, connection| connection.address != *address);
+        self.preferred_connections.remove(address);
+    }
+
+    /// Raise misbehavior score of node connected on the address. Returns the
+    /// node and its new score, None if the peer hasn't sent Hello yet
+    pub(crate) fn record_misbehavior(
+        &mut self,
+        address: &SocketAddr,
+        misbehavior: Misbehavior,
+        now: Instant,
+    ) -> Option<(xdr::NodeId, u32)> {
+        let node_id = *self
+            .node_connections
+            .iter()
+            .find(|(_, connection)| connection.address == *address)?
+            .0;
+
+        let score = self
+            .misbehavior_scores
+            .entry(node_id)
+            .or_insert_with(|| MisbehaviorScore::new(now))
+            .add(misbehavior, now);
+        Some((node_id, score))
+    }
+
+    pub(crate) fn target_outbound_peers(&self) -> usize {
+        *CONFIG.target_outbound_peers()
     }
 
-    /// Max limit number of connections we can have between peers
-    pub(crate) fn max_limit_of_authenticated_peers(&self) -> usize {
-        *CONFIG.max_peers() as usize
+    pub(crate) fn max_outbound_peers(&self) -> usize {
+        *CONFIG.max_outbound_peers()
     }
 
-    pub(crate) fn min_limit_of_authenticated_peers(&self) -> usize {
-        *CONFIG.min_peers() as usize
+    pub(crate) fn max_inbound_peers(&self) -> usize {
+        *CONFIG.max_inbound_peers()
     }
 
     pub(crate) fn populate_known_peers_from_db(&mut self) {
         let peers = database::Peer::all().expect("[Overlay Manager] Can`t recreate initial peer adresses. Check your database connection");
-        for peer in peers {
-            self.add_known_peer(peer.address().to_owned());
+        for address in peers.iter().filter_map(database::Peer::address) {
+            self.add_known_peer(address);
         }
     }
 
-    pub(crate) fn reached_max_of_authenticated_peers(&self) -> bool {
-        self.authenticated_peers.len() >= self.max_limit_of_authenticated_peers()
+    /// Save nodes we are connected to, so they are dialed first after restart.
+    /// Returns number of saved peers
+    pub(crate) fn save_peers(&self) -> usize {
+        let mut saved = 0;
+        for connection in self
+            .node_connections
+            .values()
+            .filter(|connection| self.authenticated_peers.contains(&connection.address))
+        {
+            let address = &connection.listening_address;
+            match database::Peer::insert_if_missing(address)
+                .and_then(|_| database::Peer::record_success(address))
+            {
+                Ok(_) => saved += 1,
+                Err(e) => warn!("[Overlay] unable to save peer {}, cause: {}", address, e),
+            }
+        }
+        saved
     }
 
-    pub(crate) fn reached_min_of_authenticated_peers(&self) -> bool {
-        self.authenticated_peers.len() >= self.min_limit_of_authenticated_peers()
+    /// Number of connections to dial to reach outbound target, pending ones are counted too
+    pub(crate) fn outbound_peers_to_target(&self) -> usize {
+        let outbound_peers =
+            self.authenticated_connections(true).count() + self.pending_peers.len();
+        self.target_outbound_peers().saturating_sub(outbound_peers)
+    }
+
+    /// Peers from database whose backoff has passed and we are not connected to yet
+    pub(crate) fn peers_ready_to_connect(&self, limit: usize) -> Vec<SocketAddr> {
+        let busy_peers = self.authenticated_peers.len() + self.pending_peers.len();
+        let peers = match database::Peer::ready_to_connect((limit + busy_peers) as i64) {
+            Ok(peers) => peers,
+            Err(_) => return vec![],
+        };
+
+        peers
+            .iter()
+            .filter_map(database::Peer::address)
+            .filter(|address| {
+                !self.authenticated_peers.contains(address) && !self.pending_peers.contains(address)
+            })
+            .take(limit)
+            .collect()
     }
 
-    pub(crate) fn peers_to_authenticated_min_limit(&self) -> i32 {
-        self.min_limit_of_authenticated_peers() as i32 - self.authenticated_peers.len() as i32
+    /// Randomized set of public addresses from database we share in reply to GET_PEERS.
+    /// Addresses with the requester IP are excluded
+    pub(crate) fn peers_to_share(&self, requester_address: &SocketAddr) -> Vec<xdr::PeerAddress> {
+        let requester_ip = requester_address.ip();
+        let peers = match database::Peer::random_sample(
+            MAX_FAILURES_TO_SEND,
+            &requester_ip,
+            PEERS_SAMPLE_SIZE,
+        ) {
+            Ok(peers) => peers,
+            Err(_) => return vec![],
+        };
+
+        select_peers_to_share(peers, Some(requester_ip))
     }
 
-    pub(crate) fn known_peer_adresses(&self) -> &HashSet<String> {
+    pub(crate) fn known_peer_adresses(&self) -> &HashSet<SocketAddr> {
         &self.known_peer_adresses
     }
 
-    pub(crate) fn authenticated_peers(&self) -> &HashSet<String> {
+    pub(crate) fn authenticated_peers(&self) -> &HashSet<SocketAddr> {
         &self.authenticated_peers
     }
 
-    pub(crate) fn pending_peers(&self) -> &HashSet<String> {
+    pub(crate) fn pending_peers(&self) -> &HashSet<SocketAddr> {
         &self.pending_peers
     }
 
-    pub(crate) fn failed_peers(&self) -> &HashSet<String> {
+    pub(crate) fn failed_peers(&self) -> &HashSet<SocketAddr> {
         &self.failed_peers
     }
 }
 
-// stub for current ledger value
-fn unix_time() -> u32 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32
+/// When two nodes call each other at the same time, both of them keep the
+/// connection initiated by the node with the lower ID, so they drop the same one.
+/// This deliberately differs from stellar-core, which rejects the newer connection:
+/// each side may see the two Hellos in a different order, so both could be dropped
+fn prefer_new_connection(
+    local_node_id: &xdr::NodeId,
+    remote_node_id: &xdr::NodeId,
+    existing: &NodeConnection,
+    new: &NodeConnection,
+) -> bool {
+    if existing.we_called_remote == new.we_called_remote {
+        return false;
+    }
+
+    let xdr::PublicKey::Ed25519(xdr::Uint256(local_key)) = local_node_id;
+    let xdr::PublicKey::Ed25519(xdr::Uint256(remote_key)) = remote_node_id;
+    new.we_called_remote == (local_key < remote_key)
+}
+
+/// Addresses with port out of range are skipped
+fn parse_peer_addresses(peers_addresses: &[xdr::PeerAddress]) -> Vec<SocketAddr> {
+    peers_addresses
+        .iter()
+        .filter_map(|peer_address| SocketAddr::try_from(peer_address).ok())
+        .filter(|address| address.port() != 0)
+        .collect()
+}
+
+fn select_peers_to_share(
+    peers: Vec<database::Peer>,
+    requester_ip: Option<IpAddr>,
+) -> Vec<xdr::PeerAddress> {
+    peers
+        .iter()
+        .filter(|peer| peer.numfailures <= MAX_FAILURES_TO_SEND)
+        .filter_map(|peer| {
+            let address = peer.address()?;
+            if !is_public_address(&address.ip()) || Some(address.ip()) == requester_ip {
+                return None;
+            }
+
+            Some(xdr::PeerAddress {
+                num_failures: peer.numfailures as xdr::Uint32,
+                ..xdr::PeerAddress::from(address)
+            })
+        })
+        .take(MAX_PEERS_TO_SEND)
+        .collect()
+}
+
+/// Private, loopback and other special addresses are useless for remote peers
+fn is_public_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            // "this network" 0.0.0.0/8 and shared address space 100.64.0.0/10
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || octets[0] == 0
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            // unique local fc00::/7 and link local fe80::/10
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first_segment & 0xfe00 == 0xfc00
+                || first_segment & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factories::peer::build_db_peer;
+
+    fn node_id(byte: u8) -> xdr::NodeId {
+        xdr::PublicKey::Ed25519(xdr::Uint256([byte; 32]))
+    }
+
+    fn connection(address: &str, we_called_remote: bool) -> NodeConnection {
+        NodeConnection {
+            address: address.parse().unwrap(),
+            listening_address: "54.1.2.3:11625".parse().unwrap(),
+            we_called_remote,
+        }
+    }
+
+    mod register_node_connection {
+        use super::*;
+
+        #[test]
+        fn rejects_second_connection_with_authenticated_node() {
+            let mut manager = OverlayManager::new();
+            let first = connection("54.1.2.3:11625", true);
+            manager.add_authenticated_peer(first.address);
+
+            assert_eq!(
+                manager.register_node_connection(&node_id(1), node_id(2), first),
+                HelloVerdict::Accept
+            );
+            assert_eq!(
+                manager.register_node_connection(
+                    &node_id(1),
+                    node_id(2),
+                    connection("54.1.2.3:40000", false)
+                ),
+                HelloVerdict::Reject
+            );
+        }
+
+        #[test]
+        fn lower_node_id_keeps_connection_it_initiated() {
+            let outgoing = connection("54.1.2.3:11625", true);
+            let incoming = connection("54.1.2.3:40000", false);
+
+            let mut manager = OverlayManager::new();
+            manager.register_node_connection(&node_id(1), node_id(2), incoming);
+            assert_eq!(
+                manager.register_node_connection(&node_id(1), node_id(2), outgoing),
+                HelloVerdict::ReplaceExisting(incoming.address)
+            );
+
+            let mut manager = OverlayManager::new();
+            manager.register_node_connection(&node_id(1), node_id(2), outgoing);
+            assert_eq!(
+                manager.register_node_connection(&node_id(1), node_id(2), incoming),
+                HelloVerdict::Reject
+            );
+        }
+
+        #[test]
+        fn higher_node_id_keeps_connection_initiated_by_remote() {
+            let outgoing = connection("54.1.2.3:11625", true);
+            let incoming = connection("54.1.2.3:40000", false);
+
+            let mut manager = OverlayManager::new();
+            manager.register_node_connection(&node_id(2), node_id(1), outgoing);
+            assert_eq!(
+                manager.register_node_connection(&node_id(2), node_id(1), incoming),
+                HelloVerdict::ReplaceExisting(outgoing.address)
+            );
+        }
+
+        #[test]
+        fn accepts_node_again_after_connection_is_forgotten() {
+            let mut manager = OverlayManager::new();
+            let first = connection("54.1.2.3:11625", true);
+            manager.add_authenticated_peer(first.address);
+            manager.register_node_connection(&node_id(1), node_id(2), first);
+
+            manager.remove_authenticated_peer(&first.address);
+            manager.forget_node_connection(&first.address);
+            assert_eq!(
+                manager.register_node_connection(
+                    &node_id(1),
+                    node_id(2),
+                    connection("54.1.2.3:40000", false)
+                ),
+                HelloVerdict::Accept
+            );
+        }
+    }
+
+    mod check_admission {
+        use super::*;
+
+        #[test]
+        fn preferred_by_node_id_or_listening_address() {
+            let mut manager = OverlayManager::new();
+            manager.add_preferred_node_id(node_id(2));
+            manager.add_preferred_address("54.1.2.3:11625".parse().unwrap());
+
+            let incoming = connection("54.1.2.3:40000", false);
+            assert!(manager.is_preferred(&node_id(2), &incoming));
+            assert!(manager.is_preferred(&node_id(3), &incoming));
+
+            let other = NodeConnection {
+                listening_address: "54.1.2.4:11625".parse().unwrap(),
+                ..incoming
+            };
+            assert!(!manager.is_preferred(&node_id(3), &other));
+        }
+
+        #[test]
+        fn preferred_only_mode_rejects_other_peers() {
+            let mut manager = OverlayManager::new();
+            manager.add_preferred_node_id(node_id(2));
+            let incoming = connection("54.1.2.3:40000", false);
+
+            assert_eq!(
+                manager.check_admission(&node_id(2), &incoming, true),
+                Ok(())
+            );
+            assert_eq!(
+                manager.check_admission(&node_id(3), &incoming, true),
+                Err(PeerError::NotPreferred)
+            );
+            assert_eq!(
+                manager.check_admission(&node_id(3), &incoming, false),
+                Ok(())
+            );
+        }
+    }
+
+    mod admit_authenticated {
+        use super::*;
+
+        fn authenticate(manager: &mut OverlayManager, node: u8, connection: NodeConnection) {
+            manager.register_node_connection(&node_id(0), node_id(node), connection);
+            manager.add_authenticated_peer(connection.address);
+        }
+
+        fn fill_inbound_slots(manager: &mut OverlayManager) {
+            for i in 0..manager.max_inbound_peers() {
+                let address = format!("54.1.2.{}:40000", i);
+                authenticate(manager, i as u8 + 1, connection(&address, false));
+            }
+        }
+
+        #[test]
+        fn rejects_peer_over_inbound_limit() {
+            let mut manager = OverlayManager::new();
+            fill_inbound_slots(&mut manager);
+
+            let inbound = connection("54.1.3.1:40000", false);
+            manager.register_node_connection(&node_id(0), node_id(200), inbound);
+            assert_eq!(
+                manager.admit_authenticated(&inbound.address),
+                Admission::Reject
+            );
+
+            let outbound = connection("54.1.3.2:11625", true);
+            manager.register_node_connection(&node_id(0), node_id(201), outbound);
+            assert_eq!(
+                manager.admit_authenticated(&outbound.address),
+                Admission::Accept
+            );
+        }
+
+        #[test]
+        fn evicts_newest_peer_for_preferred_one() {
+            let mut manager = OverlayManager::new();
+            fill_inbound_slots(&mut manager);
+
+            let newest: SocketAddr = "54.1.2.0:40000".parse().unwrap();
+            let later = Instant::now() + std::time::Duration::from_secs(1);
+            manager.authenticated_at.insert(newest, later);
+
+            let preferred = connection("54.1.3.1:40000", false);
+            manager.register_node_connection(&node_id(0), node_id(200), preferred);
+            manager.add_preferred_connection(preferred.address);
+            assert_eq!(
+                manager.admit_authenticated(&preferred.address),
+                Admission::Evict(newest)
+            );
+        }
+
+        #[test]
+        fn preferred_peers_are_never_evicted() {
+            let mut manager = OverlayManager::new();
+            fill_inbound_slots(&mut manager);
+            for i in 0..manager.max_inbound_peers() {
+                manager.add_preferred_connection(format!("54.1.2.{}:40000", i).parse().unwrap());
+            }
+
+            let preferred = connection("54.1.3.1:40000", false);
+            manager.register_node_connection(&node_id(0), node_id(200), preferred);
+            manager.add_preferred_connection(preferred.address);
+            assert_eq!(
+                manager.admit_authenticated(&preferred.address),
+                Admission::Accept
+            );
+        }
+    }
+
+    mod dialable_address {
+        use super::*;
+
+        #[test]
+        fn uses_listening_address_of_inbound_peer() {
+            let mut manager = OverlayManager::new();
+            let inbound = connection("54.1.2.3:40000", false);
+            assert_eq!(manager.dialable_address(&inbound.address), None);
+
+            manager.register_node_connection(&node_id(1), node_id(2), inbound);
+            assert_eq!(
+                manager.dialable_address(&inbound.address),
+                Some(inbound.listening_address)
+            );
+
+            let outbound: SocketAddr = "54.4.5.6:11625".parse().unwrap();
+            manager.add_pending_peer(outbound);
+            assert_eq!(manager.dialable_address(&outbound), Some(outbound));
+        }
+
+        #[test]
+        fn failed_inbound_peer_is_not_retried() {
+            let mut manager = OverlayManager::new();
+            let inbound = connection("54.1.2.3:40000", false);
+            manager.register_node_connection(&node_id(1), node_id(2), inbound);
+            manager.add_authenticated_peer(inbound.address);
+
+            manager.move_peer_to_failed_list(inbound.address);
+            assert!(!manager.is_peer_exist(&inbound.address));
+            assert!(!manager.failed_peers.contains(&inbound.listening_address));
+        }
+    }
+
+    mod record_misbehavior {
+        use super::*;
+
+        #[test]
+        fn score_is_kept_per_node_across_connections() {
+            let now = Instant::now();
+            let mut manager = OverlayManager::new();
+            let first = connection("54.1.2.3:11625", true);
+            manager.register_node_connection(&node_id(1), node_id(2), first);
+
+            assert_eq!(
+                manager.record_misbehavior(&first.address, Misbehavior::OversizedData, now),
+                Some((node_id(2), 25))
+            );
+
+            manager.forget_node_connection(&first.address);
+            let second = connection("54.1.2.3:40000", false);
+            manager.register_node_connection(&node_id(1), node_id(2), second);
+
+            assert_eq!(
+                manager.record_misbehavior(&second.address, Misbehavior::OversizedData, now),
+                Some((node_id(2), 50))
+            );
+        }
+
+        #[test]
+        fn ignores_peer_without_hello() {
+            let mut manager = OverlayManager::new();
+
+            assert_eq!(
+                manager.record_misbehavior(
+                    &"54.1.2.3:11625".parse().unwrap(),
+                    Misbehavior::MalformedMessage,
+                    Instant::now()
+                ),
+                None
+            );
+        }
+    }
+
+    mod parse_peer_addresses {
+        use super::*;
+
+        #[test]
+        fn ipv4_and_ipv6_addresses() {
+            let addresses = parse_peer_addresses(&[
+                xdr::PeerAddress {
+                    ip: xdr::PeerAddressIp::Ipv4([54, 1, 2, 3]),
+                    port: 11625,
+                    num_failures: 0,
+                },
+                xdr::PeerAddress {
+                    ip: xdr::PeerAddressIp::Ipv6([
+                        0x2a, 1, 0x4, 0xf8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+                    ]),
+                    port: 11625,
+                    num_failures: 0,
+                },
+            ]);
+
+            let expected: Vec<SocketAddr> = vec![
+                "54.1.2.3:11625".parse().unwrap(),
+                "[2a01:4f8::1]:11625".parse().unwrap(),
+            ];
+            assert_eq!(addresses, expected);
+        }
+
+        #[test]
+        fn skips_invalid_ports() {
+            let addresses = parse_peer_addresses(&[
+                xdr::PeerAddress {
+                    ip: xdr::PeerAddressIp::Ipv4([54, 1, 2, 3]),
+                    port: 0,
+                    num_failures: 0,
+                },
+                xdr::PeerAddress {
+                    ip: xdr::PeerAddressIp::Ipv4([54, 1, 2, 3]),
+                    port: 70000,
+                    num_failures: 0,
+                },
+            ]);
+
+            assert!(addresses.is_empty());
+        }
+    }
+
+    mod select_peers_to_share {
+        use super::*;
+
+        #[test]
+        fn skips_private_and_loopback_addresses() {
+            let peers = vec![
+                build_db_peer("10.0.0.1", 11625, 0),
+                build_db_peer("127.0.0.1", 11625, 0),
+                build_db_peer("::1", 11625, 0),
+                build_db_peer("fe80::1", 11625, 0),
+                build_db_peer("54.1.2.3", 11625, 0),
+            ];
+
+            let shared = select_peers_to_share(peers, None);
+            assert_eq!(shared.len(), 1);
+            assert_eq!(shared[0].ip, xdr::PeerAddressIp::Ipv4([54, 1, 2, 3]));
+            assert_eq!(shared[0].port, 11625);
+        }
+
+        #[test]
+        fn shares_ipv6_addresses() {
+            let peers = vec![build_db_peer("2a01:4f8::1", 11625, 0)];
+
+            let shared = select_peers_to_share(peers, None);
+            assert_eq!(shared.len(), 1);
+            assert_eq!(
+                SocketAddr::try_from(&shared[0]).unwrap(),
+                "[2a01:4f8::1]:11625".parse().unwrap()
+            );
+        }
+
+        #[test]
+        fn skips_requester_and_failed_peers() {
+            let peers = vec![
+                build_db_peer("54.1.2.3", 11625, 0),
+                build_db_peer("54.1.2.4", 11625, MAX_FAILURES_TO_SEND + 1),
+            ];
+
+            let requester_ip = "54.1.2.3".parse().ok();
+            assert!(select_peers_to_share(peers, requester_ip).is_empty());
+        }
+
+        #[test]
+        fn limits_number_of_addresses() {
+            let peers = (0..MAX_PEERS_TO_SEND + 10)
+                .map(|i| build_db_peer(&format!("54.1.{}.{}", i / 256, i % 256), 11625, 0))
+                .collect();
+
+            assert_eq!(select_peers_to_share(peers, None).len(), MAX_PEERS_TO_SEND);
+        }
+    }
+
+    mod is_public_address {
+        use super::*;
+
+        fn is_public(ip: &str) -> bool {
+            is_public_address(&ip.parse().unwrap())
+        }
+
+        #[test]
+        fn special_ipv4_ranges() {
+            for ip in &[
+                "0.0.0.0",
+                "0.1.2.3",
+                "100.64.0.1",
+                "100.127.255.254",
+                "224.0.0.1",
+                "239.255.255.250",
+                "255.255.255.255",
+                "169.254.1.1",
+                "192.168.1.1",
+            ] {
+                assert!(!is_public(ip), "{} must not be public", ip);
+            }
+
+            assert!(is_public("100.63.255.255"));
+            assert!(is_public("100.128.0.1"));
+            assert!(is_public("54.1.2.3"));
+        }
+
+        #[test]
+        fn special_ipv6_ranges() {
+            for ip in &["::", "::1", "fc00::1", "fd12:3456::1", "fe80::1", "ff02::1"] {
+                assert!(!is_public(ip), "{} must not be public", ip);
+            }
+
+            assert!(is_public("2a01:4f8::1"));
+        }
+    }
 }
@@ -1,20 +1,38 @@
 use super::{
-    debug, error, info, serde_xdr, sha2::Digest, xdr, BigEndian, LocalNode, Rng, WriteBytesExt,
-    CONFIG, LOCAL_NODE,
+    error,
+    framing::encode_record,
+    info,
+    metrics::{SentMessage, Traffic},
+    peer_activity::PeerActivity,
+    serde_xdr, xdr, Connection, MessagePriority, Misbehavior, PeerAuth, PeerTimeout, PeerTimeouts,
+    Rng, CONFIG, LOCAL_NODE, METRICS, PEER_AUTH,
 };
 use hkdf::Hkdf;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
+use std::convert::TryFrom;
 use std::fmt;
-use std::io::{Cursor, Read, Write};
-use std::net::TcpStream;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use x25519_dalek::{PublicKey, StaticSecret};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use x25519_dalek::PublicKey;
+
+/// Overlay protocol versions range we support
+const OVERLAY_PROTOCOL_VERSION: xdr::Uint32 = 9000;
+const OVERLAY_PROTOCOL_MIN_VERSION: xdr::Uint32 = 0;
 
 #[derive(Debug)]
 pub enum MessageReceiveError {
     TCP(std::io::Error),
     Parse(serde_xdr::CompatDeserializationError),
+    /// Message MAC doesn't match the one computed with `received_mac_key`
+    BadMac,
+    /// Message sequence doesn't match the expected receive sequence
+    OutOfOrderSequence {
+        expected: xdr::Uint64,
+        received: xdr::Uint64,
+    },
 }
 
 impl fmt::Display for MessageReceiveError {
@@ -22,6 +40,24 @@ impl fmt::Display for MessageReceiveError {
         match self {
             Self::TCP(e) => e.fmt(f),
             Self::Parse(e) => e.fmt(f),
+            Self::BadMac => write!(f, "unexpected MAC"),
+            Self::OutOfOrderSequence { expected, received } => write!(
+                f,
+                "unexpected auth sequence: expected {}, received {}",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl MessageReceiveError {
+    /// Misbehavior the error is counted as. Connection failures aren't peer fault,
+    /// and MAC or sequence mismatch only proves the connection is broken, so
+    /// such peers are dropped without raising the score of the node
+    pub fn misbehavior(&self) -> Option<Misbehavior> {
+        match self {
+            Self::Parse(_) => Some(Misbehavior::MalformedMessage),
+            Self::TCP(_) | Self::BadMac | Self::OutOfOrderSequence { .. } => None,
         }
     }
 }
@@ -31,6 +67,7 @@ impl std::error::Error for MessageReceiveError {
         match self {
             Self::TCP(e) => Some(e),
             Self::Parse(e) => Some(e),
+            Self::BadMac | Self::OutOfOrderSequence { .. } => None,
         }
     }
 }
@@ -47,18 +84,25 @@ impl From<std::io::Error> for MessageReceiveError {
     }
 }
 
+/// Stage of handshake with remote peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HandshakeState {
+    AwaitingHello,
+    /// Hello is valid, overlay manager decides whether we keep connection with this node
+    AwaitingVerdict,
+    AwaitingAuth,
+    Authenticated,
+}
+
 pub struct Peer {
-    /// Socket for write/read with connected peer
-    stream: std::net::TcpStream,
+    /// Connection with peer served by reactor
+    connection: Connection,
+    /// We initiated connection to remote peer
+    we_called_remote: bool,
     /// Current message sequence position.
     send_message_sequence: xdr::Uint64,
-    /// Signed certificate for a hour
-    cached_auth_cert: xdr::AuthCert,
-    // Authentication system keys. Our ECDH secret and public keys are randomized on startup
-    // More info in: stellar-core/src/overlay/PeerAuth.h file
-    /// Public authentication system key
-    auth_public_key: PublicKey,
-    auth_secret_key: StaticSecret,
+    /// Expected sequence of the next authenticated message from peer
+    received_message_sequence: xdr::Uint64,
     /// Shared key with peer
     auth_shared_key: [u8; 32],
     /// Received MAC key from peer
@@ -70,73 +114,145 @@ pub struct Peer {
     /// Signed Hello message
     hello: xdr::Hello,
     /// Peer remote address
-    address: String,
+    address: SocketAddr,
     /// Received hello message from peer
     peer_info: xdr::Hello,
-    /// authenticated peer flag
-    is_authenticated: bool,
+    handshake_state: HandshakeState,
+    /// When connection made progress last time
+    activity: PeerActivity,
+    /// Metrics of messages sent and received over the connection
+    traffic: Arc<Traffic>,
 }
 
 pub trait PeerInterface {
-    fn start_authentication(&mut self, we_called_remote: bool) -> ();
-    fn handle_hello(&mut self, received_hello: xdr::StellarMessage, we_called_remote: bool) -> ();
+    fn start_authentication(&mut self);
+    fn handle_handshake_message(&mut self, message: xdr::StellarMessage) -> Result<(), PeerError>;
+    fn continue_handshake(&mut self);
+    fn reject_handshake(&mut self, error: PeerError);
+    fn handle_hello(
+        &mut self,
+        received_hello: xdr::StellarMessage,
+        we_called_remote: bool,
+    ) -> Result<(), PeerError>;
     fn set_remote_keys(
         &mut self,
         remote_pub_key: xdr::Curve25519Public,
         received_nonce: xdr::Uint256,
         we_called_remote: bool,
     ) -> ();
-    fn new_auth_cert(node_info: &LocalNode, auth_public_key: &PublicKey) -> xdr::AuthCert;
     fn send_message(&mut self, message: xdr::StellarMessage);
-    fn send_header(&mut self, message_length: u32);
-    fn receive_message(&mut self) -> Result<xdr::AuthenticatedMessage, MessageReceiveError>;
-    fn receive_header(&mut self) -> usize;
+    fn receive_message(
+        &mut self,
+        record: &[u8],
+    ) -> Result<xdr::AuthenticatedMessage, MessageReceiveError>;
     fn increment_message_sequence(&mut self);
     fn set_authenticated(&mut self);
     fn is_authenticated(&self) -> bool;
-    fn address(&self) -> &String;
+    fn address(&self) -> &SocketAddr;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PeerError {
     AuthFail,
     ConnectionFail,
     InvalidPeerAddress,
+    /// Received message doesn't match current handshake stage
+    UnexpectedMessage,
+    /// Remote Hello has network_id different from ours
+    WrongNetwork,
+    /// Remote overlay versions range doesn't intersect with ours
+    IncompatibleOverlayVersion,
+    /// Remote AuthCert expiration is in the past
+    ExpiredAuthCert,
+    /// Remote AuthCert isn't signed by remote node key
+    InvalidAuthCertSignature,
+    /// Remote Hello has our own node ID
+    ConnectingToSelf,
+    /// We already have connection with remote node ID
+    DuplicateConnection,
+    /// Only preferred peers are accepted
+    NotPreferred,
+    /// We have no room for one more non-preferred peer
+    TooManyPeers,
+    /// Connection is dropped to make room for preferred peer
+    Evicted,
+    /// Remote node ID is banned by operator
+    Banned,
+    /// Peer misbehavior score passed the threshold
+    Misbehaving,
+    ShuttingDown,
+}
+
+impl PeerError {
+    /// Error code we send to remote peer before dropping connection
+    pub fn error_code(self) -> xdr::ErrorCode {
+        match self {
+            PeerError::WrongNetwork
+            | PeerError::IncompatibleOverlayVersion
+            | PeerError::ConnectingToSelf
+            | PeerError::DuplicateConnection
+            | PeerError::Banned => xdr::ErrorCode::ErrConf,
+            PeerError::ExpiredAuthCert
+            | PeerError::InvalidAuthCertSignature
+            | PeerError::AuthFail => xdr::ErrorCode::ErrAuth,
+            PeerError::UnexpectedMessage | PeerError::Misbehaving => xdr::ErrorCode::ErrData,
+            PeerError::NotPreferred | PeerError::TooManyPeers | PeerError::Evicted => {
+                xdr::ErrorCode::ErrLoad
+            }
+            PeerError::ConnectionFail | PeerError::InvalidPeerAddress | PeerError::ShuttingDown => {
+                xdr::ErrorCode::ErrMisc
+            }
+        }
+    }
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            PeerError::AuthFail => "authentication failed",
+            PeerError::ConnectionFail => "connection failed",
+            PeerError::InvalidPeerAddress => "invalid peer address",
+            PeerError::UnexpectedMessage => "unexpected message",
+            PeerError::WrongNetwork => "wrong network passphrase",
+            PeerError::IncompatibleOverlayVersion => "wrong protocol version",
+            PeerError::ExpiredAuthCert => "expired auth cert",
+            PeerError::InvalidAuthCertSignature => "bad auth cert",
+            PeerError::ConnectingToSelf => "connecting to self",
+            PeerError::DuplicateConnection => "connecting already-connected peer",
+            PeerError::Banned => "node is banned",
+            PeerError::NotPreferred => "peer rejected: preferred peers only",
+            PeerError::TooManyPeers => "peer rejected: too many peers",
+            PeerError::Evicted => "preferred peer selected instead",
+            PeerError::Misbehaving => "peer misbehaves",
+            PeerError::ShuttingDown => "shutting down",
+        };
+        write!(f, "{}", description)
+    }
 }
 
 impl Peer {
     /// Return peer instance with connection
-    pub(crate) fn new(stream: std::net::TcpStream, address: String) -> Peer {
+    pub(crate) fn new(connection: Connection, address: SocketAddr, we_called_remote: bool) -> Peer {
         let mut rng = rand::thread_rng();
         let nonce: [u8; 32] = rng.gen();
 
-        let auth_secret_key = StaticSecret::new(&mut rng);
-        let auth_public_key = PublicKey::from(&auth_secret_key);
-
-        let mut public_key: [u8; 32] = Default::default();
-        public_key.copy_from_slice(&LOCAL_NODE.key_pair.public.to_bytes());
-        let peer_id = xdr::PublicKey::Ed25519(xdr::Uint256(public_key));
-
-        let auth_cert = Peer::new_auth_cert(&LOCAL_NODE, &auth_public_key);
-
         let hello = xdr::Hello {
             ledger_version: 9000 as xdr::Uint32,
-            overlay_version: 9000 as xdr::Uint32,
-            overlay_min_version: 0 as xdr::Uint32,
+            overlay_version: OVERLAY_PROTOCOL_VERSION,
+            overlay_min_version: OVERLAY_PROTOCOL_MIN_VERSION,
             network_id: LOCAL_NODE.network_id().to_owned(),
             version_str: String::from("stellar-core-rust[alpha-0.0]"),
             listening_port: *CONFIG.local_node().port() as i32,
-            peer_id,
-            cert: auth_cert.clone(),
+            peer_id: LOCAL_NODE.node_id(),
+            cert: PEER_AUTH.auth_cert(&LOCAL_NODE),
             nonce: xdr::Uint256(nonce),
         };
 
         Peer {
-            stream,
+            connection,
+            we_called_remote,
             send_message_sequence: 0 as xdr::Uint64,
-            cached_auth_cert: auth_cert,
-            auth_secret_key,
-            auth_public_key,
+            received_message_sequence: 0 as xdr::Uint64,
             auth_shared_key: Default::default(),
             received_mac_key: Default::default(),
             sended_mac_key: Default::default(),
@@ -144,34 +260,161 @@ impl Peer {
             hello,
             address,
             peer_info: Default::default(),
-            is_authenticated: false,
+            handshake_state: HandshakeState::AwaitingHello,
+            activity: PeerActivity::new(Instant::now()),
+            traffic: METRICS.peer_traffic(&address),
         }
     }
 
-    /// Accept peer_address in parseable format and trying to start_authenticate new connection
-    pub(crate) fn connect_to(peer_address: String) -> Result<Peer, PeerError> {
-        let address = match peer_address.parse() {
-            Ok(addr) => addr,
-            Err(_) => return Err(PeerError::InvalidPeerAddress),
-        };
+    /// Close connection after queued messages are written
+    pub(crate) fn close(&self) {
+        self.connection.close();
+    }
 
-        match TcpStream::connect_timeout(&address, Duration::new(5, 0)) {
-            Ok(stream) => {
-                debug!("Established peer connection with: {}", address);
-                Ok(Peer::new(stream, peer_address))
-            }
-            Err(e) => {
-                debug!("Failed to connect: {}, cause {}", address, e);
-                Err(PeerError::ConnectionFail)
-            }
+    /// Close connection at once, queued messages are dropped
+    pub(crate) fn abort(&self) {
+        self.connection.abort();
+    }
+
+    /// Reactor wrote all queued messages to socket
+    pub(crate) fn record_drained(&mut self) {
+        self.activity.record_drained(Instant::now());
+    }
+
+    pub(crate) fn check_timeouts(&self, timeouts: &PeerTimeouts) -> Option<PeerTimeout> {
+        self.activity
+            .check_timeouts(Instant::now(), self.is_authenticated(), timeouts)
+    }
+
+    /// Write something to quiet authenticated peer, so it doesn't drop us as idle.
+    /// DONT_HAVE for item nobody asked is ignored by remote peer
+    pub(crate) fn send_keepalive_if_needed(&mut self, timeouts: &PeerTimeouts) {
+        if !self.is_authenticated() || !self.activity.needs_keepalive(Instant::now(), timeouts) {
+            return;
+        }
+
+        self.send_message(xdr::StellarMessage::DontHave(xdr::DontHave {
+            type_: xdr::MessageType::TxSet,
+            req_hash: xdr::Uint256::default(),
+        }));
+    }
+
+    /// Node ID from remote Hello
+    pub(crate) fn remote_node_id(&self) -> &xdr::NodeId {
+        &self.peer_info.peer_id
+    }
+
+    pub(crate) fn we_called_remote(&self) -> bool {
+        self.we_called_remote
+    }
+
+    /// Address remote peer accepts connections on. Incoming connection comes
+    /// from ephemeral port, so listening port from Hello is used instead
+    pub(crate) fn remote_listening_address(&self) -> SocketAddr {
+        if self.we_called_remote {
+            return self.address;
+        }
+
+        match listening_port(&self.peer_info) {
+            Some(port) => SocketAddr::new(self.address.ip(), port),
+            None => self.address,
+        }
+    }
+
+    /// Remote Hello is validated and waits for overlay manager verdict
+    pub(crate) fn is_awaiting_verdict(&self) -> bool {
+        self.handshake_state == HandshakeState::AwaitingVerdict
+    }
+
+    /// Validate remote Hello: node ID, network, overlay versions and AuthCert
+    fn validate_hello(&self, hello: &xdr::Hello) -> Result<(), PeerError> {
+        if hello.peer_id == self.hello.peer_id {
+            return Err(PeerError::ConnectingToSelf);
+        }
+
+        if hello.network_id != *LOCAL_NODE.network_id() {
+            return Err(PeerError::WrongNetwork);
         }
+
+        if hello.overlay_min_version > OVERLAY_PROTOCOL_VERSION
+            || hello.overlay_version < OVERLAY_PROTOCOL_MIN_VERSION
+        {
+            return Err(PeerError::IncompatibleOverlayVersion);
+        }
+
+        if listening_port(hello).is_none() {
+            return Err(PeerError::InvalidPeerAddress);
+        }
+
+        PeerAuth::verify_remote_auth_cert(&LOCAL_NODE, &hello.peer_id, &hello.cert)
     }
 
-    pub(crate) fn peer_addr(&self) -> String {
-        self.stream.peer_addr().unwrap().ip().to_string()
+    /// Notify remote peer why we are dropping connection
+    pub(crate) fn send_error_message(&mut self, error: PeerError) {
+        self.send_message(xdr::StellarMessage::Error(xdr::Error {
+            code: error.error_code(),
+            msg: error.to_string(),
+        }));
+    }
+
+    /// Check MAC and sequence of received message the same way remote peer signed it.
+    /// HELLO and ERROR messages are sent without MAC, so they are skipped
+    fn authenticate_message(
+        &mut self,
+        message: &xdr::AuthenticatedMessage,
+    ) -> Result<(), MessageReceiveError> {
+        let xdr::AuthenticatedMessage::V0(am0) = message;
+
+        if !is_mac_required(&am0.message) {
+            return Ok(());
+        }
+
+        if am0.sequence != self.received_message_sequence {
+            return Err(MessageReceiveError::OutOfOrderSequence {
+                expected: self.received_message_sequence,
+                received: am0.sequence,
+            });
+        }
+
+        message_mac(&self.received_mac_key, am0.sequence, &am0.message)
+            .verify(&am0.mac.mac)
+            .map_err(|_| MessageReceiveError::BadMac)?;
+
+        self.received_message_sequence += 1;
+
+        Ok(())
     }
 }
 
+/// Port from Hello is XDR int, only 1..=65535 can be dialed
+fn listening_port(hello: &xdr::Hello) -> Option<u16> {
+    u16::try_from(hello.listening_port)
+        .ok()
+        .filter(|port| *port != 0)
+}
+
+/// HELLO and ERROR messages could be sent before MAC keys are established
+fn is_mac_required(message: &xdr::StellarMessage) -> bool {
+    match message {
+        xdr::StellarMessage::Hello(_) | xdr::StellarMessage::Error(_) => false,
+        _ => true,
+    }
+}
+
+/// HMAC-SHA256 over XDR packed sequence and message
+fn message_mac(
+    mac_key: &[u8; 32],
+    sequence: xdr::Uint64,
+    message: &xdr::StellarMessage,
+) -> Hmac<Sha256> {
+    let mut packed_auth_message_v0 = Vec::new();
+    serde_xdr::to_writer(&mut packed_auth_message_v0, &sequence).unwrap();
+    serde_xdr::to_writer(&mut packed_auth_message_v0, message).unwrap();
+    let mut mac = Hmac::<Sha256>::new_varkey(mac_key).unwrap();
+    mac.input(&packed_auth_message_v0[..]);
+    mac
+}
+
 impl PeerInterface for Peer {
     // Connection process:
     // A wants to connect to B
@@ -188,78 +431,112 @@ impl PeerInterface for Peer {
     // Node Ed25519 keys) the result of which is then fed through HKDF with the
     // per-connection nonces. See PeerAuth.h.
     // If any verify step fails, the peer disconnects immediately.
-    /// Start connection process to peer.
+    /// Start connection process to peer. Connection initiator sends Hello first,
+    /// acceptor waits for it, the rest of handshake is driven by `handle_handshake_message`.
     /// More additional info: https://github.com/stellar/stellar-core/blob/ddef8bcacc5193bdd4daa07af404f1b6b1adaf39/src/overlay/OverlayManagerImpl.cpp#L28-L45
-    fn start_authentication(&mut self, we_called_remote: bool) {
+    fn start_authentication(&mut self) {
         info!(
             "[Overlay][Peer] Started authentication proccess peer: {}",
             self.address
         );
+        // handshake must be completed before authentication timeout
+        self.activity.start_handshake(Instant::now());
 
-        if we_called_remote {
+        if self.we_called_remote {
             self.send_message(xdr::StellarMessage::Hello(self.hello.clone()));
-            match self.receive_message() {
-                Ok(xdr::AuthenticatedMessage::V0(hello)) => {
-                    self.handle_hello(hello.message, we_called_remote);
-                }
-                _ => {
+        }
+    }
+
+    /// Handle message received before authentication is completed
+    fn handle_handshake_message(&mut self, message: xdr::StellarMessage) -> Result<(), PeerError> {
+        match message {
+            xdr::StellarMessage::Hello(_)
+                if self.handshake_state == HandshakeState::AwaitingHello =>
+            {
+                if let Err(e) = self.handle_hello(message, self.we_called_remote) {
                     info!(
-                        "[Overlay][Peer] Received not hello message from peer {}. Authentication aborted",
-                        self.address
+                        "[Overlay][Peer] Invalid hello from peer {}: {}. Authentication aborted",
+                        self.address, e
                     );
-                    return;
+                    self.send_error_message(e);
+                    return Err(e);
                 }
+                // reply waits until overlay manager checks remote node ID
+                self.handshake_state = HandshakeState::AwaitingVerdict;
+                Ok(())
             }
-            self.send_message(xdr::StellarMessage::Auth(xdr::Auth { unused: 0 }));
             // last auth message from remote peer
-            if self.receive_message().is_err() {
+            xdr::StellarMessage::Auth(_)
+                if self.handshake_state == HandshakeState::AwaitingAuth =>
+            {
+                if !self.we_called_remote {
+                    self.send_message(xdr::StellarMessage::Auth(xdr::Auth { unused: 0 }));
+                }
+                self.set_authenticated();
+
                 info!(
-                    "[Overlay][Peer] Not received last auth message {}. Authentication aborted",
+                    "[Overlay] Authentication completed for peer {}",
                     self.address
                 );
-                return;
+                Ok(())
             }
-        } else {
-            match self.receive_message() {
-                Ok(xdr::AuthenticatedMessage::V0(hello)) => {
-                    self.handle_hello(hello.message, we_called_remote);
-                }
-                _ => {
-                    info!(
-                        "[Overlay][Peer] Received non hello message from peer {}. Authentication aborted",
-                        self.address
-                    );
-                    return;
-                }
+            xdr::StellarMessage::Error(error) => {
+                info!(
+                    "[Overlay][Peer] Peer {} aborted authentication: {}",
+                    self.address, error.msg
+                );
+                Err(PeerError::AuthFail)
             }
-            self.send_message(xdr::StellarMessage::Hello(self.hello.clone()));
-
-            // last auth message from remote peer
-            if self.receive_message().is_err() {
+            _ => {
                 info!(
-                    "[Overlay][Peer] Not received last auth message {}. Authentication aborted",
+                    "[Overlay][Peer] Unexpected message from peer {}. Authentication aborted",
                     self.address
                 );
-                return;
+                self.send_error_message(PeerError::UnexpectedMessage);
+                Err(PeerError::UnexpectedMessage)
             }
-            self.send_message(xdr::StellarMessage::Auth(xdr::Auth { unused: 0 }));
         }
+    }
 
-        self.set_authenticated();
+    /// Overlay manager accepted remote node ID, reply to its Hello
+    fn continue_handshake(&mut self) {
+        if self.handshake_state != HandshakeState::AwaitingVerdict {
+            return;
+        }
 
+        if self.we_called_remote {
+            self.send_message(xdr::StellarMessage::Auth(xdr::Auth { unused: 0 }));
+        } else {
+            self.send_message(xdr::StellarMessage::Hello(self.hello.clone()));
+        }
+        self.handshake_state = HandshakeState::AwaitingAuth;
+    }
+
+    /// Overlay manager refused connection with remote node ID
+    fn reject_handshake(&mut self, error: PeerError) {
         info!(
-            "[Overlay] Authentication completed for peer {}",
-            self.address
+            "[Overlay][Peer] Connection with peer {} rejected: {}",
+            self.address, error
         );
+        self.send_error_message(error);
     }
 
-    fn handle_hello(&mut self, received_hello: xdr::StellarMessage, we_called_remote: bool) {
+    fn handle_hello(
+        &mut self,
+        received_hello: xdr::StellarMessage,
+        we_called_remote: bool,
+    ) -> Result<(), PeerError> {
         match received_hello {
             xdr::StellarMessage::Hello(hello) => {
+                self.validate_hello(&hello)?;
                 self.set_remote_keys(hello.cert.pubkey, hello.nonce, we_called_remote);
                 self.peer_info = hello;
+                Ok(())
+            }
+            _ => {
+                error!("[Overlay] Received non hello message");
+                Err(PeerError::UnexpectedMessage)
             }
-            _ => error!("[Overlay] Received non hello message"),
         }
     }
 
@@ -274,15 +551,15 @@ impl PeerInterface for Peer {
         let mut public_b: [u8; 32] = Default::default();
 
         if we_called_remote {
-            public_a.copy_from_slice(&self.auth_public_key.as_bytes()[..]);
+            public_a.copy_from_slice(&PEER_AUTH.auth_public_key().as_bytes()[..]);
             public_b.copy_from_slice(&remote_pub_key.key[..]);
         } else {
             public_a.copy_from_slice(&remote_pub_key.key[..]);
-            public_b.copy_from_slice(&self.auth_public_key.as_bytes()[..]);
+            public_b.copy_from_slice(&PEER_AUTH.auth_public_key().as_bytes()[..]);
         }
 
-        let shared_secret = &self
-            .auth_secret_key
+        let shared_secret = &PEER_AUTH
+            .auth_secret_key()
             .diffie_hellman(&PublicKey::from(remote_pub_key.key));
 
         let mut buffer: Vec<u8> = Default::default();
@@ -331,45 +608,10 @@ impl PeerInterface for Peer {
         self.received_mac_key = okm;
     }
 
-    /// Make expired certicate for all connection with peers
-    fn new_auth_cert(node_info: &LocalNode, auth_public_key: &PublicKey) -> xdr::AuthCert {
-        let unix_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        let expiration_limit: u64 = 3600; // 1 hour
-        let expiration: xdr::Uint64 = expiration_limit + unix_time;
-
-        let mut buffer = Vec::new();
-
-        serde_xdr::to_writer(&mut buffer, &node_info.network_id).unwrap();
-        serde_xdr::to_writer(&mut buffer, &xdr::EnvelopeType::EnvelopeTypeAuth).unwrap();
-        serde_xdr::to_writer(&mut buffer, &expiration).unwrap();
-        serde_xdr::to_writer(
-            &mut buffer,
-            &xdr::Curve25519Public {
-                key: *auth_public_key.as_bytes(),
-            },
-        )
-        .unwrap();
-
-        let mut hasher = sha2::Sha256::new();
-        hasher.input(buffer);
-        let hash = hasher.result();
-        let sign = &node_info.key_pair.sign(&hash);
-
-        xdr::AuthCert {
-            pubkey: xdr::Curve25519Public {
-                key: *auth_public_key.as_bytes(),
-            },
-            expiration,
-            sig: xdr::Signature(sign.to_bytes().to_vec()),
-        }
-    }
-
     /// Send XDR message to remote peer
     fn send_message(&mut self, message: xdr::StellarMessage) {
+        let priority = MessagePriority::of(&message);
+
         let mut am0 = xdr::AuthenticatedMessageV0 {
             sequence: self.send_message_sequence,
             message,
@@ -378,81 +620,44 @@ impl PeerInterface for Peer {
             },
         };
 
-        match am0.message {
-            xdr::StellarMessage::Hello(_) | xdr::StellarMessage::Error(_) => {}
-            _ => {
-                let mut packed_auth_message_v0 = Vec::new();
-                serde_xdr::to_writer(&mut packed_auth_message_v0, &am0.sequence).unwrap();
-                serde_xdr::to_writer(&mut packed_auth_message_v0, &am0.message).unwrap();
-                let mut mac = Hmac::<Sha256>::new_varkey(&self.sended_mac_key).unwrap();
-                mac.input(&packed_auth_message_v0[..]);
-                am0.mac = xdr::HmacSha256Mac {
-                    mac: mac.result().code().into(),
-                };
-                self.increment_message_sequence();
-            }
-        };
+        if is_mac_required(&am0.message) {
+            am0.mac = xdr::HmacSha256Mac {
+                mac: message_mac(&self.sended_mac_key, am0.sequence, &am0.message)
+                    .result()
+                    .code()
+                    .into(),
+            };
+            self.increment_message_sequence();
+        }
 
         let am = xdr::AuthenticatedMessage::V0(am0);
 
         let packed_auth_message = serde_xdr::to_bytes(&am).unwrap();
 
-        self.send_header(packed_auth_message.len() as u32);
+        let xdr::AuthenticatedMessage::V0(ref am0) = am;
+        let sent = SentMessage::new(&self.traffic, &am0.message, packed_auth_message.len());
 
-        self.stream.write(&packed_auth_message[..]);
+        self.connection
+            .send(priority, encode_record(&packed_auth_message), Some(sent));
+        self.activity.record_queued(Instant::now());
     }
 
-    /// Send legnth of of upcoming message fragment
-    fn send_header(&mut self, message_length: u32) {
-        // In RPC (see RFC5531 section 11), the high bit means this is the
-        // last record fragment in a record.  If the high bit is clear, it
-        // means another fragment follows.  We don't currently implement
-        // continuation fragments, and instead always set the last-record
-        // bit to produce a single-fragment record.
-
-        let mut header = Vec::new();
-        header
-            .write_u32::<BigEndian>(message_length | 0x8000_0000)
-            .unwrap();
-        self.stream.write(&header[..]);
-    }
-
-    // We always receive messages as single-fragment messages.
-    /// Get legnth of incoming message fragment
-    fn receive_header(&mut self) -> usize {
-        let mut header: [u8; 4] = Default::default();
-        if let Err(_e) = self.stream.read_exact(&mut header) {
-            return 0;
-        }
-
-        let mut message_length: usize;
-        message_length = header[0] as usize; // clear the XDR 'continuation' bit
-        message_length &= 0x7f;
-        message_length <<= 8;
-        message_length |= header[1] as usize;
-        message_length <<= 8;
-        message_length |= header[2] as usize;
-        message_length <<= 8;
-        message_length |= header[3] as usize;
-
-        message_length
-    }
-
-    fn receive_message(&mut self) -> Result<xdr::AuthenticatedMessage, MessageReceiveError> {
-        let message_length = self.receive_header();
-
-        let mut message_content = vec![0u8; message_length];
+    /// Parse record received from reactor and check its MAC
+    fn receive_message(
+        &mut self,
+        record: &[u8],
+    ) -> Result<xdr::AuthenticatedMessage, MessageReceiveError> {
+        self.activity.record_read(Instant::now());
+        let mut cursor = Cursor::new(record);
 
-        self.stream.read_exact(&mut message_content)?;
+        let authenticated_message: xdr::AuthenticatedMessage = serde_xdr::from_reader(&mut cursor)?;
 
-        let mut cursor = Cursor::new(message_content);
+        self.authenticate_message(&authenticated_message)?;
 
-        let authenticated_message: Result<xdr::AuthenticatedMessage, MessageReceiveError> =
-            serde_xdr::from_reader(&mut cursor).map_err(|e| e.into());
+        let xdr::AuthenticatedMessage::V0(ref am0) = authenticated_message;
+        METRICS.record_received(&self.traffic, &am0.message, record.len());
 
-        // TODO: compare with HmacSha256Mac setted in Peer in stage of auth
-        // TODO: check sequence of messages
-        authenticated_message
+        Ok(authenticated_message)
     }
 
     fn increment_message_sequence(&mut self) {
@@ -460,47 +665,74 @@ impl PeerInterface for Peer {
     }
 
     fn set_authenticated(&mut self) {
-        self.is_authenticated = true;
+        self.handshake_state = HandshakeState::Authenticated;
     }
 
     fn is_authenticated(&self) -> bool {
-        self.is_authenticated
+        self.handshake_state == HandshakeState::Authenticated
     }
 
-    fn address(&self) -> &String {
+    fn address(&self) -> &SocketAddr {
         &self.address
     }
 }
 
-impl Clone for Peer {
-    fn clone(&self) -> Self {
-        Peer {
-            stream: self
-                .stream
-                .try_clone()
-                .expect("Failed when try to clone socket stream"),
-            send_message_sequence: self.send_message_sequence,
-            cached_auth_cert: self.cached_auth_cert.clone(),
-            auth_secret_key: self.auth_secret_key.clone(),
-            auth_public_key: self.auth_public_key,
-            auth_shared_key: self.auth_shared_key,
-            received_mac_key: self.received_mac_key,
-            sended_mac_key: self.sended_mac_key,
-            nonce: self.nonce,
-            hello: self.hello.clone(),
-            address: self.address.clone(),
-            peer_info: self.peer_info.clone(),
-            is_authenticated: self.is_authenticated,
-        }
-    }
-}
-
 impl fmt::Debug for Peer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{{address: {:?}, peer_info: {:?}, is_authenticated: {:?}}}",
-            &self.address, &self.peer_info, &self.is_authenticated
+            "{{address: {:?}, peer_info: {:?}, handshake_state: {:?}}}",
+            &self.address, &self.peer_info, &self.handshake_state
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factories::internal_xdr::{build_hello, build_transaction};
+
+    #[test]
+    fn message_mac_verifies_with_same_key() {
+        let key = [7u8; 32];
+        let message = build_transaction();
+        let code = message_mac(&key, 3, &message).result().code();
+
+        assert!(message_mac(&key, 3, &message).verify(&code).is_ok());
+    }
+
+    #[test]
+    fn message_mac_rejects_other_key_or_sequence() {
+        let key = [7u8; 32];
+        let message = build_transaction();
+        let code = message_mac(&key, 3, &message).result().code();
+
+        assert!(message_mac(&[8u8; 32], 3, &message).verify(&code).is_err());
+        assert!(message_mac(&key, 4, &message).verify(&code).is_err());
+    }
+
+    #[test]
+    fn listening_port_must_fit_in_port_range() {
+        let mut hello = build_hello();
+        assert_eq!(listening_port(&hello), Some(11625));
+
+        for invalid_port in &[0, -1, 65536, 0x1_2b69] {
+            hello.listening_port = *invalid_port;
+            assert_eq!(listening_port(&hello), None);
+        }
+
+        hello.listening_port = 65535;
+        assert_eq!(listening_port(&hello), Some(65535));
+    }
+
+    #[test]
+    fn hello_and_error_are_sent_without_mac() {
+        assert!(!is_mac_required(&xdr::StellarMessage::Hello(
+            Default::default()
+        )));
+        assert!(!is_mac_required(&xdr::StellarMessage::Error(
+            Default::default()
+        )));
+        assert!(is_mac_required(&build_transaction()));
+    }
+}
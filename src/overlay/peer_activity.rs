@@ -0,0 +1,220 @@
+use super::CONFIG;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Reason to drop peer which stopped making progress
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerTimeout {
+    /// Handshake isn't completed in time
+    Authentication,
+    /// Nothing was read from peer for too long, our own writes don't count
+    Idle,
+    /// Peer doesn't read what we send, so outbound queue is never drained
+    Straggler,
+}
+
+impl fmt::Display for PeerTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            PeerTimeout::Authentication => "authentication timeout",
+            PeerTimeout::Idle => "idle timeout",
+            PeerTimeout::Straggler => "straggling (cannot keep up)",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PeerTimeouts {
+    pub authentication: Duration,
+    pub idle: Duration,
+    pub straggler: Duration,
+}
+
+impl PeerTimeouts {
+    pub fn from_config() -> Self {
+        PeerTimeouts {
+            authentication: Duration::from_secs(*CONFIG.peer_authentication_timeout()),
+            idle: Duration::from_secs(*CONFIG.peer_timeout()),
+            straggler: Duration::from_secs(*CONFIG.peer_straggler_timeout()),
+        }
+    }
+
+    /// We write something to quiet peer before its idle timeout fires
+    pub fn keepalive_interval(&self) -> Duration {
+        self.idle / 2
+    }
+}
+
+/**
+ * PeerActivity tracks when connection with peer made progress last time.
+ * Writes are counted when reactor reports outbound queue is drained, not when
+ * message is queued, so a peer which stopped reading is detected as straggler.
+ */
+#[derive(Debug)]
+pub(crate) struct PeerActivity {
+    handshake_started_at: Instant,
+    last_read: Instant,
+    last_write: Instant,
+    /// Since when queued data waits to be written, None if everything is written
+    write_pending_since: Option<Instant>,
+}
+
+impl PeerActivity {
+    pub fn new(now: Instant) -> Self {
+        PeerActivity {
+            handshake_started_at: now,
+            last_read: now,
+            last_write: now,
+            write_pending_since: None,
+        }
+    }
+
+    pub fn start_handshake(&mut self, now: Instant) {
+        self.handshake_started_at = now;
+    }
+
+    pub fn record_read(&mut self, now: Instant) {
+        self.last_read = now;
+    }
+
+    pub fn record_queued(&mut self, now: Instant) {
+        if self.write_pending_since.is_none() {
+            self.write_pending_since = Some(now);
+        }
+    }
+
+    pub fn record_drained(&mut self, now: Instant) {
+        self.last_write = now;
+        self.write_pending_since = None;
+    }
+
+    pub fn check_timeouts(
+        &self,
+        now: Instant,
+        is_authenticated: bool,
+        timeouts: &PeerTimeouts,
+    ) -> Option<PeerTimeout> {
+        if !is_authenticated {
+            if now.duration_since(self.handshake_started_at) >= timeouts.authentication {
+                return Some(PeerTimeout::Authentication);
+            }
+            return None;
+        }
+
+        if let Some(pending_since) = self.write_pending_since {
+            if now.duration_since(pending_since) >= timeouts.straggler {
+                return Some(PeerTimeout::Straggler);
+            }
+        }
+
+        // the same as stellar-core PEER_TIMEOUT, keepalives we write
+        // must not keep silent peer alive
+        if now.duration_since(self.last_read) >= timeouts.idle {
+            return Some(PeerTimeout::Idle);
+        }
+
+        None
+    }
+
+    /// Nothing is queued and nothing was written for keepalive interval
+    pub fn needs_keepalive(&self, now: Instant, timeouts: &PeerTimeouts) -> bool {
+        self.write_pending_since.is_none()
+            && now.duration_since(self.last_write) >= timeouts.keepalive_interval()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts() -> PeerTimeouts {
+        PeerTimeouts {
+            authentication: Duration::from_secs(2),
+            idle: Duration::from_secs(30),
+            straggler: Duration::from_secs(120),
+        }
+    }
+
+    mod check_timeouts {
+        use super::*;
+
+        #[test]
+        fn authentication_timeout_before_handshake_is_completed() {
+            let start = Instant::now();
+            let activity = PeerActivity::new(start);
+            let later = start + Duration::from_secs(3);
+
+            assert_eq!(
+                activity.check_timeouts(later, false, &timeouts()),
+                Some(PeerTimeout::Authentication)
+            );
+            assert_eq!(activity.check_timeouts(later, true, &timeouts()), None);
+        }
+
+        #[test]
+        fn idle_timeout_without_reads_and_writes() {
+            let start = Instant::now();
+            let mut activity = PeerActivity::new(start);
+            activity.record_read(start + Duration::from_secs(20));
+
+            assert_eq!(
+                activity.check_timeouts(start + Duration::from_secs(40), true, &timeouts()),
+                None
+            );
+            assert_eq!(
+                activity.check_timeouts(start + Duration::from_secs(50), true, &timeouts()),
+                Some(PeerTimeout::Idle)
+            );
+        }
+
+        #[test]
+        fn idle_timeout_despite_our_keepalives() {
+            let start = Instant::now();
+            let mut activity = PeerActivity::new(start);
+            for secs in &[15, 30] {
+                let now = start + Duration::from_secs(*secs);
+                assert!(activity.needs_keepalive(now, &timeouts()));
+                activity.record_queued(now);
+                activity.record_drained(now);
+            }
+
+            assert_eq!(
+                activity.check_timeouts(start + Duration::from_secs(30), true, &timeouts()),
+                Some(PeerTimeout::Idle)
+            );
+        }
+
+        #[test]
+        fn straggler_timeout_while_queue_is_not_drained() {
+            let start = Instant::now();
+            let mut activity = PeerActivity::new(start);
+            activity.record_queued(start);
+            // peer keeps sending, but doesn't read
+            activity.record_read(start + Duration::from_secs(125));
+
+            assert_eq!(
+                activity.check_timeouts(start + Duration::from_secs(130), true, &timeouts()),
+                Some(PeerTimeout::Straggler)
+            );
+
+            activity.record_drained(start + Duration::from_secs(130));
+            assert_eq!(
+                activity.check_timeouts(start + Duration::from_secs(130), true, &timeouts()),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn keepalive_after_half_of_idle_timeout() {
+        let start = Instant::now();
+        let mut activity = PeerActivity::new(start);
+
+        assert!(!activity.needs_keepalive(start + Duration::from_secs(10), &timeouts()));
+        assert!(activity.needs_keepalive(start + Duration::from_secs(15), &timeouts()));
+
+        activity.record_queued(start + Duration::from_secs(15));
+        assert!(!activity.needs_keepalive(start + Duration::from_secs(20), &timeouts()));
+    }
+}
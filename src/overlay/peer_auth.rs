@@ -0,0 +1,237 @@
+use super::{peer::PeerError, serde_xdr, sha2::Digest, xdr, LocalNode};
+use sha2::Sha256;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Lifetime of signed AuthCert
+const AUTH_CERT_EXPIRATION_LIMIT: u64 = 3600; // 1 hour
+/// AuthCert is signed again when it has less time than this left
+const AUTH_CERT_REFRESH_MARGIN: u64 = AUTH_CERT_EXPIRATION_LIMIT / 2;
+
+/**
+ * PeerAuth keeps authentication system keys and AuthCert of our node.
+ * Our ECDH secret and public keys are randomized on startup and shared by
+ * all peer connections, so the certificate is signed once and only refreshed
+ * periodically instead of on every connection.
+ * More info in: stellar-core/src/overlay/PeerAuth.h file
+ */
+pub struct PeerAuth {
+    /// Public authentication system key
+    auth_public_key: PublicKey,
+    auth_secret_key: StaticSecret,
+    /// Signed certificate we present to peers
+    cached_auth_cert: RwLock<xdr::AuthCert>,
+}
+
+impl PeerAuth {
+    pub fn new(node_info: &LocalNode) -> Self {
+        let mut rng = rand::thread_rng();
+
+        let auth_secret_key = StaticSecret::new(&mut rng);
+        let auth_public_key = PublicKey::from(&auth_secret_key);
+        let auth_cert = Self::new_auth_cert(node_info, &auth_public_key);
+
+        PeerAuth {
+            auth_public_key,
+            auth_secret_key,
+            cached_auth_cert: RwLock::new(auth_cert),
+        }
+    }
+
+    pub fn auth_public_key(&self) -> &PublicKey {
+        &self.auth_public_key
+    }
+
+    pub fn auth_secret_key(&self) -> &StaticSecret {
+        &self.auth_secret_key
+    }
+
+    /// Return cached certificate. It is signed here only when periodic refresh didn't happen in time
+    pub fn auth_cert(&self, node_info: &LocalNode) -> xdr::AuthCert {
+        let auth_cert = self.cached_auth_cert.read().unwrap().clone();
+        if auth_cert.expiration > unix_time() {
+            return auth_cert;
+        }
+
+        self.refresh_auth_cert(node_info)
+    }
+
+    /// Sign new certificate if the cached one expires soon. Returns true if certificate was refreshed
+    pub fn refresh_if_expiring(&self, node_info: &LocalNode) -> bool {
+        let expiration = self.cached_auth_cert.read().unwrap().expiration;
+        if expiration >= unix_time() + AUTH_CERT_REFRESH_MARGIN {
+            return false;
+        }
+
+        self.refresh_auth_cert(node_info);
+        true
+    }
+
+    fn refresh_auth_cert(&self, node_info: &LocalNode) -> xdr::AuthCert {
+        let auth_cert = Self::new_auth_cert(node_info, &self.auth_public_key);
+        *self.cached_auth_cert.write().unwrap() = auth_cert.clone();
+        auth_cert
+    }
+
+    /// Make expired certicate for all connection with peers
+    pub fn new_auth_cert(node_info: &LocalNode, auth_public_key: &PublicKey) -> xdr::AuthCert {
+        let expiration: xdr::Uint64 = AUTH_CERT_EXPIRATION_LIMIT + unix_time();
+
+        let pubkey = xdr::Curve25519Public {
+            key: *auth_public_key.as_bytes(),
+        };
+
+        let hash = auth_cert_hash(&node_info.network_id, expiration, &pubkey);
+        let sign = &node_info.key_pair.sign(&hash);
+
+        xdr::AuthCert {
+            pubkey,
+            expiration,
+            sig: xdr::Signature(sign.to_bytes().to_vec()),
+        }
+    }
+
+    /// Check remote certificate the same way we sign ours in `new_auth_cert`
+    pub fn verify_remote_auth_cert(
+        node_info: &LocalNode,
+        peer_id: &xdr::NodeId,
+        cert: &xdr::AuthCert,
+    ) -> Result<(), PeerError> {
+        if cert.expiration < unix_time() {
+            return Err(PeerError::ExpiredAuthCert);
+        }
+
+        let hash = auth_cert_hash(&node_info.network_id, cert.expiration, &cert.pubkey);
+
+        let xdr::PublicKey::Ed25519(xdr::Uint256(peer_key)) = peer_id;
+        let public_key = ed25519_dalek::PublicKey::from_bytes(peer_key)
+            .map_err(|_| PeerError::InvalidAuthCertSignature)?;
+        let signature = ed25519_dalek::Signature::from_bytes(&cert.sig.0)
+            .map_err(|_| PeerError::InvalidAuthCertSignature)?;
+
+        public_key
+            .verify(&hash, &signature)
+            .map_err(|_| PeerError::InvalidAuthCertSignature)
+    }
+}
+
+impl fmt::Debug for PeerAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{auth_public_key: {:?}, cached_auth_cert: {:?}}}",
+            self.auth_public_key.as_bytes(),
+            self.cached_auth_cert.read().unwrap()
+        )
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Hash of (network_id, EnvelopeTypeAuth, expiration, pubkey) signed by node key in AuthCert
+fn auth_cert_hash(
+    network_id: &xdr::Hash,
+    expiration: xdr::Uint64,
+    pubkey: &xdr::Curve25519Public,
+) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    serde_xdr::to_writer(&mut buffer, network_id).unwrap();
+    serde_xdr::to_writer(&mut buffer, &xdr::EnvelopeType::EnvelopeTypeAuth).unwrap();
+    serde_xdr::to_writer(&mut buffer, &expiration).unwrap();
+    serde_xdr::to_writer(&mut buffer, pubkey).unwrap();
+
+    Sha256::digest(&buffer).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factories::internal_xdr::build_public_key;
+    use crate::factories::local_node::build_local_node;
+
+    fn local_peer_id(node: &LocalNode) -> xdr::NodeId {
+        xdr::PublicKey::Ed25519(xdr::Uint256(node.key_pair.public.to_bytes()))
+    }
+
+    mod verify_remote_auth_cert {
+        use super::*;
+
+        #[test]
+        fn signed_by_peer() {
+            let node = build_local_node();
+            let cert = PeerAuth::new(&node).auth_cert(&node);
+
+            assert!(PeerAuth::verify_remote_auth_cert(&node, &local_peer_id(&node), &cert).is_ok());
+        }
+
+        #[test]
+        fn signed_by_another_node() {
+            let node = build_local_node();
+            let cert = PeerAuth::new(&node).auth_cert(&node);
+
+            assert_eq!(
+                PeerAuth::verify_remote_auth_cert(&node, &build_public_key(), &cert),
+                Err(PeerError::InvalidAuthCertSignature)
+            );
+        }
+
+        #[test]
+        fn tampered_expiration() {
+            let node = build_local_node();
+            let mut cert = PeerAuth::new(&node).auth_cert(&node);
+            cert.expiration += 1;
+
+            assert_eq!(
+                PeerAuth::verify_remote_auth_cert(&node, &local_peer_id(&node), &cert),
+                Err(PeerError::InvalidAuthCertSignature)
+            );
+        }
+
+        #[test]
+        fn expired() {
+            let node = build_local_node();
+            let mut cert = PeerAuth::new(&node).auth_cert(&node);
+            cert.expiration = 1;
+
+            assert_eq!(
+                PeerAuth::verify_remote_auth_cert(&node, &local_peer_id(&node), &cert),
+                Err(PeerError::ExpiredAuthCert)
+            );
+        }
+    }
+
+    mod refresh_if_expiring {
+        use super::*;
+
+        #[test]
+        fn fresh_cert() {
+            let node = build_local_node();
+            let peer_auth = PeerAuth::new(&node);
+            let cert = peer_auth.auth_cert(&node);
+
+            assert!(!peer_auth.refresh_if_expiring(&node));
+            assert_eq!(peer_auth.auth_cert(&node), cert);
+        }
+
+        #[test]
+        fn expiring_cert() {
+            let node = build_local_node();
+            let peer_auth = PeerAuth::new(&node);
+            peer_auth.cached_auth_cert.write().unwrap().expiration = unix_time() + 10;
+
+            assert!(peer_auth.refresh_if_expiring(&node));
+
+            let cert = peer_auth.auth_cert(&node);
+            assert!(cert.expiration > unix_time() + AUTH_CERT_REFRESH_MARGIN);
+            assert!(PeerAuth::verify_remote_auth_cert(&node, &local_peer_id(&node), &cert).is_ok());
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use super::{xdr, CONFIG};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Window rate limits are counted in
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+/// Misbehavior score is forgiven by one point every this many seconds
+const SCORE_DECAY_INTERVAL_SECS: u64 = 6;
+
+/// Group of messages sharing one rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageCategory {
+    Transaction,
+    ScpMessage,
+    Peers,
+    Other,
+}
+
+impl MessageCategory {
+    pub fn of(message: &xdr::StellarMessage) -> Self {
+        match message {
+            xdr::StellarMessage::Transaction(_) => MessageCategory::Transaction,
+            xdr::StellarMessage::Envelope(_) => MessageCategory::ScpMessage,
+            xdr::StellarMessage::Peers(_) => MessageCategory::Peers,
+            _ => MessageCategory::Other,
+        }
+    }
+
+    /// Messages of the category peer may send us per second
+    pub fn rate_limit(self) -> u32 {
+        let limits = CONFIG.peer_rate_limits();
+        match self {
+            MessageCategory::Transaction => *limits.transactions(),
+            MessageCategory::ScpMessage => *limits.scp_messages(),
+            MessageCategory::Peers => *limits.peers(),
+            MessageCategory::Other => *limits.other(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateCheck {
+    Within,
+    /// Limit is exceeded for the first time in current window
+    Exceeded,
+    /// Limit was already exceeded in current window
+    StillExceeded,
+}
+
+/// Counts messages peer sent us in the current window, per category
+#[derive(Debug)]
+pub(crate) struct PeerRateLimiter {
+    window_started_at: Instant,
+    counts: HashMap<MessageCategory, u32>,
+}
+
+impl PeerRateLimiter {
+    pub fn new(now: Instant) -> Self {
+        PeerRateLimiter {
+            window_started_at: now,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, category: MessageCategory, limit: u32, now: Instant) -> RateCheck {
+        if now.duration_since(self.window_started_at) >= RATE_LIMIT_WINDOW {
+            self.window_started_at = now;
+            self.counts.clear();
+        }
+
+        let count = self.counts.entry(category).or_insert(0);
+        *count += 1;
+
+        if *count <= limit {
+            RateCheck::Within
+        } else if *count == limit + 1 {
+            RateCheck::Exceeded
+        } else {
+            RateCheck::StillExceeded
+        }
+    }
+}
+
+/// Something peer did wrong, raises its misbehavior score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    /// Message can't be parsed
+    MalformedMessage,
+    /// SCP envelope isn't signed by its node
+    InvalidSignature,
+    /// Message holds more data than the protocol allows
+    OversizedData,
+    /// Peer sent us the same flooded message again
+    DuplicateData,
+    /// Peer sent more messages of the category than its rate limit
+    RateLimitExceeded(MessageCategory),
+}
+
+impl Misbehavior {
+    pub fn penalty(self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage | Misbehavior::InvalidSignature => 50,
+            Misbehavior::OversizedData => 25,
+            Misbehavior::RateLimitExceeded(_) => 10,
+            // honest peers send it back when flooding races with us
+            Misbehavior::DuplicateData => 0,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Misbehavior::MalformedMessage => write!(f, "malformed message"),
+            Misbehavior::InvalidSignature => write!(f, "invalid signature"),
+            Misbehavior::OversizedData => write!(f, "oversized data"),
+            Misbehavior::DuplicateData => write!(f, "duplicate data"),
+            Misbehavior::RateLimitExceeded(category) => {
+                write!(f, "rate limit of {:?} exceeded", category)
+            }
+        }
+    }
+}
+
+/**
+ * MisbehaviorScore sums penalties of node misbehavior. The score slowly
+ * decays, so occasional mistakes of honest nodes are forgiven, while a node
+ * misbehaving repeatedly passes the threshold and is dropped.
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct MisbehaviorScore {
+    score: u32,
+    updated_at: Instant,
+}
+
+impl MisbehaviorScore {
+    pub fn new(now: Instant) -> Self {
+        MisbehaviorScore {
+            score: 0,
+            updated_at: now,
+        }
+    }
+
+    /// Add penalty and return the new score. Decay time is advanced by whole
+    /// intervals, so frequent penalties don't stop the score from decaying
+    pub fn add(&mut self, misbehavior: Misbehavior, now: Instant) -> u32 {
+        let forgiven = now.duration_since(self.updated_at).as_secs() / SCORE_DECAY_INTERVAL_SECS;
+        if forgiven > 0 {
+            self.score = self.score.saturating_sub(forgiven as u32);
+            self.updated_at += Duration::from_secs(forgiven * SCORE_DECAY_INTERVAL_SECS);
+        }
+
+        self.score = self.score.saturating_add(misbehavior.penalty());
+        self.score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_per_category_and_window() {
+        let start = Instant::now();
+        let mut limiter = PeerRateLimiter::new(start);
+
+        assert_eq!(
+            limiter.check(MessageCategory::Peers, 1, start),
+            RateCheck::Within
+        );
+        assert_eq!(
+            limiter.check(MessageCategory::Peers, 1, start),
+            RateCheck::Exceeded
+        );
+        assert_eq!(
+            limiter.check(MessageCategory::Peers, 1, start),
+            RateCheck::StillExceeded
+        );
+        assert_eq!(
+            limiter.check(MessageCategory::Transaction, 1, start),
+            RateCheck::Within
+        );
+
+        let next_window = start + RATE_LIMIT_WINDOW;
+        assert_eq!(
+            limiter.check(MessageCategory::Peers, 1, next_window),
+            RateCheck::Within
+        );
+    }
+
+    #[test]
+    fn score_adds_penalties_and_decays() {
+        let start = Instant::now();
+        let mut score = MisbehaviorScore::new(start);
+
+        assert_eq!(score.add(Misbehavior::OversizedData, start), 25);
+        assert_eq!(score.add(Misbehavior::InvalidSignature, start), 75);
+
+        let later = start + Duration::from_secs(SCORE_DECAY_INTERVAL_SECS * 10);
+        assert_eq!(score.add(Misbehavior::DuplicateData, later), 65);
+    }
+
+    #[test]
+    fn frequent_penalties_dont_stop_decay() {
+        let start = Instant::now();
+        let mut score = MisbehaviorScore::new(start);
+        score.add(Misbehavior::OversizedData, start);
+
+        // one interval and a bit later, the bit counts towards the next interval
+        let interval = Duration::from_secs(SCORE_DECAY_INTERVAL_SECS);
+        let first = start + interval + Duration::from_secs(1);
+        assert_eq!(score.add(Misbehavior::DuplicateData, first), 24);
+        assert_eq!(
+            score.add(Misbehavior::DuplicateData, start + interval * 2),
+            23
+        );
+    }
+}
@@ -0,0 +1,664 @@
+use super::{
+    debug, error, framing::RecordReader, info, metrics::SentMessage, warn, MessagePriority,
+    OutboundQueue, CONFIG, METRICS,
+};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token of reactor commands queue
+const COMMANDS: Token = Token(0);
+/// Token of listening socket
+const LISTENER: Token = Token(1);
+/// Tokens of peer connections start from this one
+const FIRST_CONNECTION_ID: usize = 2;
+/// Max number of bytes read from socket at once
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+pub(crate) type ConnectionId = usize;
+
+/// Events reactor delivers to owner of connection
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ConnectionEvent {
+    /// Outgoing connection established
+    Connected,
+    /// Complete record received from remote peer
+    Record(Vec<u8>),
+    /// All queued data is written to socket
+    Drained,
+    /// Connection closed by remote peer or failed
+    Closed(String),
+}
+
+pub(crate) type ConnectionSink = Box<dyn Fn(ConnectionEvent) + Send>;
+pub(crate) type ListenerSink = Box<dyn Fn(Connection, SocketAddr) + Send>;
+
+enum ReactorCmd {
+    Listen(std::net::TcpListener, ListenerSink),
+    Connect(ConnectionId, SocketAddr, ConnectionSink),
+    Attach(ConnectionId, ConnectionSink),
+    Send(ConnectionId, MessagePriority, Vec<u8>, Option<SentMessage>),
+    Close(ConnectionId),
+    Abort(ConnectionId),
+    StopListening,
+    Shutdown,
+}
+
+/**
+ * Reactor serves sockets of all overlay connections on its own thread.
+ * It waits for socket readiness, reads incoming records and writes queued data,
+ * so actors never block on network I/O: they receive ConnectionEvent's from
+ * the reactor and hand outgoing data over through Connection handles.
+ */
+#[derive(Clone)]
+pub(crate) struct Reactor {
+    /// Guarded to keep handle Sync, as actor props arguments require
+    commands: Arc<Mutex<Sender<ReactorCmd>>>,
+    waker: SetReadiness,
+    next_connection_id: Arc<AtomicUsize>,
+    /// Set when reactor thread exits after shutdown
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Reactor {
+    /// Start reactor thread and return handle to it
+    pub fn spawn() -> io::Result<Reactor> {
+        let poll = Poll::new()?;
+        let (registration, waker) = Registration::new2();
+        poll.register(&registration, COMMANDS, Ready::readable(), PollOpt::edge())?;
+
+        let (commands, receiver) = channel();
+        let reactor = Reactor {
+            commands: Arc::new(Mutex::new(commands)),
+            waker,
+            next_connection_id: Arc::new(AtomicUsize::new(FIRST_CONNECTION_ID)),
+            stopped: Arc::new((Mutex::new(false), Condvar::new())),
+        };
+
+        let event_loop = EventLoop {
+            poll,
+            _registration: registration,
+            commands: receiver,
+            reactor: reactor.clone(),
+            listener: None,
+            connections: HashMap::new(),
+            is_shutting_down: false,
+        };
+
+        thread::Builder::new()
+            .name("overlay-reactor".to_string())
+            .spawn(move || event_loop.run())?;
+
+        Ok(reactor)
+    }
+
+    /// Accept incoming connections from listener and pass them to sink
+    pub fn listen(&self, listener: std::net::TcpListener, sink: ListenerSink) {
+        self.execute(ReactorCmd::Listen(listener, sink));
+    }
+
+    /// Start connecting to remote address. Sink receives `Connected` when connection is established
+    pub fn connect(&self, address: SocketAddr, sink: ConnectionSink) -> Connection {
+        let connection = self.new_connection();
+        self.execute(ReactorCmd::Connect(connection.id, address, sink));
+        connection
+    }
+
+    /// Stop accepting incoming connections
+    pub fn stop_listening(&self) {
+        self.execute(ReactorCmd::StopListening);
+    }
+
+    /// Refuse new connections and stop once all existing ones are closed,
+    /// so data queued before closing is written
+    pub fn shutdown(&self) {
+        self.execute(ReactorCmd::Shutdown);
+    }
+
+    /// Wait until reactor thread stops after shutdown. Returns false on timeout
+    pub fn wait_stopped(&self, timeout: Duration) -> bool {
+        let (ref stopped, ref condvar) = *self.stopped;
+        let deadline = Instant::now() + timeout;
+        let mut stopped = stopped.lock().unwrap();
+        while !*stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            stopped = condvar.wait_timeout(stopped, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    fn new_connection(&self) -> Connection {
+        Connection {
+            id: self.next_connection_id.fetch_add(1, Ordering::SeqCst),
+            reactor: self.clone(),
+        }
+    }
+
+    fn execute(&self, cmd: ReactorCmd) {
+        if self.commands.lock().unwrap().send(cmd).is_err() {
+            error!("[Overlay][Reactor] reactor thread is not running");
+            return;
+        }
+
+        if let Err(e) = self.waker.set_readiness(Ready::readable()) {
+            error!("[Overlay][Reactor] unable to wake up reactor, cause: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for Reactor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reactor")
+    }
+}
+
+/// Handle of connection served by reactor
+#[derive(Clone)]
+pub(crate) struct Connection {
+    id: ConnectionId,
+    reactor: Reactor,
+}
+
+impl Connection {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Start delivering events of accepted connection to sink
+    pub fn attach(&self, sink: ConnectionSink) {
+        self.reactor.execute(ReactorCmd::Attach(self.id, sink));
+    }
+
+    /// Queue data to be written to socket. Data of low priority could be dropped
+    /// if remote peer doesn't keep up with reading, so the message is counted
+    /// as sent only after it is written
+    pub fn send(&self, priority: MessagePriority, data: Vec<u8>, sent: Option<SentMessage>) {
+        self.reactor
+            .execute(ReactorCmd::Send(self.id, priority, data, sent));
+    }
+
+    /// Close connection after all queued data is written
+    pub fn close(&self) {
+        self.reactor.execute(ReactorCmd::Close(self.id));
+    }
+
+    /// Close connection at once, dropping queued data
+    pub fn abort(&self) {
+        self.reactor.execute(ReactorCmd::Abort(self.id));
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection({})", self.id)
+    }
+}
+
+struct ConnectionState {
+    stream: TcpStream,
+    /// Receiver of connection events. Accepted connections are not read until it is attached
+    sink: Option<ConnectionSink>,
+    is_connecting: bool,
+    is_closing: bool,
+    reader: RecordReader,
+    write_queue: OutboundQueue,
+}
+
+impl ConnectionState {
+    fn new(stream: TcpStream, sink: Option<ConnectionSink>, is_connecting: bool) -> Self {
+        ConnectionState {
+            stream,
+            sink,
+            is_connecting,
+            is_closing: false,
+            reader: RecordReader::new(*CONFIG.max_message_size()),
+            write_queue: OutboundQueue::from_config(),
+        }
+    }
+
+    fn notify(&self, event: ConnectionEvent) {
+        if let Some(ref sink) = self.sink {
+            sink(event);
+        }
+    }
+}
+
+struct EventLoop {
+    poll: Poll,
+    _registration: Registration,
+    commands: Receiver<ReactorCmd>,
+    reactor: Reactor,
+    listener: Option<(TcpListener, ListenerSink)>,
+    connections: HashMap<ConnectionId, ConnectionState>,
+    is_shutting_down: bool,
+}
+
+impl EventLoop {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("[Overlay][Reactor] poll failed, cause: {}", e);
+                return;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    COMMANDS => self.handle_commands(),
+                    LISTENER => self.accept_connections(),
+                    Token(id) => self.handle_connection_ready(id, event.readiness()),
+                }
+            }
+
+            if self.is_shutting_down && self.connections.is_empty() {
+                info!("[Overlay][Reactor] all connections are closed, reactor stopped");
+                let (ref stopped, ref condvar) = *self.reactor.stopped;
+                *stopped.lock().unwrap() = true;
+                condvar.notify_all();
+                return;
+            }
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        if let Err(e) = self.reactor.waker.set_readiness(Ready::empty()) {
+            error!(
+                "[Overlay][Reactor] unable to reset commands readiness: {}",
+                e
+            );
+        }
+
+        while let Ok(cmd) = self.commands.try_recv() {
+            match cmd {
+                ReactorCmd::Listen(listener, sink) => self.listen(listener, sink),
+                ReactorCmd::Connect(id, address, sink) => self.connect(id, address, sink),
+                ReactorCmd::Attach(id, sink) => self.attach(id, sink),
+                ReactorCmd::Send(id, priority, data, sent) => self.send(id, priority, data, sent),
+                ReactorCmd::Close(id) => self.close(id),
+                ReactorCmd::Abort(id) => {
+                    self.remove(id);
+                }
+                ReactorCmd::StopListening => self.stop_listening(),
+                ReactorCmd::Shutdown => {
+                    self.stop_listening();
+                    self.is_shutting_down = true;
+                }
+            }
+        }
+    }
+
+    fn listen(&mut self, listener: std::net::TcpListener, sink: ListenerSink) {
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("[Overlay][Reactor] unable to use listener, cause: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self
+            .poll
+            .register(&listener, LISTENER, Ready::readable(), PollOpt::edge())
+        {
+            error!(
+                "[Overlay][Reactor] unable to register listener, cause: {}",
+                e
+            );
+            return;
+        }
+
+        self.listener = Some((listener, sink));
+        self.accept_connections();
+    }
+
+    fn stop_listening(&mut self) {
+        if let Some((listener, _)) = self.listener.take() {
+            if let Err(e) = self.poll.deregister(&listener) {
+                warn!(
+                    "[Overlay][Reactor] unable to deregister listener, cause: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    fn accept_connections(&mut self) {
+        loop {
+            let accepted = match self.listener {
+                Some((ref listener, _)) => listener.accept(),
+                None => return,
+            };
+
+            match accepted {
+                Ok((stream, address)) => {
+                    let connection = self.reactor.new_connection();
+                    if let Err(e) = self.register_stream(connection.id(), &stream) {
+                        info!(
+                            "[Overlay][Reactor] unable to register connection {}, cause: {}",
+                            address, e
+                        );
+                        continue;
+                    }
+
+                    self.connections
+                        .insert(connection.id(), ConnectionState::new(stream, None, false));
+
+                    if let Some((_, ref sink)) = self.listener {
+                        sink(connection, address);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    info!("[Overlay][Reactor] connection failed, cause: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn connect(&mut self, id: ConnectionId, address: SocketAddr, sink: ConnectionSink) {
+        if self.is_shutting_down {
+            sink(ConnectionEvent::Closed("shutting down".to_string()));
+            return;
+        }
+
+        let stream = match TcpStream::connect(&address) {
+            Ok(stream) => stream,
+            Err(e) => {
+                sink(ConnectionEvent::Closed(e.to_string()));
+                return;
+            }
+        };
+
+        if let Err(e) = self.register_stream(id, &stream) {
+            sink(ConnectionEvent::Closed(e.to_string()));
+            return;
+        }
+
+        self.connections
+            .insert(id, ConnectionState::new(stream, Some(sink), true));
+    }
+
+    fn register_stream(&self, id: ConnectionId, stream: &TcpStream) -> io::Result<()> {
+        self.poll.register(
+            stream,
+            Token(id),
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        )
+    }
+
+    fn attach(&mut self, id: ConnectionId, sink: ConnectionSink) {
+        match self.connections.get_mut(&id) {
+            Some(connection) => connection.sink = Some(sink),
+            None => {
+                sink(ConnectionEvent::Closed("connection is closed".to_string()));
+                return;
+            }
+        }
+
+        // Data could arrive before connection was attached
+        self.read(id);
+    }
+
+    fn send(
+        &mut self,
+        id: ConnectionId,
+        priority: MessagePriority,
+        data: Vec<u8>,
+        sent: Option<SentMessage>,
+    ) {
+        let is_queued = match self.connections.get_mut(&id) {
+            Some(ref mut connection) if !connection.is_closing => {
+                connection.write_queue.push(priority, data, sent)
+            }
+            _ => return,
+        };
+
+        if !is_queued {
+            // peer is too slow even for messages we can't skip
+            if priority == MessagePriority::High {
+                self.fail(id, "outbound queue overflow".to_string());
+                return;
+            }
+            debug!(
+                "[Overlay][Reactor] outbound queue of connection {} is full, message dropped",
+                id
+            );
+        }
+
+        self.write(id);
+    }
+
+    fn close(&mut self, id: ConnectionId) {
+        let can_be_removed = match self.connections.get_mut(&id) {
+            Some(connection) => {
+                connection.is_closing = true;
+                connection.is_connecting || connection.write_queue.is_empty()
+            }
+            None => return,
+        };
+
+        if can_be_removed {
+            self.remove(id);
+        }
+    }
+
+    fn handle_connection_ready(&mut self, id: ConnectionId, readiness: Ready) {
+        if readiness.is_writable() {
+            self.handle_writable(id);
+        }
+
+        if readiness.is_readable() {
+            self.read(id);
+        }
+    }
+
+    fn handle_writable(&mut self, id: ConnectionId) {
+        let result = match self.connections.get_mut(&id) {
+            Some(ref mut connection) if connection.is_connecting => {
+                match connection.stream.take_error() {
+                    Ok(Some(e)) | Err(e) => Err(e),
+                    Ok(None) => match connection.stream.peer_addr() {
+                        Ok(_) => {
+                            debug!("[Overlay][Reactor] connection {} established", id);
+                            connection.is_connecting = false;
+                            connection.notify(ConnectionEvent::Connected);
+                            Ok(())
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::NotConnected => return,
+                        Err(e) => Err(e),
+                    },
+                }
+            }
+            Some(_) => Ok(()),
+            None => return,
+        };
+
+        match result {
+            Ok(()) => self.write(id),
+            Err(e) => self.fail(id, e.to_string()),
+        }
+    }
+
+    fn read(&mut self, id: ConnectionId) {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+
+        loop {
+            let connection = match self.connections.get_mut(&id) {
+                Some(connection) => connection,
+                None => return,
+            };
+
+            if connection.sink.is_none() || connection.is_connecting || connection.is_closing {
+                return;
+            }
+
+            match connection.stream.read(&mut chunk) {
+                Ok(0) => {
+                    let reason = match connection.reader.finish() {
+                        Ok(()) => "connection closed by remote peer".to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    self.fail(id, reason);
+                    return;
+                }
+                Ok(read) => {
+                    connection.reader.extend(&chunk[..read]);
+                    loop {
+                        match connection.reader.next_record() {
+                            Ok(Some(record)) => connection.notify(ConnectionEvent::Record(record)),
+                            Ok(None) => break,
+                            Err(e) => {
+                                self.fail(id, e.to_string());
+                                return;
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.fail(id, e.to_string());
+                    return;
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, id: ConnectionId) {
+        loop {
+            let connection = match self.connections.get_mut(&id) {
+                Some(connection) => connection,
+                None => return,
+            };
+
+            if connection.is_connecting {
+                return;
+            }
+
+            let result = match connection.write_queue.front() {
+                Some(data) => connection.stream.write(data),
+                None => {
+                    if connection.is_closing {
+                        self.remove(id);
+                    }
+                    return;
+                }
+            };
+
+            match result {
+                Ok(0) => {
+                    self.fail(id, "unable to write to socket".to_string());
+                    return;
+                }
+                // partial write, the rest is written on the next iteration
+                Ok(written) => {
+                    if let Some(sent) = connection.write_queue.consume(written) {
+                        METRICS.record_sent(&sent);
+                    }
+                    if connection.write_queue.is_empty() {
+                        connection.notify(ConnectionEvent::Drained);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.fail(id, e.to_string());
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Drop connection because of error and notify its owner
+    fn fail(&mut self, id: ConnectionId, reason: String) {
+        if let Some(connection) = self.remove(id) {
+            debug!("[Overlay][Reactor] connection {} failed: {}", id, reason);
+            if !connection.is_closing {
+                connection.notify(ConnectionEvent::Closed(reason));
+            }
+        }
+    }
+
+    fn remove(&mut self, id: ConnectionId) -> Option<ConnectionState> {
+        let connection = self.connections.remove(&id)?;
+        // Socket may be already closed by remote side, nothing to do about it
+        self.poll.deregister(&connection.stream).ok();
+        connection.stream.shutdown(Shutdown::Both).ok();
+        Some(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay::framing::encode_record;
+    use std::time::Duration;
+
+    #[test]
+    fn deliver_records_between_connections() {
+        let timeout = Duration::from_secs(5);
+        let reactor = Reactor::spawn().unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (accepted_sender, accepted) = channel();
+        reactor.listen(
+            listener,
+            Box::new(move |connection, _| {
+                accepted_sender.send(connection).ok();
+            }),
+        );
+
+        let (outgoing_sender, outgoing_events) = channel();
+        let outgoing = reactor.connect(
+            address,
+            Box::new(move |event| {
+                outgoing_sender.send(event).ok();
+            }),
+        );
+        assert_eq!(
+            outgoing_events.recv_timeout(timeout).unwrap(),
+            ConnectionEvent::Connected
+        );
+
+        let incoming = accepted.recv_timeout(timeout).unwrap();
+        let (incoming_sender, incoming_events) = channel();
+        incoming.attach(Box::new(move |event| {
+            incoming_sender.send(event).ok();
+        }));
+
+        outgoing.send(MessagePriority::Normal, encode_record(&[1, 2, 3]), None);
+        assert_eq!(
+            incoming_events.recv_timeout(timeout).unwrap(),
+            ConnectionEvent::Record(vec![1, 2, 3])
+        );
+        assert_eq!(
+            outgoing_events.recv_timeout(timeout).unwrap(),
+            ConnectionEvent::Drained
+        );
+
+        outgoing.close();
+        match incoming_events.recv_timeout(timeout).unwrap() {
+            ConnectionEvent::Closed(_) => (),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
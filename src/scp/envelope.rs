@@ -0,0 +1,89 @@
+use super::{local_node::LocalNode, xdr};
+
+/// Sign SCP statement of our node, the same way stellar-core HerderImpl::signEnvelope does
+pub fn sign_envelope(node_info: &LocalNode, statement: xdr::ScpStatement) -> xdr::ScpEnvelope {
+    let payload = envelope_payload(&node_info.network_id, &statement);
+    let sign = node_info.key_pair.sign(&payload);
+
+    xdr::ScpEnvelope {
+        statement,
+        signature: xdr::Signature(sign.to_bytes().to_vec()),
+    }
+}
+
+/// Envelope must be signed by the node its statement belongs to
+pub fn verify_envelope(network_id: &xdr::Hash, envelope: &xdr::ScpEnvelope) -> bool {
+    let xdr::PublicKey::Ed25519(xdr::Uint256(node_key)) = &envelope.statement.node_id;
+    let public_key = match ed25519_dalek::PublicKey::from_bytes(node_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+    let signature = match ed25519_dalek::Signature::from_bytes(&envelope.signature.0) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let payload = envelope_payload(network_id, &envelope.statement);
+    public_key.verify(&payload, &signature).is_ok()
+}
+
+/// (network_id, EnvelopeTypeScp, statement) serialized to XDR
+fn envelope_payload(network_id: &xdr::Hash, statement: &xdr::ScpStatement) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    serde_xdr::to_writer(&mut buffer, network_id).unwrap();
+    serde_xdr::to_writer(&mut buffer, &xdr::EnvelopeType::EnvelopeTypeScp).unwrap();
+    serde_xdr::to_writer(&mut buffer, statement).unwrap();
+
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factories::local_node::build_local_node;
+
+    fn statement(node_info: &LocalNode) -> xdr::ScpStatement {
+        xdr::ScpStatement {
+            node_id: node_info.node_id(),
+            slot_index: 42,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn signed_by_statement_node() {
+        let node = build_local_node();
+        let envelope = sign_envelope(&node, statement(&node));
+
+        assert!(verify_envelope(&node.network_id, &envelope));
+    }
+
+    #[test]
+    fn tampered_statement() {
+        let node = build_local_node();
+        let mut envelope = sign_envelope(&node, statement(&node));
+        envelope.statement.slot_index += 1;
+
+        assert!(!verify_envelope(&node.network_id, &envelope));
+    }
+
+    #[test]
+    fn another_network() {
+        let node = build_local_node();
+        let envelope = sign_envelope(&node, statement(&node));
+
+        assert!(!verify_envelope(&xdr::Hash([7; 32]), &envelope));
+    }
+
+    #[test]
+    fn malformed_signature() {
+        let node = build_local_node();
+        let envelope = xdr::ScpEnvelope {
+            statement: statement(&node),
+            signature: xdr::Signature(vec![1, 2, 3]),
+        };
+
+        assert!(!verify_envelope(&node.network_id, &envelope));
+    }
+}
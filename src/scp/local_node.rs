@@ -1,4 +1,4 @@
-use super::{crypto, lazy_static, xdr, Network, CONFIG};
+use super::{crypto, lazy_static, xdr, Network, PeerAuth, CONFIG};
 use ed25519_dalek::Keypair;
 
 lazy_static! {
@@ -7,6 +7,9 @@ lazy_static! {
         CONFIG.seed().to_owned(),
         &Network::network().network_id(),
     );
+    /// Authentication keys and AuthCert shared by all peer connections of our node
+    #[derive(Debug)]
+    pub static ref PEER_AUTH: PeerAuth = PeerAuth::new(&LOCAL_NODE);
 }
 
 #[derive(Debug)]
@@ -45,4 +48,9 @@ impl LocalNode {
     pub fn key_pair(&self) -> &ed25519_dalek::Keypair {
         &self.key_pair
     }
+
+    /// Our node ID, peers identify us by it
+    pub fn node_id(&self) -> xdr::NodeId {
+        xdr::PublicKey::Ed25519(xdr::Uint256(self.key_pair.public.to_bytes()))
+    }
 }
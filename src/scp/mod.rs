@@ -1,9 +1,12 @@
 #![allow(dead_code)]
 
+pub(crate) mod envelope;
 pub(crate) mod local_node;
+pub(crate) mod quorum_set;
 
 pub(crate) use crate::config::CONFIG;
 pub(crate) use crate::crypto;
 pub(crate) use crate::network::Network;
+pub(crate) use crate::overlay::PeerAuth;
 pub(crate) use crate::xdr;
 pub(crate) use lazy_static::lazy_static;
@@ -0,0 +1,113 @@
+use super::xdr;
+use std::collections::HashSet;
+
+/// Deepest level of inner sets, the same as stellar-core MAXIMUM_QUORUM_NESTING_LEVEL
+const MAX_NESTING_LEVEL: usize = 4;
+
+/// The same as stellar-core MAXIMUM_QUORUM_NODES
+const MAX_NODES: usize = 1000;
+
+/// Check quorum set the way stellar-core isQuorumSetSane does: each validator
+/// appears once and thresholds are in range. Extra checks also require threshold
+/// of every set to be its majority, so no two disjoint quorums can exist
+pub fn check_sanity(quorum_set: &xdr::ScpQuorumSet, extra_checks: bool) -> Result<(), String> {
+    let mut validators = HashSet::new();
+    check_set(quorum_set, 0, extra_checks, &mut validators)?;
+
+    if validators.is_empty() {
+        return Err("has no validators".to_string());
+    }
+    if validators.len() > MAX_NODES {
+        return Err(format!("has more than {} validators", MAX_NODES));
+    }
+    Ok(())
+}
+
+fn check_set(
+    quorum_set: &xdr::ScpQuorumSet,
+    level: usize,
+    extra_checks: bool,
+    validators: &mut HashSet<xdr::NodeId>,
+) -> Result<(), String> {
+    if level > MAX_NESTING_LEVEL {
+        return Err(format!(
+            "has inner sets nested deeper than {} levels",
+            MAX_NESTING_LEVEL
+        ));
+    }
+
+    let entries = (quorum_set.validators.len() + quorum_set.inner_sets.len()) as u32;
+    if quorum_set.threshold == 0 || quorum_set.threshold > entries {
+        return Err(format!(
+            "threshold {} is out of range of {} entries",
+            quorum_set.threshold, entries
+        ));
+    }
+    if extra_checks && quorum_set.threshold * 2 <= entries {
+        return Err(format!(
+            "threshold {} of {} entries is unsafe, it must be their majority",
+            quorum_set.threshold, entries
+        ));
+    }
+
+    for validator in &quorum_set.validators {
+        if !validators.insert(*validator) {
+            return Err("has the same validator more than once".to_string());
+        }
+    }
+    for inner_set in &quorum_set.inner_sets {
+        check_set(inner_set, level + 1, extra_checks, validators)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator(byte: u8) -> xdr::NodeId {
+        xdr::PublicKey::Ed25519(xdr::Uint256([byte; 32]))
+    }
+
+    fn quorum_set(
+        threshold: u32,
+        validators: Vec<xdr::NodeId>,
+        inner_sets: Vec<xdr::ScpQuorumSet>,
+    ) -> xdr::ScpQuorumSet {
+        xdr::ScpQuorumSet {
+            threshold,
+            validators,
+            inner_sets,
+        }
+    }
+
+    #[test]
+    fn accepts_majority_thresholds() {
+        let inner_set = quorum_set(2, vec![validator(1), validator(2)], vec![]);
+        let top = quorum_set(2, vec![validator(3)], vec![inner_set]);
+        assert!(check_sanity(&top, true).is_ok());
+    }
+
+    #[test]
+    fn minority_threshold_passes_without_extra_checks() {
+        let minority = quorum_set(1, vec![validator(1), validator(2)], vec![]);
+        assert!(check_sanity(&minority, false).is_ok());
+    }
+
+    #[test]
+    fn rejects_unsafe_quorum_sets() {
+        let validators = vec![validator(1), validator(2)];
+        assert!(check_sanity(&quorum_set(1, validators.clone(), vec![]), true).is_err());
+        assert!(check_sanity(&quorum_set(3, validators, vec![]), false).is_err());
+
+        let inner_set = quorum_set(1, vec![validator(1)], vec![]);
+        let duplicated = quorum_set(2, vec![validator(1)], vec![inner_set]);
+        assert!(check_sanity(&duplicated, false).is_err());
+
+        let nested = (0..=MAX_NESTING_LEVEL)
+            .fold(quorum_set(1, vec![validator(1)], vec![]), |inner_set, _| {
+                quorum_set(1, vec![], vec![inner_set])
+            });
+        assert!(check_sanity(&nested, false).is_err());
+    }
+}
@@ -26,7 +26,7 @@ use serde_xdr::opaque_data;
 
      typedef opaque Hash[32];
 */
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Default, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Default, Deserialize, Serialize)]
 pub struct Hash(#[serde(with = "opaque_data::fixed_length")] pub [u8; 32]);
 
 /*
@@ -34,7 +34,7 @@ pub struct Hash(#[serde(with = "opaque_data::fixed_length")] pub [u8; 32]);
 
      typedef opaque uint256[32];
 */
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Default, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Default, Deserialize, Serialize)]
 pub struct Uint256(#[serde(with = "opaque_data::fixed_length")] pub [u8; 32]);
 
 /*
@@ -130,7 +130,7 @@ pub enum SignerKeyType {
          uint256 ed25519;
      };
 */
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub enum PublicKey {
     Ed25519(Uint256),
 }
@@ -3852,3 +3852,30 @@ impl From<AuthenticatedMessage> for StellarMessage {
         msg.message
     }
 }
+
+impl std::convert::TryFrom<&PeerAddress> for std::net::SocketAddr {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(item: &PeerAddress) -> Result<Self, Self::Error> {
+        let ip: std::net::IpAddr = match item.ip {
+            PeerAddressIp::Ipv4(octets) => octets.into(),
+            PeerAddressIp::Ipv6(octets) => octets.into(),
+        };
+        let port = std::convert::TryFrom::try_from(item.port)?;
+        Ok(std::net::SocketAddr::new(ip, port))
+    }
+}
+
+impl From<std::net::SocketAddr> for PeerAddress {
+    fn from(item: std::net::SocketAddr) -> Self {
+        let ip = match item.ip() {
+            std::net::IpAddr::V4(ip) => PeerAddressIp::Ipv4(ip.octets()),
+            std::net::IpAddr::V6(ip) => PeerAddressIp::Ipv6(ip.octets()),
+        };
+        PeerAddress {
+            ip,
+            port: Uint32::from(item.port()),
+            num_failures: 0,
+        }
+    }
+}